@@ -0,0 +1,12 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate common;
+
+use common::fuzz::{ self, Case };
+
+fuzz_target!(|data: &[u8]| {
+    let case = Case::from_bytes(data);
+    if let Err(divergence) = fuzz::check(&case, 10_000) {
+        panic!("{}", divergence);
+    }
+});
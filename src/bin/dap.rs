@@ -0,0 +1,35 @@
+use common::dap;
+use common::error::{err, Error};
+use std::io::BufReader;
+use std::net::TcpListener;
+use clap::{Arg, App};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("dap")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Debug UM programs from an editor, speaking the Debug Adapter Protocol on stdio")
+        .arg(Arg::with_name("port")
+            .short("p")
+            .long("port")
+            .value_name("PORT")
+            .help("Listen on this port on 127.0.0.1 for an editor to connect to, rather than using stdio"))
+        .get_matches();
+
+    match opts.value_of("port") {
+        Some(port) => {
+            let port: u16 = port.parse().map_err(|_| err("expected a port number"))?;
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for an editor on {}", listener.local_addr()?);
+            let (sock, _) = listener.accept()?;
+            let input = BufReader::new(sock.try_clone()?);
+            dap::serve(input, sock)
+        },
+        None => {
+            let input = BufReader::new(std::io::stdin());
+            dap::serve(input, std::io::stdout())
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use common::program::platters_from_scrolls;
+use common::decompiler::{ decompile, disassemble };
+use common::error::{Error};
+use std::{ io::Read, fs::File };
+use clap::{Arg, App};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("decompiler")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Lift UM programs into readable pseudo-code")
+        .arg(Arg::with_name("raw")
+            .short("r")
+            .long("raw")
+            .help("Print a plain disassembly of every platter instead of pseudo-code"))
+        .arg(Arg::with_name("FILE")
+            .help("Set the UM/UMZ program to decompile")
+            .required(true)
+            .index(1))
+        .get_matches();
+
+    let filename = opts.value_of("FILE").unwrap();
+
+    let mut file_data = vec![];
+    let mut file = File::open(filename)?;
+    file.read_to_end(&mut file_data)?;
+    let code = platters_from_scrolls(&file_data);
+
+    if opts.is_present("raw") {
+        print!("{}", disassemble(&code));
+    } else {
+        print!("{}", decompile(&code));
+    }
+    Ok(())
+}
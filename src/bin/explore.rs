@@ -0,0 +1,156 @@
+use common::program::Program;
+use common::input_fuzz;
+use common::snapshot;
+use common::explore::{ self, Patterns, Options };
+use common::error::{err, Error};
+use regex::bytes::Regex;
+use std::{ io::Read, io::Write, fs::File, path::Path, sync::Arc };
+use clap::{Arg, App};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("explore")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Search for a sequence of input lines which lead a UM program to some goal")
+        .arg(Arg::with_name("prelude")
+            .short("p")
+            .long("prelude")
+            .value_name("FILE")
+            .help("Input to feed the program before searching begins"))
+        .arg(Arg::with_name("candidates")
+            .short("c")
+            .long("candidates")
+            .value_name("FILE")
+            .required(true)
+            .help("A file of candidate input lines to try at each step"))
+        .arg(Arg::with_name("goal")
+            .short("g")
+            .long("goal")
+            .value_name("REGEX")
+            .required(true)
+            .help("Stop once the output from a line of input matches this"))
+        .arg(Arg::with_name("avoid")
+            .long("avoid")
+            .value_name("REGEX")
+            .help("Don't explore any further from output matching this"))
+        .arg(Arg::with_name("prefer")
+            .long("prefer")
+            .value_name("REGEX")
+            .help("Explore paths whose output matches this more often first"))
+        .arg(Arg::with_name("threads")
+            .short("j")
+            .long("threads")
+            .value_name("COUNT")
+            .default_value("4")
+            .help("How many worker threads to run candidates on"))
+        .arg(Arg::with_name("max-depth")
+            .long("max-depth")
+            .value_name("LINES")
+            .default_value("10")
+            .help("The most lines of input to try in a row"))
+        .arg(Arg::with_name("max-states")
+            .long("max-states")
+            .value_name("STATES")
+            .default_value("100000")
+            .help("The most distinct states to explore"))
+        .arg(Arg::with_name("max-steps")
+            .long("max-steps")
+            .value_name("STEPS")
+            .default_value("50000000")
+            .help("The most steps to run the program for on each line of input"))
+        .arg(Arg::with_name("save")
+            .short("s")
+            .long("save")
+            .value_name("FILE")
+            .help("Save a snapshot of the program once it reaches the goal"))
+        .arg(Arg::with_name("FILE")
+            .help("Set the UM/UMZ program or snapshot to start from")
+            .required(true)
+            .index(1))
+        .get_matches();
+
+    let number = |name: &str| -> Result<usize, Error> {
+        opts.value_of(name).unwrap().parse::<usize>().map_err(|_| err(format!("{} should be a number", name)))
+    };
+    let options = Options {
+        threads: number("threads")?,
+        max_depth: number("max-depth")?,
+        max_states: number("max-states")?,
+        max_steps: number("max-steps")?
+    };
+
+    let regex = |name: &str| -> Result<Option<Regex>, Error> {
+        match opts.value_of(name) {
+            Some(r) => Regex::new(r).map(Some).map_err(|e| err(format!("{} is not a valid regex: {}", name, e))),
+            None => Ok(None)
+        }
+    };
+    let patterns = Patterns {
+        goal: regex("goal")?.unwrap(),
+        avoid: regex("avoid")?,
+        prefer: regex("prefer")?
+    };
+
+    // Each line of the candidates file is one thing to try:
+    let candidates: Vec<Vec<u8>> = read_file(Path::new(opts.value_of("candidates").unwrap()))?
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| { let mut line = line.to_vec(); line.push(b'\n'); line })
+        .collect();
+    if candidates.is_empty() {
+        return Err(err("no candidate lines given"));
+    }
+
+    // Start from a snapshot or a fresh program, feeding it any prelude,
+    // until it's waiting on input:
+    let file = read_file(Path::new(opts.value_of("FILE").unwrap()))?;
+    let mut prelude = match opts.value_of("prelude") {
+        Some(path) => read_file(Path::new(path))?,
+        None => vec![]
+    };
+    let (program, waiting) = if snapshot::is_snapshot(&file) {
+        snapshot::restore(&file)?
+    } else {
+        let mut program = Program::new();
+        program.load_program(&file);
+        (program, None)
+    };
+    let (program, waiting) = match waiting {
+        Some(waiting) if prelude.is_empty() => (program, waiting),
+        waiting => {
+            let mut program = program;
+            if let Some(waiting) = waiting {
+                program.provide_input(waiting, Some(prelude.remove(0)));
+            }
+            let (program, waiting, output) = input_fuzz::prepare(program, &prelude, options.max_steps).map_err(err)?;
+            eprintln!("{}", String::from_utf8_lossy(&output));
+            (program, waiting)
+        }
+    };
+
+    let (found, summary) = explore::search(program, waiting, candidates.clone(), Arc::new(patterns), &options);
+    eprint!("{}", summary);
+
+    let found = found.ok_or_else(|| err("no sequence of input reached the goal"))?;
+    eprintln!("Reached the goal after {} lines of input:", found.inputs.len());
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for &idx in &found.inputs {
+        stdout.write_all(&candidates[idx])?;
+    }
+    eprintln!("\nWhich output:\n{}", String::from_utf8_lossy(&found.output));
+
+    if let Some(path) = opts.value_of("save") {
+        File::create(path)?.write_all(&snapshot::save(&found.program, found.waiting))?;
+    }
+
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
@@ -0,0 +1,141 @@
+use common::program::Program;
+use common::input_fuzz::{ self, Fuzzer, Finding, Ending, Run };
+use common::rng::Rng;
+use common::error::{err, Error};
+use std::{ io::Read, io::Write, fs, fs::File, path::Path };
+use clap::{Arg, App};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("fuzz_input")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Coverage guided fuzzing of the input given to a UM program")
+        .arg(Arg::with_name("prelude")
+            .short("p")
+            .long("prelude")
+            .value_name("FILE")
+            .help("Input to feed the program before fuzzing begins (eg to log in)"))
+        .arg(Arg::with_name("seeds")
+            .long("seeds")
+            .value_name("DIR")
+            .help("A folder of inputs to start fuzzing from"))
+        .arg(Arg::with_name("out")
+            .short("o")
+            .long("out")
+            .value_name("DIR")
+            .default_value("fuzz-output")
+            .help("Where to save interesting inputs"))
+        .arg(Arg::with_name("max-steps")
+            .long("max-steps")
+            .value_name("STEPS")
+            .default_value("50000000")
+            .help("The most steps to run the program for on each input"))
+        .arg(Arg::with_name("runs")
+            .short("n")
+            .long("runs")
+            .value_name("COUNT")
+            .help("How many inputs to try before stopping (defaults to running forever)"))
+        .arg(Arg::with_name("seed")
+            .short("s")
+            .long("seed")
+            .value_name("SEED")
+            .help("Seed for mutating inputs (defaults to the current time)"))
+        .arg(Arg::with_name("FILE")
+            .help("Set the UM/UMZ program to fuzz")
+            .required(true)
+            .index(1))
+        .get_matches();
+
+    let max_steps = opts.value_of("max-steps").unwrap().parse::<usize>().map_err(|_| "max-steps should be a number")?;
+    let runs = match opts.value_of("runs") {
+        Some(n) => Some(n.parse::<u64>().map_err(|_| "runs should be a number")?),
+        None => None
+    };
+    let seed = match opts.value_of("seed") {
+        Some(seed) => seed.parse::<u64>().map_err(|_| "seed should be a number")?,
+        None => Rng::from_time().next_u64()
+    };
+
+    let out = Path::new(opts.value_of("out").unwrap());
+    for dir in &["corpus", "faults", "outputs"] {
+        fs::create_dir_all(out.join(dir))?;
+    }
+
+    // Load the program and run it up to the point we want to fuzz from:
+    let mut program = Program::new();
+    program.load_program(&read_file(Path::new(opts.value_of("FILE").unwrap()))?);
+    let prelude = match opts.value_of("prelude") {
+        Some(path) => read_file(Path::new(path))?,
+        None => vec![]
+    };
+    let (base, inputter, output) = input_fuzz::prepare(program, &prelude, max_steps).map_err(err)?;
+    eprintln!("{}", String::from_utf8_lossy(&output));
+
+    eprintln!("Fuzzing with seed {}", seed);
+    let mut fuzzer = Fuzzer::new(base, inputter, max_steps, Rng::new(seed));
+
+    // Start with any seeds we've been given, or else just an empty line:
+    let mut seeds = vec![];
+    if let Some(dir) = opts.value_of("seeds") {
+        for entry in fs::read_dir(dir)? {
+            seeds.push(read_file(&entry?.path())?);
+        }
+    }
+    if seeds.is_empty() {
+        seeds.push(b"\n".to_vec());
+    }
+    for input in seeds {
+        let (run, findings) = fuzzer.add_seed(input.clone());
+        save(out, &input, &run, &findings)?;
+    }
+
+    let mut n = 0u64;
+    let mut faults = 0u64;
+    while runs.map(|r| n < r).unwrap_or(true) {
+
+        let (input, run, findings) = fuzzer.fuzz_one();
+        if let Ending::Fault(..) = run.ending {
+            faults += 1;
+        }
+        save(out, &input, &run, &findings)?;
+
+        n += 1;
+        if n % 100 == 0 {
+            eprintln!("{} runs, {} fingers covered, {} inputs in corpus, {} faults",
+                n, fuzzer.coverage_len(), fuzzer.corpus_len(), faults);
+        }
+    }
+
+    Ok(())
+}
+
+/// Save an input, alongside what it produced, into the folder
+/// for each kind of thing it found.
+fn save(out: &Path, input: &[u8], run: &Run, findings: &[Finding]) -> Result<(), Error> {
+    let name = format!("{:016x}", input_fuzz::content_hash(input));
+    for finding in findings {
+        let (dir, extra) = match finding {
+            Finding::NewCoverage(..) => ("corpus", None),
+            Finding::Fault(reason) => {
+                eprintln!("Fault: {} (saved as {})", reason, name);
+                ("faults", Some(reason.as_bytes()))
+            },
+            Finding::NewOutput(lines) => ("outputs", Some(&lines[..]))
+        };
+        let dir = out.join(dir);
+        File::create(dir.join(format!("{}.input", name)))?.write_all(input)?;
+        File::create(dir.join(format!("{}.output", name)))?.write_all(&run.output)?;
+        if let Some(extra) = extra {
+            File::create(dir.join(format!("{}.found", name)))?.write_all(extra)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
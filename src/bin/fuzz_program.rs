@@ -0,0 +1,92 @@
+use common::fuzz::{ self, Case };
+use common::decompiler::disassemble;
+use common::rng::Rng;
+use common::error::{err, Error};
+use std::{ io::Read, io::Write, fs::File };
+use clap::{Arg, App};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("fuzz_program")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Run random UM programs in the interpreter and a reference implementation, looking for differences")
+        .arg(Arg::with_name("seed")
+            .short("s")
+            .long("seed")
+            .value_name("SEED")
+            .help("Seed for generating cases (defaults to the current time)"))
+        .arg(Arg::with_name("cases")
+            .short("n")
+            .long("cases")
+            .value_name("COUNT")
+            .help("How many cases to run before stopping (defaults to running forever)"))
+        .arg(Arg::with_name("max-steps")
+            .long("max-steps")
+            .value_name("STEPS")
+            .default_value("10000")
+            .help("The most steps to run each case for"))
+        .arg(Arg::with_name("save")
+            .long("save")
+            .value_name("FILE")
+            .default_value("divergence.case")
+            .help("Where to save the first case that diverges"))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .value_name("FILE")
+            .help("Run a single saved case (or libFuzzer artifact) instead of generating them"))
+        .get_matches();
+
+    let max_steps = opts.value_of("max-steps").unwrap().parse::<usize>().map_err(|_| "max-steps should be a number")?;
+
+    // Replay a case that's been saved before:
+    if let Some(replay) = opts.value_of("replay") {
+        let mut data = vec![];
+        File::open(replay)?.read_to_end(&mut data)?;
+        let case = Case::from_bytes(&data);
+        return match fuzz::check(&case, max_steps) {
+            Ok(()) => { println!("No divergence found"); Ok(()) },
+            Err(divergence) => { report(&case, &divergence); Err(err("the case diverged")) }
+        };
+    }
+
+    let seed = match opts.value_of("seed") {
+        Some(seed) => seed.parse::<u64>().map_err(|_| "seed should be a number")?,
+        None => Rng::from_time().next_u64()
+    };
+    let cases = match opts.value_of("cases") {
+        Some(n) => Some(n.parse::<u64>().map_err(|_| "cases should be a number")?),
+        None => None
+    };
+
+    eprintln!("Fuzzing with seed {}", seed);
+    let mut rng = Rng::new(seed);
+    let mut n = 0u64;
+    while cases.map(|c| n < c).unwrap_or(true) {
+
+        let case = Case::generate(&mut rng);
+        if let Err(divergence) = fuzz::check(&case, max_steps) {
+            let path = opts.value_of("save").unwrap();
+            File::create(path)?.write_all(&case.to_bytes())?;
+            eprintln!("Case {} diverged; saved to {}", n, path);
+            report(&case, &divergence);
+            std::process::exit(1);
+        }
+
+        n += 1;
+        if n % 100_000 == 0 {
+            eprintln!("{} cases run", n);
+        }
+    }
+
+    eprintln!("{} cases run without divergence", n);
+    Ok(())
+}
+
+fn report(case: &Case, divergence: &fuzz::Divergence) {
+    println!("{}", divergence);
+    println!("input: {:?}", case.input);
+    println!("program:");
+    print!("{}", disassemble(&case.program));
+}
@@ -2,11 +2,26 @@
 #![feature(await_macro, async_await, futures_api)]
 
 use common::program::{Program, StepResult};
-use common::error::{Error};
-use common::io::IoHandler;
-use std::{ io::Read, fs::File };
+use common::instruction::Instruction;
+use common::error::{err, Error};
+use common::io::{IoHandler, IoOptions};
+use common::broadcaster::Backpressure;
+use common::event::Event;
+use common::tls::Tls;
+use common::auth::Auth;
+use common::api::Api;
+use common::control::Controls;
+use common::metrics::{ Exporter, Metrics };
+use common::mi;
+use std::{ io::Read, fs::File, path::Path, time::Duration };
 use clap::{Arg, App};
 
+// How many steps to take between answering anything asked of the program...
+const CONTROL_EVERY: usize = 4096;
+
+// ...and how long to wait for input before answering anyway:
+const CONTROL_INTERVAL: Duration = Duration::from_millis(50);
+
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
@@ -22,6 +37,101 @@ fn main() -> Result<(), Error> {
             .long("address")
             .value_name("ADDRESS")
             .help("Provide an address to listen on to allow TCP connections to take hold of input/output"))
+        .arg(Arg::with_name("telnet")
+            .long("telnet")
+            .value_name("MODE")
+            .possible_values(&["line", "character"])
+            .help("Speak telnet to TCP connections, having clients send input a line or a character at a time"))
+        .arg(Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .value_name("FILE")
+            .requires_all(&["tls-key", "address"])
+            .help("Terminate TLS on TCP connections using this PEM encoded certificate (chain)"))
+        .arg(Arg::with_name("tls-key")
+            .long("tls-key")
+            .value_name("FILE")
+            .requires("tls-cert")
+            .help("The PEM encoded private key to go with --tls-cert"))
+        .arg(Arg::with_name("password-file")
+            .long("password-file")
+            .value_name("FILE")
+            .help("Require network connections to send the password in this file (on a line of its own) before they can see output or send input"))
+        .arg(Arg::with_name("web")
+            .short("w")
+            .long("web")
+            .value_name("ADDRESS")
+            .help("Provide an address to serve a web terminal on, to use the program from a browser"))
+        .arg(Arg::with_name("api")
+            .long("api")
+            .value_name("ADDRESS")
+            .help("Provide an address to serve a JSON API on, for inspecting and controlling the program (eg 127.0.0.1:8081)"))
+        .arg(Arg::with_name("metrics")
+            .long("metrics")
+            .value_name("ADDRESS")
+            .help("Provide an address to serve metrics on at /metrics, in Prometheus' text format (eg 127.0.0.1:9100)"))
+        .arg(Arg::with_name("unix")
+            .short("u")
+            .long("unix")
+            .value_name("PATH")
+            .help("Provide a path to listen on a unix domain socket, to let local connections take hold of input/output"))
+        .arg(Arg::with_name("unix-mode")
+            .long("unix-mode")
+            .value_name("MODE")
+            .default_value("600")
+            .help("The permissions (in octal) to give the unix domain socket"))
+        .arg(Arg::with_name("stdin-discipline")
+            .long("stdin-discipline")
+            .value_name("OPTIONS")
+            .default_value("raw")
+            .help("How to tidy up input from stdin: 'raw', or any of 'crlf', 'edit' and 'echo' separated by commas"))
+        .arg(Arg::with_name("escape")
+            .long("escape")
+            .value_name("CHAR")
+            .help("The character which begins console commands at the start of a line, or 'none' (defaults to ~ when stdin is a terminal, and none otherwise)"))
+        .arg(Arg::with_name("network-discipline")
+            .long("network-discipline")
+            .value_name("OPTIONS")
+            .default_value("crlf")
+            .help("How to tidy up input from network connections: 'raw', or any of 'crlf', 'edit' and 'echo' separated by commas"))
+        .arg(Arg::with_name("role")
+            .long("role")
+            .value_name("ROLE")
+            .default_value("controller")
+            .possible_values(&["controller", "spectator"])
+            .help("Whether network connections can send input ('controller') or only watch ('spectator')"))
+        .arg(Arg::with_name("floor")
+            .long("floor")
+            .value_name("POLICY")
+            .default_value("shared")
+            .possible_values(&["shared", "exclusive"])
+            .help("Whether controllers share input a line at a time ('shared'), or take turns ('exclusive')"))
+        .arg(Arg::with_name("scrollback")
+            .long("scrollback")
+            .value_name("BYTES")
+            .default_value("4096")
+            .help("How many bytes of recent output to replay to each new connection"))
+        .arg(Arg::with_name("slow-clients")
+            .long("slow-clients")
+            .value_name("POLICY")
+            .default_value("disconnect")
+            .possible_values(&["block", "drop-oldest", "disconnect"])
+            .help("What to do when a connection can't keep up with output"))
+        .arg(Arg::with_name("client-buffer")
+            .long("client-buffer")
+            .value_name("BYTES")
+            .default_value("1048576")
+            .help("How many bytes of output can be waiting for a connection before it counts as slow"))
+        .arg(Arg::with_name("input-buffer")
+            .long("input-buffer")
+            .value_name("BYTES")
+            .default_value("65536")
+            .help("How many bytes of input can be waiting for the program before we stop reading more"))
+        .arg(Arg::with_name("show-events")
+            .long("show-events")
+            .help("Show events, like the program halting or clients connecting, on stdout and to plain TCP connections"))
+        .arg(Arg::with_name("mi")
+            .long("mi")
+            .help("Drive the program with JSON-lines commands on stdin, and get JSON-lines replies and events on stdout, instead of using it as a terminal"))
         .arg(Arg::with_name("FILE")
             .help("Set the UM/UMZ program to interpret")
             .required(true)
@@ -29,14 +139,6 @@ fn main() -> Result<(), Error> {
         .get_matches();
 
     let filename = opts.value_of("FILE").unwrap();
-    let address = if let Some(addr) = opts.value_of("address") {
-        Some(addr.parse::<std::net::SocketAddr>()?)
-    } else {
-        None
-    };
-
-    // handle in/out via separate thread.
-    let io = IoHandler::start(address);
 
     // Create new interpreter and read data into it:
     let mut program = Program::new();
@@ -45,18 +147,166 @@ fn main() -> Result<(), Error> {
     file.read_to_end(&mut file_data)?;
     program.load_program(&file_data);
 
-    // Run instructions and handle the result:
-    loop {
-        match program.step()? {
+    // With the machine interface, stdin and stdout carry commands and
+    // events, so none of the usual IO handling is needed:
+    if opts.is_present("mi") {
+        let stdin = std::io::BufReader::new(std::io::stdin());
+        return mi::run(program, None, stdin, std::io::stdout());
+    }
+
+    let address = if let Some(addr) = opts.value_of("address") {
+        Some(addr.parse::<std::net::SocketAddr>()?)
+    } else {
+        None
+    };
+    let web = if let Some(addr) = opts.value_of("web") {
+        Some(addr.parse::<std::net::SocketAddr>()?)
+    } else {
+        None
+    };
+    let (controller, mut controls) = Controls::new();
+    let api = if let Some(addr) = opts.value_of("api") {
+        Some(Api{ address: addr.parse::<std::net::SocketAddr>()?, controller: controller.clone() })
+    } else {
+        None
+    };
+    let metrics = if let Some(addr) = opts.value_of("metrics") {
+        Some(Exporter{ address: addr.parse::<std::net::SocketAddr>()?, metrics: Metrics::new() })
+    } else {
+        None
+    };
+    let mut recorder = metrics.as_ref().map(|exporter| exporter.metrics.recorder());
+    let unix = opts.value_of("unix").map(std::path::PathBuf::from);
+    let unix_mode = u32::from_str_radix(opts.value_of("unix-mode").unwrap(), 8)
+        .map_err(|_| "unix-mode should be an octal number like 600")?;
+    let tls = match (opts.value_of("tls-cert"), opts.value_of("tls-key")) {
+        (Some(cert), Some(key)) => Some(Tls::from_files(Path::new(cert), Path::new(key))?),
+        _ => None
+    };
+    let auth = match opts.value_of("password-file") {
+        Some(path) => Some(Auth::from_file(Path::new(path))?),
+        None => None
+    };
+    let telnet = if let Some(mode) = opts.value_of("telnet") {
+        Some(mode.parse().map_err(err)?)
+    } else {
+        None
+    };
+
+    let stdin = opts.value_of("stdin-discipline").unwrap().parse().map_err(err)?;
+    let network = opts.value_of("network-discipline").unwrap().parse().map_err(err)?;
+    let escape = match opts.value_of("escape") {
+        None if atty::is(atty::Stream::Stdin) => Some(b'~'),
+        None | Some("none") => None,
+        Some(c) if c.len() == 1 && c.is_ascii() => Some(c.as_bytes()[0]),
+        Some(c) => return Err(err(format!("'{}' is not an escape character (expected a single character, or 'none')", c)))
+    };
+    let role = opts.value_of("role").unwrap().parse().map_err(err)?;
+    let floor = opts.value_of("floor").unwrap().parse().map_err(err)?;
+    let scrollback = opts.value_of("scrollback").unwrap().parse::<usize>()
+        .map_err(|_| "scrollback should be a number of bytes")?;
+    let backpressure = Backpressure {
+        overflow: opts.value_of("slow-clients").unwrap().parse().map_err(err)?,
+        limit: opts.value_of("client-buffer").unwrap().parse::<usize>()
+            .map_err(|_| "client-buffer should be a number of bytes")?
+    };
+    let input_buffer = opts.value_of("input-buffer").unwrap().parse::<usize>()
+        .map_err(|_| "input-buffer should be a number of bytes")?;
+
+    // handle in/out via separate thread.
+    let io = IoHandler::start(IoOptions {
+        address,
+        telnet,
+        tls,
+        auth,
+        web,
+        api,
+        metrics,
+        unix,
+        unix_mode: Some(unix_mode),
+        stdin,
+        network,
+        role,
+        floor,
+        scrollback,
+        backpressure,
+        input_buffer: Some(input_buffer),
+        show_events: opts.is_present("show-events"),
+        console: Some(controller),
+        escape,
+        detached: false
+    })?;
+
+    // Run instructions and handle the result, answering anything asked of the
+    // program every so often. Whatever happens, everybody watching is told
+    // about it before the IO handler is closed:
+    let mut cycles = 0;
+    'running: loop {
+        if cycles % CONTROL_EVERY == 0 {
+            controls.serve(&program, None, cycles, &io);
+            if let Some(recorder) = &mut recorder {
+                recorder.record(&program, cycles, io.pending_input());
+            }
+            if controls.quitting() {
+                io.event(Event::Stopped)?;
+                break;
+            }
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder.step(&program);
+        }
+        if controls.tracing() {
+            trace(&program);
+        }
+        cycles += 1;
+        let result = match program.step() {
+            Ok(result) => result,
+            Err(e) => {
+                let _ = io.event(Event::Faulted(e.message().to_string()));
+                io.block_until_closed();
+                return Err(e);
+            }
+        };
+        match result {
             StepResult::Halted => {
+                io.event(Event::Halted)?;
                 break;
             },
             StepResult::Output{ ascii } => {
+                if let Some(recorder) = &mut recorder {
+                    recorder.output();
+                }
                 io.send(ascii)?;
             },
             StepResult::InputNeeded{ inputter } => {
-                let byte = io.recv()?;
-                program.provide_input(inputter, Some(byte));
+                let byte = loop {
+                    match io.recv_timeout(CONTROL_INTERVAL) {
+                        // Once input is closed, the program is sent EOF:
+                        Ok(Some(byte)) => break byte,
+                        Err(e) => {
+                            io.block_until_closed();
+                            return Err(e);
+                        },
+                        Ok(None) => {
+                            controls.serve(&program, Some(inputter), cycles, &io);
+                            if let Some(recorder) = &mut recorder {
+                                recorder.record(&program, cycles, io.pending_input());
+                            }
+                            if controls.quitting() {
+                                io.event(Event::Stopped)?;
+                                break 'running;
+                            }
+                            // EOF is only sent once there's no input left to read first:
+                            if controls.take_eof() {
+                                break None;
+                            }
+                        }
+                    }
+                };
+                if let (Some(recorder), Some(_)) = (&mut recorder, byte) {
+                    recorder.input();
+                }
+                program.provide_input(inputter, byte);
             },
             StepResult::Continue => {}
         }
@@ -68,4 +318,13 @@ fn main() -> Result<(), Error> {
     // that it's done:
     io.block_until_closed();
     Ok(())
-}
\ No newline at end of file
+}
+
+// Show the instruction the program is about to execute, on stderr
+// so as not to get mixed up with its output:
+fn trace(program: &Program) {
+    let finger = program.instruction_index();
+    if let Some(&platter) = program.array(0).and_then(|code| code.get(finger)) {
+        eprintln!("[trace] {:>8}: {}", finger, Instruction::decode(platter));
+    }
+}
@@ -0,0 +1,157 @@
+use common::program::Program;
+use common::input_fuzz;
+use common::symbolic::{ self, State, Goal, Limits };
+use common::error::{err, Error};
+use std::{ io::Read, io::Write, fs, fs::File, path::Path };
+use clap::{Arg, App, ArgGroup};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("symbolic")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Symbolically execute a UM program to find input that reaches some goal")
+        .arg(Arg::with_name("prelude")
+            .short("p")
+            .long("prelude")
+            .value_name("FILE")
+            .help("Input to feed the program before symbolic execution begins"))
+        .arg(Arg::with_name("finger")
+            .long("finger")
+            .value_name("FINGER")
+            .help("Find input which leads to this instruction (eg 0x1a4)"))
+        .arg(Arg::with_name("output")
+            .long("output")
+            .value_name("TEXT")
+            .help("Find input which leads to this being output"))
+        .group(ArgGroup::with_name("goal")
+            .args(&["finger", "output"])
+            .required(true))
+        .arg(Arg::with_name("solver")
+            .long("solver")
+            .value_name("COMMAND")
+            .default_value("z3 -in")
+            .help("An SMT solver which reads SMT-LIB queries on stdin"))
+        .arg(Arg::with_name("out")
+            .short("o")
+            .long("out")
+            .value_name("DIR")
+            .default_value("symbolic-output")
+            .help("Where to save queries and any input found"))
+        .arg(Arg::with_name("max-inputs")
+            .long("max-inputs")
+            .value_name("BYTES")
+            .default_value("64")
+            .help("The most bytes of symbolic input to give the program on each path"))
+        .arg(Arg::with_name("max-steps")
+            .long("max-steps")
+            .value_name("STEPS")
+            .default_value("10000000")
+            .help("The most steps to take down each path"))
+        .arg(Arg::with_name("max-paths")
+            .long("max-paths")
+            .value_name("PATHS")
+            .default_value("1000")
+            .help("The most paths to explore"))
+        .arg(Arg::with_name("all")
+            .long("all")
+            .help("Keep looking for more inputs once one has been found"))
+        .arg(Arg::with_name("FILE")
+            .help("Set the UM/UMZ program to execute")
+            .required(true)
+            .index(1))
+        .get_matches();
+
+    let number = |name: &str| -> Result<usize, Error> {
+        opts.value_of(name).unwrap().parse::<usize>().map_err(|_| err(format!("{} should be a number", name)))
+    };
+    let limits = Limits {
+        max_steps: number("max-steps")?,
+        max_paths: number("max-paths")?
+    };
+    let max_inputs = number("max-inputs")?;
+    let solver = opts.value_of("solver").unwrap();
+    let find_all = opts.is_present("all");
+
+    let goal = match (opts.value_of("finger"), opts.value_of("output")) {
+        (Some(finger), _) => Goal::Finger(parse_finger(finger)?),
+        (_, Some(text)) => Goal::Output(text.as_bytes().to_vec()),
+        _ => unreachable!("clap ensures a goal is given")
+    };
+
+    let out = Path::new(opts.value_of("out").unwrap());
+    fs::create_dir_all(out)?;
+
+    // Load the program, and if given a prelude, run it up to the point where it wants
+    // more input than that. Otherwise, start from the very beginning:
+    let mut program = Program::new();
+    program.load_program(&read_file(Path::new(opts.value_of("FILE").unwrap()))?);
+    let start = match opts.value_of("prelude") {
+        Some(path) => {
+            let prelude = read_file(Path::new(path))?;
+            let (program, inputter, output) = input_fuzz::prepare(program, &prelude, limits.max_steps).map_err(err)?;
+            eprintln!("{}", String::from_utf8_lossy(&output));
+            State::from_program(&program, Some(inputter), max_inputs)
+        },
+        None => State::from_program(&program, None, max_inputs)
+    };
+
+    // Each path that reaches the goal is handed to the solver to see whether there's
+    // some input that actually leads down it. Queries and inputs are saved in files
+    // numbered by path, so that queries for paths which can't be followed are kept too:
+    let (mut paths, mut found) = (0, 0);
+    let mut failure = None;
+    let summary = symbolic::explore(start, &goal, &limits, |reached| {
+        let query = symbolic::smt_query(&reached.state, &reached.extra);
+        let name = format!("path-{:04}", paths);
+        paths += 1;
+        if let Err(e) = File::create(out.join(format!("{}.smt2", name))).and_then(|mut f| f.write_all(query.as_bytes())) {
+            failure = Some(err(e.to_string()));
+            return false;
+        }
+        match symbolic::solve(&query, reached.state.inputs, solver) {
+            Ok(Some(input)) => {
+                eprintln!("Found input after {} steps: {:?}", reached.state.steps, String::from_utf8_lossy(&input));
+                if let Err(e) = File::create(out.join(format!("{}.input", name))).and_then(|mut f| f.write_all(&input)) {
+                    failure = Some(err(e.to_string()));
+                    return false;
+                }
+                found += 1;
+                find_all
+            },
+            Ok(None) => {
+                eprintln!("A path reached the goal, but no input can lead down it");
+                true
+            },
+            Err(e) => {
+                failure = Some(err(e));
+                false
+            }
+        }
+    });
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    eprint!("{}", summary);
+    if found == 0 {
+        return Err(err("no input found which reaches the goal"));
+    }
+    Ok(())
+}
+
+fn parse_finger(s: &str) -> Result<usize, Error> {
+    let parsed = if s.starts_with("0x") {
+        usize::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<usize>()
+    };
+    parsed.map_err(|_| err("finger should be a number"))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
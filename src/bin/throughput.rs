@@ -0,0 +1,57 @@
+use common::io::{IoHandler, IoOptions};
+use common::error::Error;
+use std::time::Instant;
+use clap::{Arg, App};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("throughput")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Measure how quickly output makes it through the interpreter's IO handling. \
+                Output goes to stdout (so send it to /dev/null or pipe it somewhere), \
+                and the results are printed to stderr")
+        .arg(Arg::with_name("megabytes")
+            .short("m")
+            .long("megabytes")
+            .value_name("MB")
+            .default_value("64")
+            .help("How much output to send"))
+        .arg(Arg::with_name("address")
+            .short("a")
+            .long("address")
+            .value_name("ADDRESS")
+            .help("Also send output to anything connected to this address (eg 127.0.0.1:8080)"))
+        .get_matches();
+
+    let megabytes = opts.value_of("megabytes").unwrap().parse::<usize>()
+        .map_err(|_| "megabytes should be a number")?;
+    let address = if let Some(addr) = opts.value_of("address") {
+        Some(addr.parse::<std::net::SocketAddr>()?)
+    } else {
+        None
+    };
+
+    let io = IoHandler::start(IoOptions {
+        address,
+        ..IoOptions::default()
+    })?;
+
+    // Something that looks a bit like program output; lines of text:
+    let line = b"The quick brown fox jumps over the lazy dog. 0123456789\n";
+    let total = megabytes * 1024 * 1024;
+
+    let start = Instant::now();
+    for byte in line.iter().cycle().take(total) {
+        io.send(*byte)?;
+    }
+    let sent = start.elapsed();
+    io.block_until_closed();
+    let done = start.elapsed();
+
+    let secs = |d: std::time::Duration| d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9;
+    eprintln!("sent {}MB in {:.3}s ({:.1}MB/s)", megabytes, secs(sent), megabytes as f64 / secs(sent));
+    eprintln!("flushed in {:.3}s ({:.1}MB/s overall)", secs(done), megabytes as f64 / secs(done));
+    Ok(())
+}
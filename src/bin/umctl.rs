@@ -0,0 +1,87 @@
+use common::daemon::{ self, Command };
+use common::error::{err, Error};
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::os::unix::net::UnixStream;
+use std::net::Shutdown;
+use std::path::Path;
+use clap::{Arg, App, SubCommand};
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let name = || Arg::with_name("NAME").help("The name of the machine").required(true).index(1);
+    let opts = App::new("umctl")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Start, list, attach to and stop UM machines running in umd")
+        .arg(Arg::with_name("socket")
+            .short("s")
+            .long("socket")
+            .value_name("PATH")
+            .global(true)
+            .help("The unix domain socket umd is listening on (defaults to umd.sock in $XDG_RUNTIME_DIR)"))
+        .subcommand(SubCommand::with_name("start")
+            .about("Start a new machine from a UM program or a snapshot")
+            .arg(name())
+            .arg(Arg::with_name("FILE").help("The UM/UMZ program or snapshot to start from").required(true).index(2)))
+        .subcommand(SubCommand::with_name("list")
+            .about("List machines, with their status and how many cycles they've run for"))
+        .subcommand(SubCommand::with_name("attach")
+            .about("Attach this terminal to a machine. Close input (Ctrl+D) to detach, leaving it running")
+            .arg(name()))
+        .subcommand(SubCommand::with_name("stop")
+            .about("Stop a machine and forget about it")
+            .arg(name()))
+        .get_matches();
+
+    let command = match opts.subcommand() {
+        ("start", Some(sub)) => {
+            // The daemon is likely somewhere else, so it's told exactly where the file is:
+            let path = Path::new(sub.value_of("FILE").unwrap()).canonicalize()?;
+            Command::Start{ name: sub.value_of("NAME").unwrap().to_string(), path }
+        },
+        ("list", _) => Command::List,
+        ("attach", Some(sub)) => Command::Attach{ name: sub.value_of("NAME").unwrap().to_string() },
+        ("stop", Some(sub)) => Command::Stop{ name: sub.value_of("NAME").unwrap().to_string() },
+        _ => return Err(err("expected a command; try --help"))
+    };
+
+    let path = opts.value_of("socket").map(std::path::PathBuf::from).unwrap_or_else(daemon::default_socket);
+    let mut sock = UnixStream::connect(&path)
+        .map_err(|e| err(format!("can't reach umd at {}: {}", path.display(), e)))?;
+    sock.write_all(format!("{}\n", command).as_bytes())?;
+
+    // The first line of the reply tells us how it went:
+    let mut reply = BufReader::new(sock.try_clone()?);
+    let mut status = String::new();
+    reply.read_line(&mut status)?;
+    match status.trim_end() {
+        "ok" => {},
+        "" => return Err(err("umd hung up without replying")),
+        status => return Err(err(status.trim_start_matches("error: ")))
+    }
+
+    // Once attached, our input is sent on until we close it (detaching),
+    // and output is shown until the machine is done with us:
+    if let Command::Attach{ .. } = command {
+        std::thread::spawn(move || {
+            let _ = std::io::copy(&mut std::io::stdin(), &mut sock);
+            let _ = sock.shutdown(Shutdown::Both);
+            eprintln!("\r\n[detached]");
+            std::process::exit(0);
+        });
+    }
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut buf = [0u8; 8 * 1024];
+    loop {
+        let n = reply.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
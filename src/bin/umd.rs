@@ -0,0 +1,78 @@
+// For async/await lark:
+#![feature(await_macro, async_await, futures_api)]
+
+use common::daemon::{ self, Machines };
+use common::error::{err, Error};
+use common::io::IoOptions;
+use common::io_extra;
+use tokio::prelude::*;
+use tokio_signal::unix::{ Signal, SIGTERM };
+use clap::{Arg, App};
+
+#[global_allocator]
+static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+fn main() -> Result<(), Error> {
+
+    // Parse args and provide program help/info on load:
+    let opts = App::new("umd")
+        .version("0.1")
+        .author("James Wilson <me@jsdw.me>")
+        .about("Run named UM machines in the background, to be started, listed, attached to and stopped with umctl")
+        .arg(Arg::with_name("socket")
+            .short("s")
+            .long("socket")
+            .value_name("PATH")
+            .help("The unix domain socket to listen for commands on (defaults to umd.sock in $XDG_RUNTIME_DIR)"))
+        .arg(Arg::with_name("network-discipline")
+            .long("network-discipline")
+            .value_name("OPTIONS")
+            .default_value("crlf")
+            .help("How to tidy up input from attached terminals: 'raw', or any of 'crlf', 'edit' and 'echo' separated by commas"))
+        .arg(Arg::with_name("scrollback")
+            .long("scrollback")
+            .value_name("BYTES")
+            .default_value("4096")
+            .help("How many bytes of recent output to replay to each terminal which attaches"))
+        .get_matches();
+
+    let path = opts.value_of("socket").map(std::path::PathBuf::from).unwrap_or_else(daemon::default_socket);
+    let network = opts.value_of("network-discipline").unwrap().parse().map_err(err)?;
+    let scrollback = opts.value_of("scrollback").unwrap().parse::<usize>()
+        .map_err(|_| "scrollback should be a number of bytes")?;
+
+    let machines = Machines::new(IoOptions {
+        network,
+        scrollback,
+        ..IoOptions::default()
+    });
+
+    // Only we get to send commands:
+    let listener = io_extra::bind_unix(&path, Some(0o600))?;
+    eprintln!("Listening for commands on {}", path.display());
+
+    tokio::run_async(async move {
+
+        // Once interrupted or terminated, the socket is cleared away as we go:
+        let interrupted = tokio_signal::ctrl_c().flatten_stream();
+        let terminated = Signal::new(SIGTERM).flatten_stream().map(|_| ());
+        let stopped = interrupted.select(terminated).into_future();
+
+        tokio::spawn_async(async move {
+            let mut connections = listener.incoming();
+            while let Some(sock) = await!(connections.next()) {
+                match sock {
+                    Err(e) => eprintln!("Error opening socket: {:?}", e),
+                    Ok(sock) => {
+                        let (reader, writer) = sock.split();
+                        daemon::serve(reader, writer, machines.clone());
+                    }
+                }
+            }
+        });
+        let _ = await!(stopped);
+        let _ = std::fs::remove_file(&path);
+        std::process::exit(0);
+    });
+    Ok(())
+}
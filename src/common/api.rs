@@ -0,0 +1,273 @@
+use crate::auth::Peer;
+use crate::broadcaster::Broadcaster;
+use crate::connection::Context;
+use crate::control::{ Controller, Request, Reply, Status };
+use crate::event::Message;
+use crate::io_extra;
+use crate::web::response;
+use tokio::prelude::*;
+use futures::sync::mpsc;
+use bytes::Bytes;
+use serde_json::{ json, Value };
+use std::net::SocketAddr;
+
+// Requests (besides their bodies) longer than this are given up on:
+const MAX_REQUEST_LEN: usize = 8192;
+
+// Bodies longer than this are refused:
+const MAX_BODY: usize = 1024 * 1024;
+
+// At most this many platters are read from an array in one go:
+const MAX_RANGE: usize = 64 * 1024;
+
+/// Where to serve a JSON API for inspecting and controlling the program,
+/// and how to reach the program to answer it.
+#[derive(Debug, Clone)]
+pub struct Api {
+    pub address: SocketAddr,
+    pub controller: Controller
+}
+
+/// What a request to the API is asking for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    Status,
+    Input,
+    Pause,
+    Resume,
+    Snapshot,
+    Array{ id: usize, start: usize, len: usize },
+    Output,
+    NotFound,
+    Bad
+}
+
+/// The parts of an HTTP request (everything up to and including the blank
+/// line after the headers) that we care about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Head {
+    pub route: Route,
+    pub content_length: usize,
+    pub password: Option<String>
+}
+
+/// Pick out what a request is asking for.
+pub fn parse_head(request: &[u8]) -> Head {
+
+    let mut head = Head{ route: Route::Bad, content_length: 0, password: None };
+    let text = match std::str::from_utf8(request) {
+        Ok(text) => text,
+        Err(_) => return head
+    };
+    let mut lines = text.split("\r\n");
+    let mut first = lines.next().unwrap_or("").split(' ');
+    let (method, target) = (first.next().unwrap_or(""), first.next().unwrap_or(""));
+
+    for line in lines {
+        if let Some(idx) = line.find(':') {
+            let (name, value) = (line[..idx].trim().to_ascii_lowercase(), line[idx + 1..].trim());
+            match &*name {
+                "content-length" => head.content_length = value.parse().unwrap_or(0),
+                "authorization" if value.starts_with("Bearer ") => head.password = Some(value[7..].to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    let mut target = target.splitn(2, '?');
+    let (path, query) = (target.next().unwrap_or(""), target.next().unwrap_or(""));
+    let param = |name: &str| query.split('&')
+        .filter_map(|pair| pair.find('=').map(|idx| (&pair[..idx], &pair[idx + 1..])))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.parse::<usize>().ok());
+
+    head.route = match (method, path) {
+        ("GET", "/status") => Route::Status,
+        ("POST", "/input") => Route::Input,
+        ("POST", "/pause") => Route::Pause,
+        ("POST", "/resume") => Route::Resume,
+        ("GET", "/snapshot") => Route::Snapshot,
+        ("GET", "/output") => Route::Output,
+        ("GET", path) if path.starts_with("/arrays/") => {
+            match (path[8..].parse(), param("start").unwrap_or(Some(0)), param("len").unwrap_or(Some(MAX_RANGE))) {
+                (Ok(id), Some(start), Some(len)) => Route::Array{ id, start, len: std::cmp::min(len, MAX_RANGE) },
+                _ => Route::Bad
+            }
+        },
+        ("GET", _) | ("POST", _) => Route::NotFound,
+        _ => Route::Bad
+    };
+    head
+}
+
+/// Answer a request to the API. Input is passed to the program from a seat of its
+/// own, and output (along with events) is streamed as server-sent events. Anything
+/// else is asked of the program itself, in between its steps. If network connections
+/// need a password, so does this, given as `Authorization: Bearer <password>`.
+pub fn serve<R, W>(reader: R, writer: W, peer: Peer, context: Context, controller: Controller)
+where R: AsyncRead + Send + 'static, W: AsyncWrite + Send + 'static {
+    tokio::spawn_async(async move {
+
+        let mut input = io_extra::stream_bytes(reader);
+        let mut request = vec![];
+        while !request.ends_with(b"\r\n\r\n") {
+            match await!(input.next()) {
+                Some(Ok(byte)) if request.len() < MAX_REQUEST_LEN => request.push(byte),
+                _ => return
+            }
+        }
+
+        let head = parse_head(&request);
+        let mut body = vec![];
+        if head.content_length <= MAX_BODY {
+            while body.len() < head.content_length {
+                match await!(input.next()) {
+                    Some(Ok(byte)) => body.push(byte),
+                    _ => return
+                }
+            }
+        }
+
+        let allowed = match (&context.auth, &head.password) {
+            (None, _) => true,
+            (Some(auth), Some(password)) => auth.check(peer, password.as_bytes()),
+            (Some(_), None) => false
+        };
+
+        let reply = match head.route {
+            _ if !allowed => error("401 Unauthorized", "a password is required"),
+            _ if head.content_length > MAX_BODY => error("413 Payload Too Large", "too much input at once"),
+            Route::Output => {
+                await!(stream_output(writer, context.broadcaster));
+                return;
+            },
+            Route::Input => {
+                // Input is passed on whole, even if it doesn't end with a newline,
+                // and we say how much of it (besides any commands) made it through:
+                let mut seat = context.floor.seat(context.role, Some("api"));
+                let mut notices = vec![];
+                for byte in &body {
+                    let _ = await!(seat.room());
+                    seat.receive(*byte, &mut notices);
+                }
+                seat.finish();
+                json(json!({ "accepted": seat.sent(), "notices": byte_string(&notices) }))
+            },
+            Route::NotFound => error("404 Not Found", "not found"),
+            Route::Bad => error("400 Bad Request", "bad request"),
+            route => {
+                let request = match route {
+                    Route::Status => Request::Status,
+                    Route::Pause => Request::Pause,
+                    Route::Resume => Request::Resume,
+                    Route::Snapshot => Request::Snapshot,
+                    Route::Array{ id, start, len } => Request::Array{ id, start, len },
+                    _ => unreachable!()
+                };
+                match await!(controller.ask(request)) {
+                    Ok(Reply::Status(status)) => json(status_json(&status)),
+                    Ok(Reply::Done) | Ok(Reply::Tracing(_)) => json(json!({})),
+                    Ok(Reply::Snapshot(bytes)) => response("200 OK", "application/octet-stream", &bytes),
+                    Ok(Reply::Array(platters)) => json(json!({ "platters": platters })),
+                    Ok(Reply::NoSuchArray) => error("404 Not Found", "no such array"),
+                    Err(_) => error("503 Service Unavailable", "the program has finished")
+                }
+            }
+        };
+        let _ = await!(tokio::io::write_all(writer, reply));
+    });
+}
+
+// Send output and events on as server-sent events, until the program
+// is done or the other end goes away:
+async fn stream_output<W: AsyncWrite + Send + 'static>(writer: W, mut broadcaster: Broadcaster) {
+
+    let head = "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/event-stream\r\n\
+                Cache-Control: no-cache\r\n\
+                Connection: close\r\n\r\n";
+    let writer = match await!(tokio::io::write_all(writer, head)) {
+        Ok((writer, _)) => writer,
+        Err(_) => return
+    };
+
+    let (send_output, recv_output) = mpsc::channel::<Message>(0);
+    let events = recv_output.map(|message| Bytes::from(match message {
+        Message::Output(chunk) => format!("event: output\ndata: {}\n\n", json!(byte_string(&chunk))),
+        Message::Event(event) => format!("event: event\ndata: {}\n\n", json!(event.to_string()))
+    }));
+    tokio::spawn(events.forward(io_extra::sink_chunks(writer).sink_map_err(|_| ())).map(|_| ()));
+    await!(broadcaster.subscribe(send_output.sink_map_err(|_| ())));
+}
+
+fn status_json(status: &Status) -> Value {
+    json!({
+        "cycles": status.cycles,
+        "finger": status.finger,
+        "arrays": status.arrays,
+        "platters": status.platters,
+        "memory_bytes": status.platters * 4,
+        "paused": status.paused,
+        "waiting_for_input": status.waiting,
+        "pending_input": status.pending_input
+    })
+}
+
+fn json(body: Value) -> Vec<u8> {
+    response("200 OK", "application/json", body.to_string().as_bytes())
+}
+
+fn error(status: &str, reason: &str) -> Vec<u8> {
+    response(status, "application/json", json!({ "error": reason }).to_string().as_bytes())
+}
+
+/// Bytes as a string to send in JSON. Each byte stands for the character with
+/// the same code, so that output which isn't valid UTF-8 makes it through intact.
+pub fn byte_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn route(request: &str) -> Route {
+        parse_head(request.as_bytes()).route
+    }
+
+    #[test]
+    fn routes() {
+        assert_eq!(route("GET /status HTTP/1.1\r\n\r\n"), Route::Status);
+        assert_eq!(route("POST /input HTTP/1.1\r\n\r\n"), Route::Input);
+        assert_eq!(route("POST /pause HTTP/1.1\r\n\r\n"), Route::Pause);
+        assert_eq!(route("POST /resume HTTP/1.1\r\n\r\n"), Route::Resume);
+        assert_eq!(route("GET /snapshot HTTP/1.1\r\n\r\n"), Route::Snapshot);
+        assert_eq!(route("GET /output HTTP/1.1\r\n\r\n"), Route::Output);
+        assert_eq!(route("GET /nothing HTTP/1.1\r\n\r\n"), Route::NotFound);
+        assert_eq!(route("POST /status HTTP/1.1\r\n\r\n"), Route::NotFound);
+        assert_eq!(route("DELETE /status HTTP/1.1\r\n\r\n"), Route::Bad);
+        assert_eq!(route("\r\n\r\n"), Route::Bad);
+    }
+
+    #[test]
+    fn array_ranges() {
+        assert_eq!(route("GET /arrays/3 HTTP/1.1\r\n\r\n"), Route::Array{ id: 3, start: 0, len: MAX_RANGE });
+        assert_eq!(route("GET /arrays/0?start=10&len=5 HTTP/1.1\r\n\r\n"), Route::Array{ id: 0, start: 10, len: 5 });
+        assert_eq!(route("GET /arrays/0?len=99999999 HTTP/1.1\r\n\r\n"), Route::Array{ id: 0, start: 0, len: MAX_RANGE });
+        assert_eq!(route("GET /arrays/x HTTP/1.1\r\n\r\n"), Route::Bad);
+        assert_eq!(route("GET /arrays/0?start=-1 HTTP/1.1\r\n\r\n"), Route::Bad);
+    }
+
+    #[test]
+    fn headers() {
+        let head = parse_head(b"POST /input HTTP/1.1\r\nContent-Length: 12\r\nAUTHORIZATION: Bearer s3cret\r\n\r\n");
+        assert_eq!(head.content_length, 12);
+        assert_eq!(head.password, Some("s3cret".to_string()));
+
+        let head = parse_head(b"POST /input HTTP/1.1\r\nAuthorization: Basic abc\r\n\r\n");
+        assert_eq!(head.content_length, 0);
+        assert_eq!(head.password, None);
+    }
+
+}
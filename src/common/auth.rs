@@ -0,0 +1,193 @@
+use crate::error::{ err, Error };
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use std::path::Path;
+
+// After this many failed attempts from one place...
+const MAX_FAILURES: u32 = 5;
+
+// ...nothing more is tried from there until this long after the last one:
+const LOCKOUT: Duration = Duration::from_secs(60);
+
+/// Where a connection came from, so that failed attempts can be kept track of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    // Over the network, from this address:
+    Remote(IpAddr),
+    // Over a unix domain socket, from this user:
+    Local(u32),
+    // From somewhere that can't be told apart from anywhere else (and so
+    // which isn't locked out, as that would lock everybody else out too):
+    Unknown
+}
+
+impl Peer {
+    /// Where a network connection came from, given its address (if it has one).
+    pub fn remote(addr: std::io::Result<std::net::SocketAddr>) -> Peer {
+        addr.map(|addr| Peer::Remote(addr.ip())).unwrap_or(Peer::Unknown)
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Peer::Remote(ip) => write!(f, "{}", ip),
+            Peer::Local(uid) => write!(f, "local user {}", uid),
+            Peer::Unknown => write!(f, "an unknown connection")
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant
+}
+
+/// A shared secret which connections must send (on a line of its own) before
+/// they're given any output or allowed to send input. Failed attempts are logged,
+/// and too many from one address lock it out for a while.
+#[derive(Clone)]
+pub struct Auth {
+    secret: Arc<Vec<u8>>,
+    // Keyed by where attempts came from:
+    failures: Arc<Mutex<HashMap<Peer, Failures>>>
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Auth {{ .. }}")
+    }
+}
+
+impl Auth {
+
+    pub fn new(secret: &[u8]) -> Auth {
+        Auth {
+            secret: Arc::new(secret.to_vec()),
+            failures: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    /// Read the secret from a file, ignoring any trailing newline.
+    pub fn from_file(path: &Path) -> Result<Auth, Error> {
+        let contents = std::fs::read(path)?;
+        let secret = match contents.iter().rposition(|b| !b"\r\n".contains(b)) {
+            Some(end) => &contents[..=end],
+            None => return Err(err(format!("{} doesn't contain a password", path.display())))
+        };
+        Ok(Auth::new(secret))
+    }
+
+    /// Can a connection from here have a go? Failures old enough to
+    /// be forgotten about (from anywhere) are cleared away first.
+    pub fn allowed(&self, peer: Peer) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, f| f.last.elapsed() < LOCKOUT);
+        failures.get(&peer).map_or(true, |f| f.count < MAX_FAILURES)
+    }
+
+    /// Check an attempt, keeping track of (and logging) any failures.
+    pub fn check(&self, peer: Peer, attempt: &[u8]) -> bool {
+
+        if !self.allowed(peer) {
+            eprintln!("Refused a login attempt from {}; too many recent failures", peer);
+            return false;
+        }
+
+        // Compare every byte, so that how long this takes doesn't give anything away:
+        let attempt = match attempt.last() {
+            Some(b'\r') => &attempt[..attempt.len() - 1],
+            _ => attempt
+        };
+        let matches = attempt.len() == self.secret.len()
+            && attempt.iter().zip(self.secret.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+
+        let mut failures = self.failures.lock().unwrap();
+        if matches {
+            failures.remove(&peer);
+        } else if peer == Peer::Unknown {
+            eprintln!("Failed login attempt from {}", peer);
+        } else {
+            let f = failures.entry(peer).or_insert(Failures{ count: 0, last: Instant::now() });
+            f.count += 1;
+            f.last = Instant::now();
+            eprintln!("Failed login attempt from {} ({} recently)", peer, f.count);
+        }
+        matches
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn remote(last: u8) -> Peer {
+        Peer::Remote(IpAddr::from([10, 0, 0, last]))
+    }
+
+    #[test]
+    fn checks_the_secret() {
+        let auth = Auth::new(b"hunter2");
+        assert!(auth.check(remote(1), b"hunter2"));
+        assert!(auth.check(remote(1), b"hunter2\r"));
+        assert!(!auth.check(remote(1), b"hunter2\r\r"));
+        assert!(!auth.check(remote(1), b"hunter"));
+        assert!(!auth.check(remote(1), b"hunter22"));
+        assert!(!auth.check(remote(1), b""));
+    }
+
+    #[test]
+    fn locks_out_after_too_many_failures() {
+        let auth = Auth::new(b"secret");
+        for _ in 0..MAX_FAILURES {
+            assert!(auth.allowed(remote(1)));
+            assert!(!auth.check(remote(1), b"guess"));
+        }
+        assert!(!auth.allowed(remote(1)));
+        // Even the right secret is refused now:
+        assert!(!auth.check(remote(1), b"secret"));
+        // But nowhere else is locked out:
+        assert!(auth.check(remote(2), b"secret"));
+    }
+
+    #[test]
+    fn lockouts_expire() {
+        let auth = Auth::new(b"secret");
+        for _ in 0..MAX_FAILURES {
+            auth.check(remote(1), b"guess");
+        }
+        assert!(!auth.allowed(remote(1)));
+        let long_ago = Instant::now().checked_sub(LOCKOUT + Duration::from_secs(1)).unwrap();
+        auth.failures.lock().unwrap().get_mut(&remote(1)).unwrap().last = long_ago;
+        assert!(auth.allowed(remote(1)));
+        assert!(auth.check(remote(1), b"secret"));
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let auth = Auth::new(b"secret");
+        for _ in 0..MAX_FAILURES - 1 {
+            auth.check(remote(1), b"guess");
+        }
+        assert!(auth.check(remote(1), b"secret"));
+        for _ in 0..MAX_FAILURES - 1 {
+            auth.check(remote(1), b"guess");
+        }
+        assert!(auth.allowed(remote(1)));
+    }
+
+    #[test]
+    fn unknown_peers_are_never_locked_out() {
+        let auth = Auth::new(b"secret");
+        for _ in 0..MAX_FAILURES * 2 {
+            assert!(!auth.check(Peer::Unknown, b"guess"));
+        }
+        assert!(auth.allowed(Peer::Unknown));
+        assert!(auth.check(Peer::Unknown, b"secret"));
+    }
+
+}
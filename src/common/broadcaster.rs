@@ -1,29 +1,112 @@
 use tokio::prelude::*;
-use futures::sync::mpsc;
+use futures::sync::{ mpsc, oneshot };
+use futures::task::{ self, Task };
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+use tokio::timer::Interval;
+use bytes::Bytes;
+use crate::event::{ Event, Message };
+
+// Once closed, how long to give connections to finish sending output:
+const CLOSE_GRACE: Duration = Duration::from_secs(2);
+
+/// What to do when a subscriber falls behind, and the bytes queued
+/// up for it reach its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    // Wait for it to catch up (holding up everybody else in the meantime):
+    Block,
+    // Throw away the oldest queued bytes to make room for new ones:
+    DropOldest,
+    // Give up on it:
+    Disconnect
+}
+
+impl std::str::FromStr for Overflow {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "block" => Ok(Overflow::Block),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err(format!("'{}' is not an overflow policy (expected 'block', 'drop-oldest' or 'disconnect')", s))
+        }
+    }
+}
+
+/// How many bytes can be queued up for a subscriber, and what to do once
+/// that many are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backpressure {
+    pub overflow: Overflow,
+    pub limit: usize
+}
+
+impl Default for Backpressure {
+    fn default() -> Backpressure {
+        Backpressure {
+            overflow: Overflow::Disconnect,
+            limit: 1024 * 1024
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Broadcaster {
-    sender: mpsc::UnboundedSender<Msg>
+    sender: mpsc::UnboundedSender<Msg>,
+    holds: Arc<AtomicUsize>
 }
 
 enum Msg {
-    Subscribe(Box<dyn Sink<SinkItem=u8, SinkError=()> + Send + Sync + 'static>),
-    Broadcast(u8),
+    Subscribe(Box<dyn Sink<SinkItem=Message, SinkError=()> + Send + Sync + 'static>, Option<Backpressure>),
+    Broadcast(Message),
     Close
 }
 
+/// Held by anything still sending output on (once it's been given it by the
+/// broadcaster), so that it gets a chance to finish before the broadcaster
+/// says that it's done.
+pub struct Hold(Arc<AtomicUsize>);
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 /// This structure adds a convenient interface which you to
 /// subscribe and send messages to the broadcaster:
 impl Broadcaster {
-    pub fn new() -> (Broadcaster, mpsc::Receiver<()>) {
-        make_broadcaster()
+    /// Create a broadcaster which remembers up to `scrollback` bytes of recent
+    /// output, to replay to anything which subscribes later on. Subscribers are
+    /// given the backpressure provided unless they ask for something else.
+    pub fn new(scrollback: usize, backpressure: Backpressure) -> (Broadcaster, mpsc::Receiver<()>) {
+        make_broadcaster(scrollback, backpressure)
+    }
+
+    pub async fn subscribe(&mut self, sink: impl Sink<SinkItem=Message, SinkError=()> + Send + Sync + 'static) -> () {
+        let msg = Msg::Subscribe(Box::new(sink), None);
+        let _ = await!(self.sender.send_async(msg));
     }
 
-    pub async fn subscribe(&mut self, sink: impl Sink<SinkItem=u8, SinkError=()> + Send + Sync + 'static) -> () {
-        let msg = Msg::Subscribe(Box::new(sink));
+    /// Subscribe, choosing what happens if the sink can't keep up.
+    pub async fn subscribe_with(&mut self, sink: impl Sink<SinkItem=Message, SinkError=()> + Send + Sync + 'static, backpressure: Backpressure) -> () {
+        let msg = Msg::Subscribe(Box::new(sink), Some(backpressure));
         let _ = await!(self.sender.send_async(msg));
     }
 
+    /// Tell every subscriber about something which happened.
+    pub async fn event(&mut self, event: Event) -> () {
+        let _ = await!(self.sender.send_async(Msg::Broadcast(Message::Event(event))));
+    }
+
+    pub fn hold(&self) -> Hold {
+        self.holds.fetch_add(1, Ordering::SeqCst);
+        Hold(self.holds.clone())
+    }
+
     pub async fn close(&mut self) -> () {
         let _ = await!(self.sender.send_async(Msg::Close));
     }
@@ -31,16 +114,16 @@ impl Broadcaster {
 }
 
 /// Broadcaster is also a valid Sink, to avoid needing to consume the inner sink
-/// on every attempt to send a byte into it, and allow us to use `.forward` to
-/// stream bytes into it.
+/// on every attempt to send a chunk into it, and allow us to use `.forward` to
+/// stream chunks into it.
 impl Sink for Broadcaster {
-    type SinkItem = u8;
+    type SinkItem = Bytes;
     type SinkError = ();
 
-    fn start_send(&mut self, byte: u8) -> Result<AsyncSink<u8>, Self::SinkError> {
-        match self.sender.start_send(Msg::Broadcast(byte)) {
+    fn start_send(&mut self, chunk: Bytes) -> Result<AsyncSink<Bytes>, Self::SinkError> {
+        match self.sender.start_send(Msg::Broadcast(Message::Output(chunk.clone()))) {
             Err(_) => Err(()),
-            Ok(inner) => Ok(inner.map(|_| byte))
+            Ok(inner) => Ok(inner.map(|_| chunk))
         }
     }
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
@@ -51,16 +134,23 @@ impl Sink for Broadcaster {
 }
 
 /// Create a new byte broadcaster (this will panic if it does not execute in the context
-/// of a tokio runtime). You can subscribe new Sinks and broadcast bytes to them. If a sink
+/// of a tokio runtime). You can subscribe new Sinks and broadcast chunks of bytes to them. If a sink
 /// errors (eg it is no longer possible to send to it) it is no longer broadcasted to.
-fn make_broadcaster() -> (Broadcaster, mpsc::Receiver<()>) {
+/// New sinks are first sent the most recent `scrollback` bytes of output.
+///
+/// Each sink is fed from its own queue, so that one which is slow to accept bytes
+/// only holds up the others if its backpressure says to block.
+fn make_broadcaster(scrollback: usize, backpressure: Backpressure) -> (Broadcaster, mpsc::Receiver<()>) {
 
     let (send_broadcaster, mut recv_broadcaster) = mpsc::unbounded();
     let (mut send_closed, recv_closed) = mpsc::channel::<()>(0);
+    let holds = Arc::new(AtomicUsize::new(0));
+    let held = holds.clone();
 
     tokio::spawn_async(async move {
 
-        let mut outputters: Vec<BoxedSink<u8,()>> = vec![];
+        let mut queues: Vec<Queue> = vec![];
+        let mut recent: VecDeque<u8> = VecDeque::with_capacity(scrollback);
         while let Some(res) = await!(recv_broadcaster.next()) {
 
             let msg = match res {
@@ -71,36 +161,44 @@ fn make_broadcaster() -> (Broadcaster, mpsc::Receiver<()>) {
             match msg {
 
                 // Subscribe a Sink to being sent output:
-                Msg::Subscribe(sink) => {
+                Msg::Subscribe(sink, chosen) => {
 
                     // Subscribe a new sink to receive output. We have to newtype
                     // the sink into our own struct since Sink isn't implemented
                     // on Box<dyn Sink> for some reason:
-                    outputters.push(BoxedSink(sink));
+                    let sink = BoxedSink(sink);
+                    let queue = subscribe_queue(sink, chosen.unwrap_or(backpressure));
+
+                    // Catch it up on recent output first, so that it knows what's
+                    // going on (but not so much that it overflows straight away):
+                    let skip = recent.len().saturating_sub(queue.limit());
+                    let replay: Vec<u8> = recent.iter().skip(skip).cloned().collect();
+                    if !replay.is_empty() {
+                        await!(queue.push(Message::Output(Bytes::from(replay))));
+                    }
+                    if !queue.is_closed() {
+                        queues.push(queue);
+                    }
 
                 },
 
-                // Get given some output to send:
-                Msg::Broadcast(byte) => {
+                // Get given some output (or an event) to send:
+                Msg::Broadcast(message) => {
 
-                    // Send a message to each sink, recording any that failed:
-                    let mut errored = vec![];
-                    for (i, sink) in outputters.iter_mut().enumerate() {
-                        if let Err(_) = await!(sink.send_async(byte)) {
-                            errored.push(i);
+                    // Remember output for anybody who subscribes later:
+                    if let Message::Output(chunk) = &message {
+                        if scrollback > 0 {
+                            recent.extend(&chunk[chunk.len().saturating_sub(scrollback)..]);
+                            let excess = recent.len().saturating_sub(scrollback);
+                            recent.drain(..excess);
                         }
                     }
 
-                    // If sending to a sink fails, remove it from the vec:
-                    if errored.len() > 0 {
-                        outputters = outputters.into_iter().enumerate().filter_map(|(i,sink)| {
-                            if errored.iter().find(|&&n| i == n).is_some() {
-                                None
-                            } else {
-                                Some(sink)
-                            }
-                        }).collect();
+                    // Queue it up for each sink, forgetting about any that are gone:
+                    for queue in &queues {
+                        await!(queue.push(message.clone()));
                     }
+                    queues.retain(|queue| !queue.is_closed());
 
                 },
 
@@ -113,6 +211,21 @@ fn make_broadcaster() -> (Broadcaster, mpsc::Receiver<()>) {
 
         }
 
+        // Anything which asked to be waited for is given the chance
+        // to receive everything queued up for it before we finish:
+        for mut queue in queues {
+            if queue.overflow == Overflow::Block {
+                let _ = await!(queue.finish());
+            }
+        }
+
+        // And anything still sending output on gets a little while to finish:
+        let deadline = Instant::now() + CLOSE_GRACE;
+        let mut ticks = Interval::new(Instant::now(), Duration::from_millis(10));
+        while held.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            let _ = await!(ticks.next());
+        }
+
         let _ = await!(send_closed.send_async(()));
 
     });
@@ -120,10 +233,232 @@ fn make_broadcaster() -> (Broadcaster, mpsc::Receiver<()>) {
     // return our interface:
     (Broadcaster {
         sender: send_broadcaster,
+        holds
     }, recv_closed)
 
 }
 
+/// Start feeding a sink from a new queue, handing back the queue.
+fn subscribe_queue(sink: BoxedSink<Message,()>, backpressure: Backpressure) -> Queue {
+
+    let shared = Arc::new(Mutex::new(Shared {
+        messages: VecDeque::new(),
+        len: 0,
+        limit: std::cmp::max(backpressure.limit, 1),
+        reader: None,
+        writer: None,
+        closed: false
+    }));
+    let (send_finished, recv_finished) = oneshot::channel();
+
+    let stream = QueueStream{ shared: shared.clone() };
+    tokio::spawn_async(async move {
+        let _ = await!(stream.forward(sink));
+        let _ = send_finished.send(());
+    });
+
+    Queue {
+        shared,
+        overflow: backpressure.overflow,
+        finished: recv_finished
+    }
+}
+
+// The state shared between the two ends of a subscriber's queue:
+struct Shared {
+    messages: VecDeque<Message>,
+    // How many bytes of output are queued up:
+    len: usize,
+    limit: usize,
+    // Who to wake up when bytes are queued:
+    reader: Option<Task>,
+    // Who to wake up when there's room for more:
+    writer: Option<Task>,
+    // Either end has gone away:
+    closed: bool
+}
+
+impl Shared {
+    fn push(&mut self, message: Message) {
+        self.len += message.output_len();
+        self.messages.push_back(message);
+        self.wake_reader();
+    }
+    fn pop(&mut self) -> Option<Message> {
+        let message = self.messages.pop_front()?;
+        self.len -= message.output_len();
+        self.wake_writer();
+        Some(message)
+    }
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.len = 0;
+    }
+    /// Throw away the oldest messages until there's room for `needed` more bytes.
+    fn make_room(&mut self, needed: usize) {
+        let mut excess = (self.len + needed).saturating_sub(self.limit);
+        while excess > 0 {
+            match self.messages.front_mut() {
+                Some(Message::Output(front)) if front.len() > excess => {
+                    front.split_to(excess);
+                    self.len -= excess;
+                    excess = 0;
+                },
+                Some(front) => {
+                    excess = excess.saturating_sub(front.output_len());
+                    self.len -= front.output_len();
+                    self.messages.pop_front();
+                },
+                None => break
+            }
+        }
+    }
+    /// Does a chunk of this size fit? A chunk always fits in an empty queue,
+    /// however big it is, so that nothing waits forever.
+    fn fits(&self, needed: usize) -> bool {
+        self.len == 0 || self.len + needed <= self.limit
+    }
+    fn wake_reader(&mut self) {
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+    fn wake_writer(&mut self) {
+        if let Some(task) = self.writer.take() {
+            task.notify();
+        }
+    }
+}
+
+/// The broadcaster's end of a subscriber's queue.
+struct Queue {
+    shared: Arc<Mutex<Shared>>,
+    overflow: Overflow,
+    finished: oneshot::Receiver<()>
+}
+
+impl Queue {
+
+    fn limit(&self) -> usize {
+        self.shared.lock().unwrap().limit
+    }
+
+    fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().closed
+    }
+
+    /// Queue up a message, doing whatever the overflow policy says if there's no room.
+    async fn push(&self, message: Message) -> () {
+        let needed = message.output_len();
+        let mut message = message;
+        while let Some(unsent) = self.offer(message) {
+            message = unsent;
+            await!(Room{ shared: self.shared.clone(), needed });
+        }
+    }
+
+    /// Try to queue up a message, handing it back if we need to wait for room first.
+    fn offer(&self, message: Message) -> Option<Message> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.closed {
+            return None;
+        }
+        if !shared.fits(message.output_len()) {
+            match self.overflow {
+                Overflow::Block => {},
+                Overflow::DropOldest => {
+                    shared.make_room(message.output_len());
+                },
+                Overflow::Disconnect => {
+                    shared.clear();
+                    shared.closed = true;
+                    shared.wake_reader();
+                    return None;
+                }
+            }
+        }
+        if shared.fits(message.output_len()) {
+            shared.push(message);
+            None
+        } else {
+            Some(message)
+        }
+    }
+
+    /// Stop queueing bytes, resolving once everything already queued has been sent on.
+    fn finish(&mut self) -> &mut oneshot::Receiver<()> {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.closed = true;
+            shared.wake_reader();
+        }
+        &mut self.finished
+    }
+
+}
+
+/// Letting go of a queue lets its reader know that no more is coming.
+impl Drop for Queue {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.closed = true;
+            shared.wake_reader();
+        }
+    }
+}
+
+/// Resolves when there's room in a queue for some bytes, or its reader has gone away.
+struct Room {
+    shared: Arc<Mutex<Shared>>,
+    needed: usize
+}
+
+impl Future for Room {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.closed || shared.fits(self.needed) {
+            Ok(Async::Ready(()))
+        } else {
+            shared.writer = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// The subscriber's end of a queue, which ends once the queue is closed and empty.
+struct QueueStream {
+    shared: Arc<Mutex<Shared>>
+}
+
+impl Stream for QueueStream {
+    type Item = Message;
+    type Error = ();
+    fn poll(&mut self) -> Poll<Option<Message>, ()> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(message) = shared.pop() {
+            Ok(Async::Ready(Some(message)))
+        } else if shared.closed {
+            Ok(Async::Ready(None))
+        } else {
+            shared.reader = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// If the subscriber goes away, the broadcaster stops queueing bytes for it.
+impl Drop for QueueStream {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.closed = true;
+            shared.clear();
+            shared.wake_writer();
+        }
+    }
+}
+
 // This is necessary to make Boxed Sinks actually impl the Sink trait,
 // as for some reason they do not appear to at the moment:
 struct BoxedSink<I,E>(Box<dyn Sink<SinkItem=I, SinkError=E> + Send + Sync + 'static>);
@@ -136,4 +471,108 @@ impl <I,E> Sink for BoxedSink<I,E> {
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         self.0.poll_complete()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn output(bytes: &[u8]) -> Message {
+        Message::Output(Bytes::from(bytes))
+    }
+
+    fn shared(limit: usize) -> Arc<Mutex<Shared>> {
+        Arc::new(Mutex::new(Shared {
+            messages: VecDeque::new(),
+            len: 0,
+            limit,
+            reader: None,
+            writer: None,
+            closed: false
+        }))
+    }
+
+    fn queue(limit: usize, overflow: Overflow) -> Queue {
+        Queue {
+            shared: shared(limit),
+            overflow,
+            finished: oneshot::channel().1
+        }
+    }
+
+    fn queued(queue: &Queue) -> Vec<Message> {
+        queue.shared.lock().unwrap().messages.iter().cloned().collect()
+    }
+
+    #[test]
+    fn fits() {
+        let shared = shared(4);
+        let mut shared = shared.lock().unwrap();
+        // Anything fits in an empty queue:
+        assert!(shared.fits(100));
+        shared.push(output(b"abc"));
+        assert!(shared.fits(1));
+        assert!(!shared.fits(2));
+        // Events take up no room:
+        shared.push(Message::Event(Event::Halted));
+        assert!(shared.fits(1));
+    }
+
+    #[test]
+    fn make_room_trims_across_messages() {
+        let shared = shared(6);
+        let mut shared = shared.lock().unwrap();
+        shared.push(output(b"ab"));
+        shared.push(Message::Event(Event::Paused));
+        shared.push(output(b"cdef"));
+
+        // The whole of the first chunk goes (along with the event
+        // after it), and then a bit of the next one:
+        shared.make_room(3);
+        assert_eq!(shared.messages.iter().cloned().collect::<Vec<_>>(), vec![output(b"def")]);
+        assert_eq!(shared.len, 3);
+        assert!(shared.fits(3));
+
+        // Asking for more room than there is empties the queue:
+        shared.make_room(10);
+        assert!(shared.messages.is_empty());
+        assert_eq!(shared.len, 0);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let queue = queue(4, Overflow::Block);
+        assert_eq!(queue.offer(output(b"abc")), None);
+        assert_eq!(queue.offer(output(b"de")), Some(output(b"de")));
+        assert_eq!(queued(&queue), vec![output(b"abc")]);
+
+        // Once there's room again, it goes in:
+        queue.shared.lock().unwrap().pop();
+        assert_eq!(queue.offer(output(b"de")), None);
+        assert_eq!(queued(&queue), vec![output(b"de")]);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let queue = queue(4, Overflow::DropOldest);
+        assert_eq!(queue.offer(output(b"abc")), None);
+        assert_eq!(queue.offer(output(b"de")), None);
+        assert_eq!(queued(&queue), vec![output(b"bc"), output(b"de")]);
+        assert!(!queue.is_closed());
+    }
+
+    #[test]
+    fn disconnect_gives_up() {
+        let queue = queue(4, Overflow::Disconnect);
+        assert_eq!(queue.offer(output(b"abc")), None);
+        assert_eq!(queue.offer(output(b"de")), None);
+        assert!(queue.is_closed());
+        assert_eq!(queued(&queue), vec![]);
+
+        // Nothing more is queued once closed:
+        assert_eq!(queue.offer(output(b"f")), None);
+        assert_eq!(queued(&queue), vec![]);
+    }
+
+}
@@ -0,0 +1,283 @@
+use crate::broadcaster::Broadcaster;
+use crate::io_extra;
+use crate::telnet::{ self, Telnet };
+use crate::websocket;
+use crate::line_discipline::{ Discipline, LineDiscipline };
+use crate::floor::{ Floor, Role, Seat };
+use crate::auth::{ Auth, Peer };
+use crate::event::{ self, Event, Message };
+use tokio::prelude::*;
+use futures::sync::{ mpsc, oneshot };
+
+/// How we talk to a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Raw,
+    Telnet(telnet::Mode),
+    WebSocket
+}
+
+impl Protocol {
+    /// Prepare some output to be sent over a connection.
+    fn encode(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Protocol::Raw => bytes.to_vec(),
+            Protocol::Telnet(..) => bytes.iter().flat_map(|&b| telnet::escape(b)).collect(),
+            Protocol::WebSocket => websocket::frame(websocket::BINARY, bytes)
+        }
+    }
+    /// Prepare an event to be sent over a connection. WebSockets are sent events
+    /// as text messages, so that they can be told apart from output.
+    fn notify(self, event: &Event) -> Vec<u8> {
+        match self {
+            Protocol::WebSocket => websocket::frame(websocket::TEXT, event.to_string().as_bytes()),
+            _ => self.encode(&event::render(event))
+        }
+    }
+    /// Prepare a message for the other end (rather than the program's output).
+    fn notice(self, text: &str) -> Vec<u8> {
+        match self {
+            Protocol::WebSocket => websocket::frame(websocket::TEXT, text.as_bytes()),
+            _ => self.encode(format!("[{}]\r\n", text).as_bytes())
+        }
+    }
+    /// Say hello to a new connection.
+    fn greet(self, name: &str) -> Vec<u8> {
+        self.notice(&format!("connected as {}; send ~help on a line of its own for commands", name))
+    }
+}
+
+/// The protocol state of a connection, which picks out the input meant for the program.
+enum Speaker {
+    Raw,
+    Telnet(Telnet),
+    WebSocket(websocket::Decoder)
+}
+
+impl Speaker {
+
+    fn new(protocol: Protocol) -> Speaker {
+        match protocol {
+            Protocol::Raw => Speaker::Raw,
+            Protocol::Telnet(mode) => Speaker::Telnet(Telnet::new(mode)),
+            Protocol::WebSocket => Speaker::WebSocket(websocket::Decoder::new())
+        }
+    }
+
+    fn start(&mut self) -> Vec<u8> {
+        match self {
+            Speaker::Telnet(telnet) => telnet.start(),
+            _ => vec![]
+        }
+    }
+
+    fn receive(&mut self, byte: u8, input: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        match self {
+            Speaker::Raw => input.push(byte),
+            Speaker::Telnet(telnet) => telnet.receive(byte, input, replies),
+            Speaker::WebSocket(decoder) => decoder.receive(byte, input, replies)
+        }
+    }
+
+    /// Have we told the other end that we'll echo its input?
+    fn echoing(&self) -> bool {
+        match self {
+            Speaker::Telnet(telnet) => telnet.echoing(),
+            _ => false
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self {
+            Speaker::WebSocket(decoder) => decoder.is_closed(),
+            _ => false
+        }
+    }
+
+}
+
+// The most output to send over a connection in one go:
+const OUTPUT_CHUNK: usize = 4096;
+
+// How many chunks can be waiting to be written to a connection:
+const WIRE_CHUNKS: usize = 4;
+
+// Password attempts longer than this are cut short:
+const MAX_ATTEMPT: usize = 1024;
+
+/// Everything a connection needs from whoever accepted it.
+#[derive(Clone)]
+pub struct Context {
+    pub floor: Floor,
+    pub role: Role,
+    pub broadcaster: Broadcaster,
+    pub protocol: Protocol,
+    pub discipline: Discipline,
+    pub auth: Option<Auth>,
+    // Whether raw connections are greeted and told about events (others always are):
+    pub show_events: bool
+}
+
+/// Hook a connection up so that anything it sends is passed to the program as input
+/// (if its seat at the floor allows), and it's sent any output. Everything written to
+/// the connection goes through a channel, so that protocol replies and echoed input
+/// can be sent alongside output. The preamble is sent before anything else.
+///
+/// If a password is needed, the connection gets no seat and no output until it has
+/// sent the right one, and is hung up on if it sends the wrong one.
+///
+/// Raw connections aren't greeted or told about events unless asked for, so that
+/// their output can be saved as-is.
+///
+/// Output is only taken from the broadcaster as fast as the connection accepts it, so
+/// that its backpressure policy can decide what to do with a slow connection. If the
+/// broadcaster gives up on it, the connection is closed.
+pub fn serve<S, W>(input: S, writer: W, preamble: Vec<u8>, peer: Peer, context: Context)
+where S: Stream<Item = u8, Error = tokio::io::Error> + Send + 'static, W: AsyncWrite + Send + 'static {
+
+    let Context { floor, role, broadcaster, protocol, discipline, auth, show_events } = context;
+    let show_events = show_events || protocol != Protocol::Raw;
+
+    // An empty chunk on the wire means that we should hang up. Until everything
+    // before it has been written, we hold the broadcaster up from finishing:
+    let (mut send_wire, recv_wire) = mpsc::channel::<Vec<u8>>(WIRE_CHUNKS);
+    let hold = broadcaster.hold();
+    tokio::spawn_async(async move {
+        let output = io_extra::sink_chunks(writer).sink_map_err(|_| ());
+        let chunks = recv_wire.take_while(|chunk| Ok(!chunk.is_empty()));
+        let _ = await!(chunks.forward(output));
+        drop(hold);
+    });
+
+    // Send the preamble now, so that it's sure to go out ahead of any output
+    // (which, thanks to scrollback, may well be sent as soon as we subscribe).
+    // Anybody who has failed to log in too often lately is turned away:
+    let mut speaker = Speaker::new(protocol);
+    let mut start = preamble;
+    start.extend(speaker.start());
+    if let Some(auth) = &auth {
+        if !auth.allowed(peer) {
+            start.extend(protocol.notice("too many failed attempts; try again later"));
+            let _ = send_wire.try_send(start);
+            let _ = send_wire.try_send(vec![]);
+            return;
+        }
+        start.extend(protocol.notice("password required; send it on a line of its own"));
+    }
+    let _ = send_wire.try_send(start);
+
+    // listen for input and send to the main thread, until told to hang up:
+    let (hang_up, hung_up) = oneshot::channel::<()>();
+    let mut discipline = LineDiscipline::new(discipline);
+    tokio::spawn_async(async move {
+
+        let mut hang_up = Some(hang_up);
+        let mut events = broadcaster.clone();
+        let mut wire = send_wire.clone();
+        let mut attempt = vec![];
+        let mut seat = None;
+        if auth.is_none() {
+            let seated = floor.seat(role, None);
+            await!(join(seated.name().to_string(), broadcaster.clone(), protocol, show_events, wire.clone(), hang_up.take()));
+            seat = Some(seated);
+        }
+
+        let (mut received, mut input_bytes, mut echo, mut reply) = (vec![], vec![], vec![], vec![]);
+        let hung_up = hung_up.into_stream().then(|_| Ok(None));
+        let mut input = input.map(Some).select(hung_up);
+        'input: loop {
+
+            // Don't read any more until the program has room for it:
+            if let Some(seat) = &seat {
+                let _ = await!(seat.room());
+            }
+            let byte = match await!(input.next()) {
+                Some(Ok(Some(byte))) => byte,
+                Some(Ok(None)) | None => break,
+                Some(Err(_)) => continue
+            };
+
+            // Strip out anything protocol related, answering it as needed:
+            speaker.receive(byte, &mut received, &mut reply);
+            if speaker.echoing() {
+                discipline.set_echo(true);
+            }
+
+            // Then tidy up what's left, and echo it (and anything the
+            // floor has to say about it) if need be. Until we have a
+            // seat, input is an attempt at the password (which isn't echoed):
+            for byte in received.drain(..) {
+                discipline.receive(byte, &mut input_bytes, &mut echo);
+            }
+            if seat.is_none() {
+                echo.clear();
+            }
+            for byte in input_bytes.drain(..) {
+                if let Some(seat) = &mut seat {
+                    seat.receive(byte, &mut echo);
+                } else if byte != b'\n' && attempt.len() < MAX_ATTEMPT {
+                    attempt.push(byte);
+                } else if byte == b'\n' && auth.as_ref().map_or(false, |auth| auth.check(peer, &attempt)) {
+                    let seated = floor.seat(role, None);
+                    await!(join(seated.name().to_string(), broadcaster.clone(), protocol, show_events, wire.clone(), hang_up.take()));
+                    seat = Some(seated);
+                } else if byte == b'\n' {
+                    let _ = await!(wire.send_async(protocol.notice("wrong password")));
+                    let _ = await!(wire.send_async(vec![]));
+                    break 'input;
+                }
+            }
+            if !echo.is_empty() {
+                reply.extend(protocol.encode(&echo));
+                echo.clear();
+            }
+            if !reply.is_empty() {
+                let _ = await!(wire.send_async(reply.split_off(0)));
+            }
+            if speaker.is_closed() {
+                break;
+            }
+        }
+        if let Some(mut seat) = seat {
+            // Whatever was still being typed is passed on before we go:
+            discipline.finish(&mut input_bytes);
+            for byte in input_bytes.drain(..) {
+                seat.receive(byte, &mut echo);
+            }
+            await!(events.event(Event::Left(seat.name().to_string())));
+        }
+    });
+}
+
+/// Greet a connection which has been given a seat (if it wants events), let everybody
+/// else know that it's joined, and then start sending it output (so that it isn't told
+/// about its own arrival). Once the output stops (because the program is done, or the
+/// connection couldn't keep up), hang up.
+async fn join(name: String, mut broadcaster: Broadcaster, protocol: Protocol, show_events: bool, mut send_wire: mpsc::Sender<Vec<u8>>, hang_up: Option<oneshot::Sender<()>>) {
+
+    if show_events {
+        let _ = await!(send_wire.send_async(protocol.greet(&name)));
+    }
+
+    let (send_output, mut recv_output) = mpsc::channel::<Message>(0);
+    tokio::spawn_async(async move {
+        'output: while let Some(Ok(message)) = await!(recv_output.next()) {
+            let pieces = match message {
+                Message::Output(chunk) => chunk.chunks(OUTPUT_CHUNK).map(|piece| protocol.encode(piece)).collect(),
+                Message::Event(event) => if show_events { vec![protocol.notify(&event)] } else { vec![] }
+            };
+            for piece in pieces {
+                if await!(send_wire.send_async(piece)).is_err() {
+                    break 'output;
+                }
+            }
+        }
+        let _ = await!(send_wire.send_async(vec![]));
+        if let Some(hang_up) = hang_up {
+            let _ = hang_up.send(());
+        }
+    });
+
+    await!(broadcaster.event(Event::Joined(name)));
+    await!(broadcaster.subscribe(send_output.sink_map_err(|_| ())));
+}
@@ -0,0 +1,250 @@
+//! Meta-commands typed at the console. Like ssh, an escape character (usually `~`) at
+//! the start of a line begins one, and the rest of the line never reaches the program.
+//! The floor's commands are given the same way, and doubling the escape character up
+//! sends a line starting with a single one.
+
+use crate::control::{ Controller, Request, Reply };
+use crate::floor::{ notice, Seat };
+use std::path::PathBuf;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+const HELP: &str = "console commands: ~snapshot [FILE] (save the program's state), ~stats, ~trace (show instructions as they run, or stop), ~pause, ~resume, ~send FILE (send a file as input), ~eof (send EOF), ~quit; when sharing input: ~control, ~release, ~flush, ~status; start a line with ~~ to send a ~";
+
+/// Something asked of the interpreter from the console.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Meta {
+    Snapshot(Option<PathBuf>),
+    Stats,
+    Trace,
+    Pause,
+    Resume,
+    Send(PathBuf),
+    Eof,
+    Quit,
+    // One of the floor's commands:
+    Floor(String),
+    Help
+}
+
+/// Parse a command (without its escape character), or say what's wrong with it.
+pub fn parse(command: &str) -> Result<Meta, String> {
+    let mut parts = command.trim().splitn(2, char::is_whitespace);
+    let (name, rest) = (parts.next().unwrap_or(""), parts.next().map(|r| r.trim()).filter(|r| !r.is_empty()));
+    match name {
+        "snapshot" => Ok(Meta::Snapshot(rest.map(PathBuf::from))),
+        "stats" => Ok(Meta::Stats),
+        "trace" => Ok(Meta::Trace),
+        "pause" => Ok(Meta::Pause),
+        "resume" => Ok(Meta::Resume),
+        "send" => rest.map(|path| Meta::Send(PathBuf::from(path))).ok_or_else(|| "send needs a file to send".to_string()),
+        "eof" => Ok(Meta::Eof),
+        "quit" => Ok(Meta::Quit),
+        "control" | "release" | "flush" | "status" => Ok(Meta::Floor(name.to_string())),
+        "help" => Ok(Meta::Help),
+        _ => Err(format!("'{}' isn't a console command", command.trim()))
+    }
+}
+
+/// Picks meta-commands out of input from the console, passing everything else on.
+pub struct Escape {
+    escape: u8,
+    command: Option<Vec<u8>>,
+    at_line_start: bool
+}
+
+impl Escape {
+
+    /// Look for commands starting with this character.
+    pub fn new(escape: u8) -> Escape {
+        Escape {
+            escape,
+            command: None,
+            at_line_start: true
+        }
+    }
+
+    /// Handle a byte of input, adding anything that isn't part of a meta-command
+    /// to `input`. Once a whole meta-command has been typed, it's handed back.
+    pub fn receive(&mut self, byte: u8, input: &mut Vec<u8>) -> Option<Result<Meta, String>> {
+
+        if let Some(mut command) = self.command.take() {
+            // A doubled up escape character is sent on, along with the rest of the line:
+            if command.is_empty() && byte == self.escape {
+                self.at_line_start = false;
+                input.push(byte);
+                return None;
+            }
+            if byte == b'\n' {
+                self.at_line_start = true;
+                return Some(parse(&String::from_utf8_lossy(&command)));
+            }
+            command.push(byte);
+            self.command = Some(command);
+            return None;
+        }
+
+        if self.at_line_start && byte == self.escape {
+            self.at_line_start = false;
+            self.command = Some(vec![]);
+            return None;
+        }
+
+        self.at_line_start = byte == b'\n';
+        input.push(byte);
+        None
+    }
+
+}
+
+/// Carry out a meta-command (begun with `escape`), returning what to tell whoever typed
+/// it. Anything to do with the program itself is asked of it in between its steps, and
+/// anything to do with sharing input is up to the floor.
+pub async fn run(meta: Meta, escape: u8, controller: Controller, seat: &mut Seat) -> Vec<u8> {
+
+    let mut notices = vec![];
+    let request = match &meta {
+        Meta::Snapshot(_) => Request::Snapshot,
+        Meta::Stats => Request::Status,
+        Meta::Trace => Request::Trace,
+        Meta::Pause => Request::Pause,
+        Meta::Resume => Request::Resume,
+        Meta::Eof => Request::Eof,
+        Meta::Quit => Request::Quit,
+        Meta::Send(path) => {
+            // Sent on as if typed, but without looking for commands in it,
+            // and without getting ahead of the program:
+            match std::fs::read(&path) {
+                Ok(bytes) => {
+                    for &byte in &bytes {
+                        let _ = await!(seat.room());
+                        seat.send(byte, &mut notices);
+                    }
+                    notice(&mut notices, &format!("sent {} bytes from {}", bytes.len(), path.display()));
+                },
+                Err(e) => notice(&mut notices, &format!("couldn't read {}: {}", path.display(), e))
+            }
+            return notices;
+        },
+        Meta::Floor(command) => {
+            seat.command(command, &mut notices);
+            return notices;
+        },
+        Meta::Help => {
+            notice(&mut notices, &HELP.replace('~', &(escape as char).to_string()));
+            return notices;
+        }
+    };
+
+    let message = match (await!(controller.ask(request)), meta) {
+        (Ok(Reply::Snapshot(bytes)), Meta::Snapshot(path)) => {
+            let path = path.unwrap_or_else(default_snapshot);
+            match std::fs::write(&path, &bytes) {
+                Ok(()) => format!("saved a snapshot to {}", path.display()),
+                Err(e) => format!("couldn't save the snapshot to {}: {}", path.display(), e)
+            }
+        },
+        (Ok(Reply::Status(status)), _) => format!(
+            "{} cycles, finger at {}, {} arrays holding {} platters ({} bytes), {} bytes of input pending{}",
+            status.cycles, status.finger, status.arrays, status.platters, status.platters * 4, status.pending_input,
+            if status.paused { "; paused" } else if status.waiting { "; waiting for input" } else { "" }
+        ),
+        (Ok(Reply::Tracing(true)), _) => "tracing instructions to stderr".to_string(),
+        (Ok(Reply::Tracing(false)), _) => "no longer tracing instructions".to_string(),
+        (Ok(_), Meta::Pause) => "paused; ~resume to carry on".to_string(),
+        (Ok(_), Meta::Resume) => "resumed".to_string(),
+        (Ok(_), Meta::Eof) => "EOF will be sent once the program has read any input waiting for it".to_string(),
+        (Ok(_), Meta::Quit) => "quitting".to_string(),
+        (Ok(_), _) => "done".to_string(),
+        (Err(_), _) => "the program has finished".to_string()
+    };
+    notice(&mut notices, &message);
+    notices
+}
+
+// Snapshots are saved here unless told otherwise, named for when they were taken:
+fn default_snapshot() -> PathBuf {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    PathBuf::from(format!("um-{}.snap", secs))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Feed some input through, returning what got through and any commands picked out:
+    fn escape(escape: u8, input: &[u8]) -> (Vec<u8>, Vec<Result<Meta, String>>) {
+        let mut escaper = Escape::new(escape);
+        let (mut passed, mut commands) = (vec![], vec![]);
+        for &byte in input {
+            if let Some(command) = escaper.receive(byte, &mut passed) {
+                commands.push(command);
+            }
+        }
+        (passed, commands)
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("snapshot"), Ok(Meta::Snapshot(None)));
+        assert_eq!(parse("snapshot  saved.snap "), Ok(Meta::Snapshot(Some(PathBuf::from("saved.snap")))));
+        assert_eq!(parse("stats"), Ok(Meta::Stats));
+        assert_eq!(parse(" trace "), Ok(Meta::Trace));
+        assert_eq!(parse("pause"), Ok(Meta::Pause));
+        assert_eq!(parse("resume"), Ok(Meta::Resume));
+        assert_eq!(parse("send input.txt"), Ok(Meta::Send(PathBuf::from("input.txt"))));
+        assert_eq!(parse("eof"), Ok(Meta::Eof));
+        assert_eq!(parse("quit"), Ok(Meta::Quit));
+        assert_eq!(parse("help"), Ok(Meta::Help));
+    }
+
+    #[test]
+    fn parses_floor_commands() {
+        for &command in &["control", "release", "flush", "status"] {
+            assert_eq!(parse(command), Ok(Meta::Floor(command.to_string())));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert!(parse("send").is_err());
+        assert!(parse("send   ").is_err());
+        assert!(parse("foo").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn passes_input_through() {
+        assert_eq!(escape(b'~', b"hello\nwor~ld\n"), (b"hello\nwor~ld\n".to_vec(), vec![]));
+    }
+
+    #[test]
+    fn picks_out_commands_at_line_start() {
+        let (passed, commands) = escape(b'~', b"~stats\nhello\n~pause\n");
+        assert_eq!(passed, b"hello\n".to_vec());
+        assert_eq!(commands, vec![Ok(Meta::Stats), Ok(Meta::Pause)]);
+    }
+
+    #[test]
+    fn doubled_escape_is_sent_on() {
+        let (passed, commands) = escape(b'~', b"~~stats\n");
+        assert_eq!(passed, b"~stats\n".to_vec());
+        assert_eq!(commands, vec![]);
+    }
+
+    #[test]
+    fn unknown_commands_are_not_input() {
+        let (passed, commands) = escape(b'~', b"~foo\nbar\n");
+        assert_eq!(passed, b"bar\n".to_vec());
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].is_err());
+    }
+
+    #[test]
+    fn other_escape_characters() {
+        let (passed, commands) = escape(b'!', b"~stats\n!stats\n!!x\n");
+        assert_eq!(passed, b"~stats\n!x\n".to_vec());
+        assert_eq!(commands, vec![Ok(Meta::Stats)]);
+    }
+
+}
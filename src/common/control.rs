@@ -0,0 +1,165 @@
+use crate::program::{ Program, Inputter };
+use crate::snapshot;
+use crate::io::IoHandler;
+use crate::event::Event;
+use crossbeam::channel;
+use futures::sync::oneshot;
+
+/// Something to ask of a running program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Status,
+    Pause,
+    Resume,
+    Snapshot,
+    Array{ id: usize, start: usize, len: usize },
+    // Show each instruction on stderr as it's executed, or stop doing so:
+    Trace,
+    // Send EOF the next time the program asks for input and none is waiting:
+    Eof,
+    Quit
+}
+
+/// How a running program answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(Status),
+    Done,
+    Snapshot(Vec<u8>),
+    Array(Vec<u32>),
+    NoSuchArray,
+    Tracing(bool)
+}
+
+/// What a running program is up to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub cycles: usize,
+    pub finger: usize,
+    pub arrays: usize,
+    pub platters: usize,
+    pub paused: bool,
+    pub waiting: bool,
+    pub pending_input: usize
+}
+
+type Ask = (Request, oneshot::Sender<Reply>);
+
+/// Asks a running program things, from anywhere.
+#[derive(Clone)]
+pub struct Controller {
+    sender: channel::Sender<Ask>
+}
+
+impl std::fmt::Debug for Controller {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Controller {{ .. }}")
+    }
+}
+
+impl Controller {
+    /// Ask something, getting back a future which resolves to the answer
+    /// (or fails if the program has finished).
+    pub fn ask(&self, request: Request) -> oneshot::Receiver<Reply> {
+        let (send, recv) = oneshot::channel();
+        self.sender.send((request, send));
+        recv
+    }
+}
+
+/// Answers what's asked of a running program, in between its steps.
+pub struct Controls {
+    receiver: channel::Receiver<Ask>,
+    paused: bool,
+    tracing: bool,
+    eof: bool,
+    quitting: bool
+}
+
+impl Controls {
+
+    pub fn new() -> (Controller, Controls) {
+        let (sender, receiver) = channel::unbounded();
+        (Controller{ sender }, Controls{ receiver, paused: false, tracing: false, eof: false, quitting: false })
+    }
+
+    /// Whether each instruction should be shown as it's executed.
+    pub fn tracing(&self) -> bool {
+        self.tracing
+    }
+
+    /// Whether EOF has been asked for, which only happens once per asking.
+    pub fn take_eof(&mut self) -> bool {
+        std::mem::replace(&mut self.eof, false)
+    }
+
+    /// Whether we've been asked to stop the program.
+    pub fn quitting(&self) -> bool {
+        self.quitting
+    }
+
+    /// Answer anything that's been asked. While paused, this blocks until asked
+    /// to resume, letting everybody watching know when either happens.
+    pub fn serve(&mut self, program: &Program, waiting: Option<Inputter>, cycles: usize, io: &IoHandler) {
+        loop {
+            let asked = if self.paused { self.receiver.recv() } else { self.receiver.try_recv() };
+            let (request, reply) = match asked {
+                Some(asked) => asked,
+                // Nobody is left to resume us if we're paused, so carry on:
+                None => {
+                    if self.paused {
+                        self.paused = false;
+                        let _ = io.event(Event::Resumed);
+                    }
+                    return;
+                }
+            };
+            let answer = match request {
+                Request::Status => Reply::Status(Status {
+                    cycles,
+                    finger: program.instruction_index(),
+                    arrays: program.active_arrays(),
+                    platters: program.platter_count(),
+                    paused: self.paused,
+                    waiting: waiting.is_some(),
+                    pending_input: io.pending_input()
+                }),
+                Request::Pause => {
+                    if !self.paused {
+                        self.paused = true;
+                        let _ = io.event(Event::Paused);
+                    }
+                    Reply::Done
+                },
+                Request::Resume => {
+                    if self.paused {
+                        self.paused = false;
+                        let _ = io.event(Event::Resumed);
+                    }
+                    Reply::Done
+                },
+                Request::Snapshot => Reply::Snapshot(snapshot::save(program, waiting)),
+                Request::Array{ id, start, len } => match program.array(id) {
+                    Some(array) => Reply::Array(array.iter().skip(start).take(len).map(|p| p.to_u32()).collect()),
+                    None => Reply::NoSuchArray
+                },
+                Request::Trace => {
+                    self.tracing = !self.tracing;
+                    Reply::Tracing(self.tracing)
+                },
+                Request::Eof => {
+                    self.eof = true;
+                    Reply::Done
+                },
+                Request::Quit => {
+                    // There's no resuming once we're stopping:
+                    self.quitting = true;
+                    self.paused = false;
+                    Reply::Done
+                }
+            };
+            let _ = reply.send(answer);
+        }
+    }
+
+}
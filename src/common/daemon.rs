@@ -0,0 +1,346 @@
+use crate::error::{ err, Error };
+use crate::program::{ Program, StepResult, Inputter };
+use crate::snapshot;
+use crate::io::{ IoHandler, IoOptions };
+use crate::connection::{ self, Context };
+use crate::auth::Peer;
+use crate::event::Event;
+use crate::io_extra;
+use tokio::prelude::*;
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread;
+
+// How many steps a machine takes between updating its cycle count
+// and checking whether it's been asked to stop:
+const CHECK_EVERY: usize = 4096;
+
+// Commands longer than this are cut short:
+const MAX_COMMAND: usize = 4096;
+
+/// Where the daemon listens for commands, unless told otherwise.
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("umd.sock"),
+        None => std::env::temp_dir().join(format!("umd-{}.sock", std::env::var("USER").unwrap_or_default()))
+    }
+}
+
+/// Something to ask the daemon, sent on a line of its own. The daemon answers with
+/// a line saying `ok` or `error: <why>`, followed by anything else it has to say.
+/// Once attached, the connection carries the machine's input and output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Start{ name: String, path: PathBuf },
+    List,
+    Attach{ name: String },
+    Stop{ name: String }
+}
+
+impl FromStr for Command {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Command, String> {
+        let s = s.trim_end_matches(|c| c == '\r' || c == '\n');
+        let mut words = s.splitn(3, ' ');
+        let command = match (words.next(), words.next(), words.next()) {
+            (Some("start"), Some(name), Some(path)) if !name.is_empty() => Command::Start{ name: name.to_string(), path: PathBuf::from(path) },
+            (Some("list"), None, None) => Command::List,
+            (Some("attach"), Some(name), None) => Command::Attach{ name: name.to_string() },
+            (Some("stop"), Some(name), None) => Command::Stop{ name: name.to_string() },
+            _ => return Err(format!("'{}' is not a command I know", s))
+        };
+        Ok(command)
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Command::Start{ name, path } => write!(f, "start {} {}", name, path.display()),
+            Command::List => write!(f, "list"),
+            Command::Attach{ name } => write!(f, "attach {}", name),
+            Command::Stop{ name } => write!(f, "stop {}", name)
+        }
+    }
+}
+
+/// What a machine is up to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Waiting,
+    Halted,
+    Faulted(String),
+    Stopped
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Status::Running => write!(f, "running"),
+            Status::Waiting => write!(f, "waiting for input"),
+            Status::Halted => write!(f, "halted"),
+            Status::Faulted(reason) => write!(f, "faulted: {}", reason),
+            Status::Stopped => write!(f, "stopped")
+        }
+    }
+}
+
+// Shared between a machine and the daemon keeping track of it:
+struct State {
+    status: Mutex<Status>,
+    cycles: AtomicUsize,
+    stop: AtomicBool
+}
+
+struct Machine {
+    path: PathBuf,
+    state: Arc<State>,
+    context: Context
+}
+
+/// The named machines a daemon is running. Each one runs on its own thread,
+/// with its own IO handler (which is left detached from stdin and stdout).
+#[derive(Clone)]
+pub struct Machines {
+    // Names are taken (with None) while their machine is being started:
+    machines: Arc<Mutex<BTreeMap<String, Option<Machine>>>>,
+    options: IoOptions
+}
+
+impl Machines {
+
+    /// Machines are given these IO options, besides being detached.
+    pub fn new(options: IoOptions) -> Machines {
+        Machines {
+            machines: Arc::new(Mutex::new(BTreeMap::new())),
+            options: IoOptions{ detached: true, ..options }
+        }
+    }
+
+    /// Start a new machine from a UM program or a snapshot.
+    pub fn start(&self, name: &str, path: &Path) -> Result<(), Error> {
+
+        // The name is taken while the machine is set up, so that nobody
+        // else can start one with the same name in the meantime:
+        match self.machines.lock().unwrap().entry(name.to_string()) {
+            Entry::Occupied(_) => return Err(err(format!("there is already a machine called {}", name))),
+            Entry::Vacant(entry) => { entry.insert(None); }
+        }
+
+        let (program, waiting) = match load(path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                self.machines.lock().unwrap().remove(name);
+                return Err(e);
+            }
+        };
+
+        let io = match IoHandler::start(self.options.clone()) {
+            Ok(io) => io,
+            Err(e) => {
+                self.machines.lock().unwrap().remove(name);
+                return Err(e);
+            }
+        };
+        let state = Arc::new(State {
+            status: Mutex::new(Status::Running),
+            cycles: AtomicUsize::new(0),
+            stop: AtomicBool::new(false)
+        });
+        let machine = Machine {
+            path: path.to_owned(),
+            state: state.clone(),
+            context: io.context()
+        };
+        self.machines.lock().unwrap().insert(name.to_string(), Some(machine));
+
+        thread::spawn(move || run(program, waiting, io, state));
+        Ok(())
+    }
+
+    /// A line for each machine, giving its name, status, cycle count and
+    /// where it was started from.
+    pub fn list(&self) -> String {
+        let mut out = String::new();
+        let machines = self.machines.lock().unwrap();
+        for (name, machine) in machines.iter().filter_map(|(name, machine)| machine.as_ref().map(|m| (name, m))) {
+            let status = machine.state.status.lock().unwrap().clone();
+            let cycles = machine.state.cycles.load(Ordering::Relaxed);
+            out.push_str(&format!("{}\t{}\t{}\t{}\n", name, status, cycles, machine.path.display()));
+        }
+        out
+    }
+
+    /// What's needed to attach a connection to a machine which is still going.
+    pub fn context(&self, name: &str) -> Result<Context, Error> {
+        let machines = self.machines.lock().unwrap();
+        let machine = find(&machines, name)?;
+        match &*machine.state.status.lock().unwrap() {
+            Status::Running | Status::Waiting => Ok(machine.context.clone()),
+            status => Err(err(format!("{} has {}", name, status)))
+        }
+    }
+
+    /// Stop a machine (if it's still going), and forget about it.
+    pub fn stop(&self, name: &str) -> Result<(), Error> {
+        let mut machines = self.machines.lock().unwrap();
+        find(&machines, name)?;
+        if let Some(Some(machine)) = machines.remove(name) {
+            machine.state.stop.store(true, Ordering::Relaxed);
+            machine.context.floor.close();
+        }
+        Ok(())
+    }
+
+}
+
+// Look up a machine, which must have finished starting:
+fn find<'a>(machines: &'a BTreeMap<String, Option<Machine>>, name: &str) -> Result<&'a Machine, Error> {
+    match machines.get(name) {
+        Some(Some(machine)) => Ok(machine),
+        Some(None) => Err(err(format!("{} is still starting", name))),
+        None => Err(err(format!("there is no machine called {}", name)))
+    }
+}
+
+// Load a UM program or a snapshot to start a machine from:
+fn load(path: &Path) -> Result<(Program, Option<Inputter>), Error> {
+    let file = std::fs::read(path)?;
+    if snapshot::is_snapshot(&file) {
+        snapshot::restore(&file)
+    } else {
+        let mut program = Program::new();
+        program.load_program(&file);
+        Ok((program, None))
+    }
+}
+
+// Run a machine until it halts, faults or is stopped, letting anybody
+// attached to it know which, and then shut its IO handler down:
+fn run(mut program: Program, mut waiting: Option<Inputter>, io: IoHandler, state: Arc<State>) {
+
+    let set_status = |status| *state.status.lock().unwrap() = status;
+    let mut cycles = 0;
+    let status = loop {
+        let result = match waiting.take() {
+            Some(inputter) => StepResult::InputNeeded{ inputter },
+            None => match program.step() {
+                Ok(result) => result,
+                Err(e) => break Status::Faulted(e.message().to_string())
+            }
+        };
+        match result {
+            StepResult::Halted => break Status::Halted,
+            StepResult::Output{ ascii } => {
+                if io.send(ascii).is_err() {
+                    break Status::Stopped;
+                }
+            },
+            StepResult::InputNeeded{ inputter } => {
+                set_status(Status::Waiting);
+                match io.recv() {
+                    Ok(Some(byte)) => program.provide_input(inputter, Some(byte)),
+                    Ok(None) | Err(_) => break Status::Stopped
+                }
+                set_status(Status::Running);
+            },
+            StepResult::Continue => {}
+        }
+        cycles += 1;
+        if cycles % CHECK_EVERY == 0 {
+            state.cycles.store(cycles, Ordering::Relaxed);
+            if state.stop.load(Ordering::Relaxed) {
+                break Status::Stopped;
+            }
+        }
+    };
+
+    state.cycles.store(cycles, Ordering::Relaxed);
+    let _ = io.event(match &status {
+        Status::Faulted(reason) => Event::Faulted(reason.clone()),
+        Status::Halted => Event::Halted,
+        _ => Event::Stopped
+    });
+    set_status(status);
+    io.block_until_closed();
+}
+
+/// Answer a connection to the daemon's control socket: read a command, and
+/// either reply and hang up, or (when attaching) hand the connection over to
+/// the machine asked for.
+pub fn serve<R, W>(reader: R, writer: W, machines: Machines)
+where R: AsyncRead + Send + 'static, W: AsyncWrite + Send + 'static {
+    tokio::spawn_async(async move {
+
+        let mut input = io_extra::stream_bytes(reader);
+        let mut line = vec![];
+        loop {
+            match await!(input.next()) {
+                Some(Ok(b'\n')) => break,
+                Some(Ok(byte)) => if line.len() < MAX_COMMAND { line.push(byte) },
+                _ => return
+            }
+        }
+
+        let command = String::from_utf8_lossy(&line).parse::<Command>().map_err(err);
+        let reply = match command {
+            Ok(Command::Attach{ name }) => match machines.context(&name) {
+                Ok(context) => {
+                    connection::serve(input, writer, b"ok\n".to_vec(), Peer::Unknown, context);
+                    return;
+                },
+                Err(e) => Err(e)
+            },
+            Ok(Command::Start{ name, path }) => machines.start(&name, &path).map(|_| String::new()),
+            Ok(Command::List) => Ok(machines.list()),
+            Ok(Command::Stop{ name }) => machines.stop(&name).map(|_| String::new()),
+            Err(e) => Err(e)
+        };
+
+        let reply = match reply {
+            Ok(body) => format!("ok\n{}", body),
+            Err(e) => format!("error: {}\n", e.message())
+        };
+        let _ = await!(tokio::io::write_all(writer, reply.into_bytes()));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("start calc /tmp/my prog.um\n".parse(), Ok(Command::Start{ name: "calc".to_string(), path: PathBuf::from("/tmp/my prog.um") }));
+        assert_eq!("list\r\n".parse(), Ok(Command::List));
+        assert_eq!("attach calc".parse(), Ok(Command::Attach{ name: "calc".to_string() }));
+        assert_eq!("stop calc\n".parse(), Ok(Command::Stop{ name: "calc".to_string() }));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        for bad in &["", "start", "start calc", "start  prog.um", "list all", "attach", "attach a b", "stop", "frobnicate calc"] {
+            assert!(bad.parse::<Command>().is_err(), "'{}' should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = vec![
+            Command::Start{ name: "calc".to_string(), path: PathBuf::from("prog.um") },
+            Command::List,
+            Command::Attach{ name: "calc".to_string() },
+            Command::Stop{ name: "calc".to_string() }
+        ];
+        for command in commands {
+            assert_eq!(command.to_string().parse(), Ok(command));
+        }
+    }
+
+}
@@ -0,0 +1,677 @@
+//! A Debug Adapter Protocol server, so that UM programs can be debugged from an editor.
+//!
+//! There's one thread, with one stack frame: the instruction the finger is on. Registers
+//! and arrays are shown as variables, and the program's output is sent to the debug console,
+//! where anything typed is sent to the program as input (and `~eof` sends EOF). Breakpoints can be set on fingers
+//! (as function or instruction breakpoints), or on source lines when the program comes with
+//! a sidecar mapping (`prog.um.map`) giving a `<finger> <path>:<line>` on each line.
+
+use crate::program::{ Program, StepResult, Inputter };
+use crate::instruction::Instruction;
+use crate::snapshot;
+use crate::error::Error;
+use crossbeam::channel;
+use serde_json::{ json, Value };
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::io::{ BufRead, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::thread;
+
+// While running, we check for new messages this often:
+const CHECK_EVERY: usize = 4096;
+
+// Output is sent on in chunks of up to this size:
+const OUTPUT_CHUNK: usize = 4096;
+
+// Messages (or header lines) longer than these aren't something we'd be sent
+// by an editor, so we give up on whoever is sending them:
+const MAX_MESSAGE: usize = 1024 * 1024;
+const MAX_HEADER_LINE: u64 = 1024;
+
+// The most variables handed back at once, if the editor doesn't ask for fewer:
+const MAX_VARIABLES: usize = 10000;
+
+// There's only one thread, and one stack frame:
+const THREAD_ID: u64 = 1;
+const FRAME_ID: u64 = 1;
+
+// Variable references for the registers and the list of arrays. Each array
+// that's been looked at since we last stopped gets a reference above these:
+const REGISTERS: u64 = 1;
+const ARRAYS: u64 = 2;
+const FIRST_ARRAY: u64 = 3;
+
+/// Where fingers came from in a program's source.
+#[derive(Debug, Default)]
+struct SourceMap {
+    lines: HashMap<usize, (PathBuf, usize)>,
+    fingers: HashMap<(PathBuf, usize), Vec<usize>>
+}
+
+impl SourceMap {
+
+    /// Read the sidecar mapping for a program, if there is one.
+    fn load(program: &Path) -> SourceMap {
+        let mut map = SourceMap::default();
+        let path = PathBuf::from(format!("{}.map", program.display()));
+        let dir = path.parent().map(|d| d.to_owned()).unwrap_or_default();
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => return map
+        };
+        for line in contents.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let (finger, location) = (parts.next().and_then(parse_finger), parts.next().map(|l| l.trim()));
+            let (finger, location) = match (finger, location) {
+                (Some(finger), Some(location)) => (finger, location),
+                _ => continue
+            };
+            let (source, number) = match location.rfind(':') {
+                Some(idx) => (&location[..idx], location[idx + 1..].parse::<usize>()),
+                None => continue
+            };
+            if let Ok(number) = number {
+                let source = canonical(&dir.join(source).to_string_lossy());
+                map.lines.insert(finger, (source.clone(), number));
+                map.fingers.entry((source, number)).or_insert_with(Vec::new).push(finger);
+            }
+        }
+        map
+    }
+
+    fn fingers(&self, source: &Path, line: usize) -> &[usize] {
+        self.fingers.get(&(source.to_owned(), line)).map(|f| &f[..]).unwrap_or(&[])
+    }
+
+}
+
+struct Debugger<W> {
+    out: W,
+    seq: u64,
+    messages: channel::Receiver<Value>,
+    program: Program,
+    waiting: Option<Inputter>,
+    input: VecDeque<u8>,
+    // Whether to send EOF once the input before it has been read:
+    eof: bool,
+    output: Vec<u8>,
+    source_map: SourceMap,
+    // Breakpoints as they were asked for, and the fingers they come to:
+    source_breaks: HashMap<PathBuf, Vec<usize>>,
+    finger_breaks: HashSet<usize>,
+    instruction_breaks: HashSet<usize>,
+    breakpoints: HashSet<usize>,
+    launched: bool,
+    configured: bool,
+    stop_on_entry: bool,
+    started: bool,
+    running: bool,
+    // Whether we've taken a step since we were last told to carry on (so
+    // that carrying on from a breakpoint doesn't stop at it straight away):
+    moved: bool,
+    // How many more steps to take before stopping, if we're stepping:
+    step_limit: Option<usize>,
+    // Once the program has halted or faulted, it can't carry on:
+    ended: bool,
+    finished: bool,
+    array_refs: Vec<usize>
+}
+
+/// Debug a program, reading messages from one place and sending them to another,
+/// until the editor disconnects.
+pub fn serve<R, W>(mut input: R, out: W) -> Result<(), Error>
+where R: BufRead + Send + 'static, W: Write {
+
+    // Messages are read on a thread of their own, so that
+    // they can be looked at while the program is running:
+    let (send_message, messages) = channel::unbounded();
+    thread::spawn(move || {
+        while let Some(message) = read_message(&mut input) {
+            if let Ok(message) = serde_json::from_slice(&message) {
+                send_message.send(message);
+            }
+        }
+    });
+
+    let mut debugger = Debugger {
+        out,
+        seq: 0,
+        messages,
+        program: Program::new(),
+        waiting: None,
+        input: VecDeque::new(),
+        eof: false,
+        output: vec![],
+        source_map: SourceMap::default(),
+        source_breaks: HashMap::new(),
+        finger_breaks: HashSet::new(),
+        instruction_breaks: HashSet::new(),
+        breakpoints: HashSet::new(),
+        launched: false,
+        configured: false,
+        stop_on_entry: false,
+        started: false,
+        running: false,
+        moved: false,
+        step_limit: None,
+        ended: false,
+        finished: false,
+        array_refs: vec![]
+    };
+
+    // Run while we can, answering messages as they arrive, and otherwise wait for them:
+    while !debugger.finished {
+        let blocked = !debugger.running || (debugger.waiting.is_some() && debugger.input.is_empty() && !debugger.eof);
+        let message = if blocked {
+            match debugger.messages.recv() {
+                Some(message) => Some(message),
+                None => break
+            }
+        } else {
+            debugger.messages.try_recv()
+        };
+        match message {
+            Some(message) => debugger.handle(message)?,
+            None => debugger.advance()?
+        }
+    }
+    Ok(())
+}
+
+// Read the body of the next message, which is preceded by headers
+// giving its length. None once there are no more messages:
+fn read_message<R: BufRead>(input: &mut R) -> Option<Vec<u8>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.by_ref().take(MAX_HEADER_LINE).read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        // A header line that doesn't end within the limit is too long:
+        if !line.ends_with('\n') {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(idx) = line.find(':') {
+            if line[..idx].trim().eq_ignore_ascii_case("content-length") {
+                length = line[idx + 1..].trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.filter(|&length| length <= MAX_MESSAGE)?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).ok()?;
+    Some(body)
+}
+
+impl <W: Write> Debugger<W> {
+
+    fn handle(&mut self, message: Value) -> Result<(), Error> {
+
+        if message["type"] != "request" {
+            return Ok(());
+        }
+        let args = message.get("arguments").cloned().unwrap_or(Value::Null);
+
+        match message["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.respond(&message, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsTerminateRequest": true,
+                    "supportsValueFormattingOptions": true
+                }))?;
+            },
+            "launch" => {
+                let path = match args["program"].as_str() {
+                    Some(path) => PathBuf::from(path),
+                    None => return self.fail(&message, "expected a program to launch")
+                };
+                let file = match std::fs::read(&path) {
+                    Ok(file) => file,
+                    Err(e) => return self.fail(&message, &format!("couldn't read {}: {}", path.display(), e))
+                };
+                if snapshot::is_snapshot(&file) {
+                    match snapshot::restore(&file) {
+                        Ok((program, waiting)) => {
+                            self.program = program;
+                            self.waiting = waiting;
+                        },
+                        Err(e) => return self.fail(&message, e.message())
+                    }
+                } else {
+                    self.program.load_program(&file);
+                }
+                self.source_map = SourceMap::load(&path);
+                self.update_breakpoints();
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.launched = true;
+                self.respond(&message, json!({}))?;
+                // Now that we know what's being debugged, breakpoints can be set:
+                self.event("initialized", json!({}))?;
+                self.start()?;
+            },
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(&message, json!({}))?;
+                self.start()?;
+            },
+            "setBreakpoints" => {
+                let source = match args["source"]["path"].as_str() {
+                    Some(source) => canonical(source),
+                    None => return self.fail(&message, "expected a source path")
+                };
+                let lines: Vec<usize> = args["breakpoints"].as_array()
+                    .map(|b| b.iter().filter_map(|b| b["line"].as_u64()).map(|l| l as usize).collect())
+                    .unwrap_or_default();
+                let breakpoints: Vec<Value> = lines.iter().map(|&line| json!({
+                    "verified": !self.source_map.fingers(&source, line).is_empty(),
+                    "line": line
+                })).collect();
+                self.source_breaks.insert(source, lines);
+                self.update_breakpoints();
+                self.respond(&message, json!({ "breakpoints": breakpoints }))?;
+            },
+            "setFunctionBreakpoints" => {
+                let fingers: Vec<Option<usize>> = args["breakpoints"].as_array()
+                    .map(|b| b.iter().map(|b| b["name"].as_str().and_then(parse_finger)).collect())
+                    .unwrap_or_default();
+                self.finger_breaks = fingers.iter().filter_map(|f| *f).collect();
+                self.update_breakpoints();
+                let breakpoints: Vec<Value> = fingers.iter().map(|f| json!({ "verified": f.is_some() })).collect();
+                self.respond(&message, json!({ "breakpoints": breakpoints }))?;
+            },
+            "setInstructionBreakpoints" => {
+                let fingers: Vec<Option<usize>> = args["breakpoints"].as_array()
+                    .map(|b| b.iter().map(|b| {
+                        let offset = b["offset"].as_i64().unwrap_or(0);
+                        b["instructionReference"].as_str().and_then(parse_finger).map(|f| (f as i64 + offset) as usize)
+                    }).collect())
+                    .unwrap_or_default();
+                self.instruction_breaks = fingers.iter().filter_map(|f| *f).collect();
+                self.update_breakpoints();
+                let breakpoints: Vec<Value> = fingers.iter().map(|f| json!({ "verified": f.is_some() })).collect();
+                self.respond(&message, json!({ "breakpoints": breakpoints }))?;
+            },
+            "threads" => {
+                self.respond(&message, json!({ "threads": [{ "id": THREAD_ID, "name": "UM" }] }))?;
+            },
+            "stackTrace" => {
+                let frame = self.frame();
+                self.respond(&message, json!({ "stackFrames": [frame], "totalFrames": 1 }))?;
+            },
+            "scopes" => {
+                self.respond(&message, json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                    { "name": "Arrays", "variablesReference": ARRAYS, "indexedVariables": self.program.active_arrays(), "expensive": true }
+                ]}))?;
+            },
+            "variables" => {
+                match self.variables(&args) {
+                    Some(variables) => self.respond(&message, json!({ "variables": variables }))?,
+                    None => self.fail(&message, "those variables no longer exist")?
+                }
+            },
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let stepping = message["command"] != "continue";
+                self.respond(&message, json!({ "allThreadsContinued": true }))?;
+                if self.ended {
+                    self.event("terminated", json!({}))?;
+                } else {
+                    self.running = true;
+                    self.moved = false;
+                    self.step_limit = if stepping { Some(1) } else { None };
+                }
+            },
+            "pause" => {
+                self.respond(&message, json!({}))?;
+                if self.running {
+                    self.stop("pause", None)?;
+                }
+            },
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or("");
+                if args["context"] == "repl" {
+                    // Typed into the debug console, so it's input for the program, besides
+                    // `~eof` (and a doubled up ~ sends a line starting with a single one).
+                    // Each character stands for the byte with the same code:
+                    if expression == "~eof" {
+                        self.eof = true;
+                    } else {
+                        let line = if expression.starts_with("~~") { &expression[1..] } else { expression };
+                        let bytes: Vec<u8> = line.chars().chain(Some('\n')).filter(|&c| (c as u32) < 256).map(|c| c as u8).collect();
+                        self.input.extend(&bytes);
+                    }
+                    self.respond(&message, json!({ "result": "", "variablesReference": 0 }))?;
+                } else {
+                    match self.evaluate(expression) {
+                        Some(result) => self.respond(&message, json!({ "result": result, "variablesReference": 0 }))?,
+                        None => self.fail(&message, "expected a register (r0 to r7) or finger")?
+                    }
+                }
+            },
+            "disconnect" => {
+                self.respond(&message, json!({}))?;
+                self.finished = true;
+            },
+            "terminate" => {
+                self.respond(&message, json!({}))?;
+                self.event("terminated", json!({}))?;
+                self.finished = true;
+            },
+            command => {
+                self.fail(&message, &format!("'{}' isn't supported", command))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Start running once we've been launched and configured:
+    fn start(&mut self) -> Result<(), Error> {
+        if !self.launched || !self.configured || self.started {
+            return Ok(());
+        }
+        self.started = true;
+        if self.stop_on_entry {
+            self.stop("entry", None)
+        } else {
+            self.running = true;
+            self.moved = false;
+            Ok(())
+        }
+    }
+
+    // Run for a while, until we stop or it's time to look for messages:
+    fn advance(&mut self) -> Result<(), Error> {
+        for _ in 0..CHECK_EVERY {
+            if let Some(inputter) = self.waiting {
+                match self.input.pop_front() {
+                    Some(byte) => self.program.provide_input(inputter, Some(byte)),
+                    None if self.eof => {
+                        self.program.provide_input(inputter, None);
+                        self.eof = false;
+                    },
+                    None => return self.send_output()
+                }
+                self.waiting = None;
+            }
+            if self.step_limit == Some(0) {
+                return self.stop("step", None);
+            }
+            if self.moved && self.breakpoints.contains(&self.program.instruction_index()) {
+                return self.stop("breakpoint", None);
+            }
+
+            self.moved = true;
+            if let Some(steps) = &mut self.step_limit {
+                *steps -= 1;
+            }
+            match self.program.step() {
+                Ok(StepResult::Halted) => {
+                    self.running = false;
+                    self.ended = true;
+                    self.send_output()?;
+                    self.event("exited", json!({ "exitCode": 0 }))?;
+                    return self.event("terminated", json!({}));
+                },
+                Ok(StepResult::Output{ ascii }) => {
+                    self.output.push(ascii);
+                    if self.output.len() >= OUTPUT_CHUNK {
+                        self.send_output()?;
+                    }
+                },
+                Ok(StepResult::InputNeeded{ inputter }) => self.waiting = Some(inputter),
+                Ok(StepResult::Continue) => {},
+                Err(e) => {
+                    // Stop where the fault happened, so that it can be looked into:
+                    self.ended = true;
+                    let reason = format!("the program has faulted: {}", e.message());
+                    self.send_output()?;
+                    self.event("output", json!({ "category": "stderr", "output": format!("{}\n", reason) }))?;
+                    return self.stop("exception", Some(reason));
+                }
+            }
+        }
+        self.send_output()
+    }
+
+    fn stop(&mut self, reason: &str, description: Option<String>) -> Result<(), Error> {
+        self.running = false;
+        self.step_limit = None;
+        self.array_refs.clear();
+        self.send_output()?;
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        self.event("stopped", body)
+    }
+
+    fn update_breakpoints(&mut self) {
+        let mut breakpoints: HashSet<usize> = self.finger_breaks.union(&self.instruction_breaks).cloned().collect();
+        for (source, lines) in &self.source_breaks {
+            for &line in lines {
+                breakpoints.extend(self.source_map.fingers(source, line));
+            }
+        }
+        self.breakpoints = breakpoints;
+    }
+
+    fn frame(&self) -> Value {
+        let finger = self.program.instruction_index();
+        let name = match self.program.array(0).and_then(|code| code.get(finger)) {
+            Some(&platter) => format!("{}: {:?}", finger, Instruction::decode(platter)),
+            None => format!("{}: (past the end of the program)", finger)
+        };
+        let mut frame = json!({
+            "id": FRAME_ID,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": finger.to_string()
+        });
+        if let Some((source, line)) = self.source_map.lines.get(&finger) {
+            frame["source"] = json!({ "path": source });
+            frame["line"] = json!(line);
+        }
+        frame
+    }
+
+    fn variables(&mut self, args: &Value) -> Option<Vec<Value>> {
+        let start = args["start"].as_u64().unwrap_or(0) as usize;
+        let count = match args["count"].as_u64() {
+            Some(count) if count > 0 => std::cmp::min(count as usize, MAX_VARIABLES),
+            _ => MAX_VARIABLES
+        };
+        let hex = args["format"]["hex"].as_bool().unwrap_or(false);
+        let show = |value: u32| if hex { format!("0x{:08x}", value) } else { value.to_string() };
+
+        let variables = match args["variablesReference"].as_u64()? {
+            REGISTERS => self.program.registers().iter().enumerate().map(|(idx, reg)| json!({
+                "name": format!("r{}", idx),
+                "value": show(reg.to_u32()),
+                "variablesReference": 0
+            })).collect(),
+            ARRAYS => {
+                let arrays: Vec<(usize, usize)> = self.program.arrays().skip(start).take(count).map(|(id, a)| (id, a.len())).collect();
+                arrays.into_iter().map(|(id, len)| json!({
+                    "name": format!("array {}", id),
+                    "value": format!("{} platters", len),
+                    "variablesReference": self.array_ref(id),
+                    "indexedVariables": len
+                })).collect()
+            },
+            reference => {
+                let id = *self.array_refs.get(reference.checked_sub(FIRST_ARRAY)? as usize)?;
+                self.program.array(id)?.iter().enumerate().skip(start).take(count).map(|(idx, platter)| json!({
+                    "name": format!("[{}]", idx),
+                    "value": show(platter.to_u32()),
+                    "variablesReference": 0
+                })).collect()
+            }
+        };
+        Some(variables)
+    }
+
+    // A reference to expand an array with, which lasts until we next stop:
+    fn array_ref(&mut self, id: usize) -> u64 {
+        let idx = match self.array_refs.iter().position(|&a| a == id) {
+            Some(idx) => idx,
+            None => {
+                self.array_refs.push(id);
+                self.array_refs.len() - 1
+            }
+        };
+        FIRST_ARRAY + idx as u64
+    }
+
+    fn evaluate(&self, expression: &str) -> Option<String> {
+        let expression = expression.trim();
+        if expression == "finger" {
+            return Some(self.program.instruction_index().to_string());
+        }
+        if expression.starts_with('r') {
+            let reg = expression[1..].parse::<usize>().ok()?;
+            return self.program.registers().get(reg).map(|r| r.to_u32().to_string());
+        }
+        None
+    }
+
+    fn send_output(&mut self) -> Result<(), Error> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        // Each byte stands for the character with the same code:
+        let output: String = self.output.drain(..).map(|b| b as char).collect();
+        self.event("output", json!({ "category": "stdout", "output": output }))
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> Result<(), Error> {
+        let response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body
+        });
+        self.send(response)
+    }
+
+    fn fail(&mut self, request: &Value, reason: &str) -> Result<(), Error> {
+        let response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": reason
+        });
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<(), Error> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), Error> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+}
+
+// Fingers are given in decimal, or in hex with a leading 0x:
+fn parse_finger(text: &str) -> Option<usize> {
+    let text = text.trim();
+    if text.starts_with("0x") {
+        usize::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn canonical(path: &str) -> PathBuf {
+    Path::new(path).canonicalize().unwrap_or_else(|_| PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn read_all(input: &[u8]) -> Vec<Option<Vec<u8>>> {
+        let mut input = std::io::BufReader::new(input);
+        let mut messages = vec![];
+        loop {
+            let message = read_message(&mut input);
+            let done = message.is_none();
+            messages.push(message);
+            if done {
+                return messages;
+            }
+        }
+    }
+
+    #[test]
+    fn reads_messages() {
+        let input = b"Content-Length: 5\r\n\r\nhello\
+                      content-length:  2\r\nContent-Type: x\r\n\r\nhi";
+        assert_eq!(read_all(input), vec![Some(b"hello".to_vec()), Some(b"hi".to_vec()), None]);
+    }
+
+    #[test]
+    fn needs_a_length() {
+        assert_eq!(read_all(b"Content-Type: x\r\n\r\nhello"), vec![None]);
+        assert_eq!(read_all(b"Content-Length: lots\r\n\r\nhello"), vec![None]);
+        // The body must all be there:
+        assert_eq!(read_all(b"Content-Length: 10\r\n\r\nhello"), vec![None]);
+    }
+
+    #[test]
+    fn refuses_oversized_messages() {
+        let header = format!("X-Padding: {}\r\nContent-Length: 2\r\n\r\nhi", "a".repeat(MAX_HEADER_LINE as usize));
+        assert_eq!(read_all(header.as_bytes()), vec![None]);
+
+        let body = format!("Content-Length: {}\r\n\r\n{}", MAX_MESSAGE + 1, "a".repeat(MAX_MESSAGE + 1));
+        assert_eq!(read_all(body.as_bytes()), vec![None]);
+
+        let body = format!("Content-Length: {}\r\n\r\n{}", MAX_MESSAGE, "a".repeat(MAX_MESSAGE));
+        assert_eq!(read_all(body.as_bytes()).len(), 2);
+    }
+
+    #[test]
+    fn loads_source_maps() {
+        let dir = std::env::temp_dir().join(format!("um-dap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("prog.s");
+        std::fs::write(&source, "").unwrap();
+        std::fs::write(dir.join("prog.um.map"), "# finger line\n\
+                                                  0 prog.s:1\n\
+                                                  0x2 prog.s:3\n\
+                                                  \t5   prog.s:3\n\
+                                                  \n\
+                                                  nonsense\n\
+                                                  7 prog.s:x\n\
+                                                  8 prog.s\n").unwrap();
+
+        let map = SourceMap::load(&dir.join("prog.um"));
+        let missing = SourceMap::load(&dir.join("other.um"));
+        let source = source.canonicalize().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(map.lines.len(), 3);
+        assert_eq!(map.lines.get(&0), Some(&(source.clone(), 1)));
+        assert_eq!(map.lines.get(&2), Some(&(source.clone(), 3)));
+        assert_eq!(map.fingers(&source, 3), &[2, 5]);
+        assert_eq!(map.fingers(&source, 2), &[] as &[usize]);
+        assert!(missing.lines.is_empty());
+    }
+
+}
@@ -0,0 +1,1052 @@
+use crate::platter::Platter;
+use crate::instruction::Instruction;
+use std::collections::{ BTreeMap, BTreeSet, VecDeque };
+use std::fmt::{ self, Display, Write };
+
+/// Lift a UM program into structured pseudo-code. Execution is assumed to begin
+/// at finger 0 with every register set to 0, as it does for a freshly loaded
+/// program. Code which is only reached via computed jumps that we can't resolve
+/// is not decompiled, and is reported as unreached instead.
+pub fn decompile(code: &[Platter]) -> String {
+    let decompiler = Decompiler::new(code);
+    let mut out = String::new();
+    decompiler.render(&mut out);
+    out
+}
+
+/// Produce a plain listing of the instructions in a UM program,
+/// one line per platter.
+pub fn disassemble(code: &[Platter]) -> String {
+    let mut out = String::new();
+    for (finger, &platter) in code.iter().enumerate() {
+        let _ = writeln!(out, "{:08x}: {:08x}  {}", finger, platter.to_u32(), Instruction::decode(platter));
+    }
+    out
+}
+
+/// What we know about the value of a register on entry to a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Const(u32),
+    Array(usize),
+    Unknown
+}
+
+type State = [Value; 8];
+
+const ALL_REGISTERS: u8 = 0xFF;
+
+fn join(a: &State, b: &State) -> State {
+    let mut out = *a;
+    for (o, b) in out.iter_mut().zip(b.iter()) {
+        if *o != *b {
+            *o = Value::Unknown;
+        }
+    }
+    out
+}
+
+/// An expression over the values held in registers at some point in a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Const(u32),
+    Reg(usize),
+    Temp(usize),
+    // The identifier handed back by the allocation at this finger:
+    Array(usize),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Nand(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Select(Box<Expr>, Box<Expr>, Box<Expr>),
+    Load(Box<Expr>, Box<Expr>),
+    Input
+}
+
+impl Expr {
+
+    fn add(a: Expr, b: Expr) -> Expr {
+        // Keep any constant on the right to cut down on the cases to match:
+        let (a, b) = match (a, b) {
+            (Expr::Const(c), x) => (x, Expr::Const(c)),
+            other => other
+        };
+        match (a, b) {
+            (Expr::Const(a), Expr::Const(b)) => Expr::Const(a.wrapping_add(b)),
+            (x, Expr::Const(0)) => x,
+            // (x + ~y) + 1 is how subtraction is spelled:
+            (Expr::Add(x, y), Expr::Const(1)) => {
+                match (*x, *y) {
+                    (x, Expr::Not(y)) | (Expr::Not(y), x) => Expr::Sub(Box::new(x), y),
+                    (x, Expr::Const(d)) => Expr::add(x, Expr::Const(d.wrapping_add(1))),
+                    (x, y) => Expr::Add(Box::new(Expr::Add(Box::new(x), Box::new(y))), Box::new(Expr::Const(1)))
+                }
+            },
+            // ~y + 1 is negation:
+            (Expr::Not(y), Expr::Const(1)) => {
+                Expr::Sub(Box::new(Expr::Const(0)), y)
+            },
+            // Fold constants into an existing constant offset:
+            (Expr::Add(x, y), Expr::Const(c)) => {
+                match *y {
+                    Expr::Const(d) => Expr::add(*x, Expr::Const(c.wrapping_add(d))),
+                    y => Expr::Add(Box::new(Expr::Add(x, Box::new(y))), Box::new(Expr::Const(c)))
+                }
+            },
+            (x, Expr::Const(c)) => {
+                if c >= 0x8000_0000 {
+                    Expr::Sub(Box::new(x), Box::new(Expr::Const(c.wrapping_neg())))
+                } else {
+                    Expr::Add(Box::new(x), Box::new(Expr::Const(c)))
+                }
+            },
+            // x + -y is x - y:
+            (x, Expr::Sub(z, y)) | (Expr::Sub(z, y), x) => {
+                if *z == Expr::Const(0) {
+                    Expr::Sub(Box::new(x), y)
+                } else {
+                    Expr::Add(Box::new(x), Box::new(Expr::Sub(z, y)))
+                }
+            },
+            (a, b) => Expr::Add(Box::new(a), Box::new(b))
+        }
+    }
+
+    fn mul(a: Expr, b: Expr) -> Expr {
+        match (a, b) {
+            (Expr::Const(a), Expr::Const(b)) => Expr::Const(a.wrapping_mul(b)),
+            (x, Expr::Const(1)) | (Expr::Const(1), x) => x,
+            (_, Expr::Const(0)) | (Expr::Const(0), _) => Expr::Const(0),
+            (a, b) => Expr::Mul(Box::new(a), Box::new(b))
+        }
+    }
+
+    fn div(a: Expr, b: Expr) -> Expr {
+        match (a, b) {
+            (x, Expr::Const(1)) => x,
+            (Expr::Const(a), Expr::Const(b)) => {
+                if b != 0 {
+                    Expr::Const(a / b)
+                } else {
+                    Expr::Div(Box::new(Expr::Const(a)), Box::new(Expr::Const(b)))
+                }
+            },
+            (a, b) => Expr::Div(Box::new(a), Box::new(b))
+        }
+    }
+
+    fn nand(a: Expr, b: Expr) -> Expr {
+        match (a, b) {
+            (Expr::Const(a), Expr::Const(b)) => Expr::Const(!(a & b)),
+            (x, Expr::Const(0xFFFF_FFFF)) | (Expr::Const(0xFFFF_FFFF), x) => Expr::not(x),
+            (_, Expr::Const(0)) | (Expr::Const(0), _) => Expr::Const(0xFFFF_FFFF),
+            (Expr::Not(a), Expr::Not(b)) => Expr::Or(a, b),
+            (a, b) => {
+                if a == b {
+                    Expr::not(a)
+                } else {
+                    Expr::Nand(Box::new(a), Box::new(b))
+                }
+            }
+        }
+    }
+
+    fn not(a: Expr) -> Expr {
+        match a {
+            Expr::Const(a) => Expr::Const(!a),
+            Expr::Not(a) => *a,
+            Expr::Nand(a, b) => Expr::And(a, b),
+            Expr::And(a, b) => Expr::Nand(a, b),
+            a => Expr::Not(Box::new(a))
+        }
+    }
+
+    fn select(cond: Expr, then: Expr, otherwise: Expr) -> Expr {
+        match cond {
+            Expr::Const(0) => otherwise,
+            Expr::Const(_) => then,
+            cond => {
+                if then == otherwise {
+                    then
+                } else {
+                    Expr::Select(Box::new(cond), Box::new(then), Box::new(otherwise))
+                }
+            }
+        }
+    }
+
+    fn value(&self) -> Value {
+        match *self {
+            Expr::Const(c) => Value::Const(c),
+            Expr::Array(site) => Value::Array(site),
+            _ => Value::Unknown
+        }
+    }
+
+    fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Const(..) | Expr::Reg(..) | Expr::Temp(..) | Expr::Array(..) | Expr::Input => vec![],
+            Expr::Not(a) => vec![a],
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b)
+                | Expr::Nand(a, b) | Expr::And(a, b) | Expr::Or(a, b) | Expr::Load(a, b) => vec![a, b],
+            Expr::Select(a, b, c) => vec![a, b, c]
+        }
+    }
+
+    fn uses_reg(&self, reg: usize) -> bool {
+        if let Expr::Reg(r) = *self {
+            return r == reg;
+        }
+        self.children().into_iter().any(|e| e.uses_reg(reg))
+    }
+
+    fn replace_reg(&mut self, reg: usize, with: &Expr) {
+        match self {
+            Expr::Reg(r) if *r == reg => { *self = with.clone(); },
+            Expr::Const(..) | Expr::Reg(..) | Expr::Temp(..) | Expr::Array(..) | Expr::Input => {},
+            Expr::Not(a) => a.replace_reg(reg, with),
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b)
+                | Expr::Nand(a, b) | Expr::And(a, b) | Expr::Or(a, b) | Expr::Load(a, b) => {
+                a.replace_reg(reg, with);
+                b.replace_reg(reg, with);
+            },
+            Expr::Select(a, b, c) => {
+                a.replace_reg(reg, with);
+                b.replace_reg(reg, with);
+                c.replace_reg(reg, with);
+            }
+        }
+    }
+
+    fn is_atom(&self) -> bool {
+        match self {
+            Expr::Const(..) | Expr::Reg(..) | Expr::Temp(..) | Expr::Array(..)
+                | Expr::Input | Expr::Load(..) => true,
+            _ => false
+        }
+    }
+
+}
+
+/// Wraps an expression which is being used as an array identifier
+/// so that it displays as the array it names.
+struct ArrayName<'a>(&'a Expr);
+
+impl <'a> Display for ArrayName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Expr::Const(0) => write!(f, "code"),
+            Expr::Array(..) => write!(f, "{}", self.0),
+            e => write!(f, "mem({})", e)
+        }
+    }
+}
+
+/// Wraps an expression which is used as a subexpression, so that
+/// it gets parenthesised if needbe.
+struct Operand<'a>(&'a Expr);
+
+impl <'a> Display for Operand<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_atom() {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "({})", self.0)
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Const(c) if *c < 10 => write!(f, "{}", c),
+            Expr::Const(c) => write!(f, "{:#x}", c),
+            Expr::Reg(r) => write!(f, "r{}", r),
+            Expr::Temp(t) => write!(f, "t{}", t),
+            Expr::Array(site) => write!(f, "arr_{:06x}", site),
+            Expr::Add(a, b) => write!(f, "{} + {}", Operand(a), Operand(b)),
+            Expr::Sub(a, b) if **a == Expr::Const(0) => write!(f, "-{}", Operand(b)),
+            Expr::Sub(a, b) => write!(f, "{} - {}", Operand(a), Operand(b)),
+            Expr::Mul(a, b) => write!(f, "{} * {}", Operand(a), Operand(b)),
+            Expr::Div(a, b) => write!(f, "{} / {}", Operand(a), Operand(b)),
+            Expr::Nand(a, b) => write!(f, "~({} & {})", Operand(a), Operand(b)),
+            Expr::And(a, b) => write!(f, "{} & {}", Operand(a), Operand(b)),
+            Expr::Or(a, b) => write!(f, "{} | {}", Operand(a), Operand(b)),
+            Expr::Not(a) => write!(f, "~{}", Operand(a)),
+            Expr::Select(c, a, b) => write!(f, "{} ? {} : {}", Operand(c), Operand(a), Operand(b)),
+            Expr::Load(a, i) => write!(f, "{}[{}]", ArrayName(a), i),
+            Expr::Input => write!(f, "getc()")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Var {
+    Reg(usize),
+    Temp(usize)
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Assign(Var, Expr),
+    Store{ array: Expr, index: Expr, value: Expr },
+    Alloc{ site: usize, size: Expr },
+    Free(Expr),
+    Output(Expr)
+}
+
+impl Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stmt::Assign(Var::Reg(r), e) => write!(f, "r{} = {};", r, e),
+            Stmt::Assign(Var::Temp(t), e) => write!(f, "t{} = {};", t, e),
+            Stmt::Store{ array, index, value } => write!(f, "{}[{}] = {};", ArrayName(array), index, value),
+            Stmt::Alloc{ site, size } => write!(f, "{} = alloc({});", Expr::Array(*site), size),
+            Stmt::Free(e) => write!(f, "free({});", ArrayName(e)),
+            Stmt::Output(Expr::Const(c)) if *c < 128 && (*c as u8 as char).is_ascii_graphic() => {
+                write!(f, "putc('{}');", *c as u8 as char)
+            },
+            Stmt::Output(e) => write!(f, "putc({});", e)
+        }
+    }
+}
+
+/// How control leaves a block.
+#[derive(Debug, Clone)]
+enum Exit {
+    Goto(usize),
+    Branch{ cond: Expr, then: usize, otherwise: usize },
+    Jump(Expr),
+    LoadProgram{ array: Expr, finger: Expr },
+    Halt,
+    Fault(String)
+}
+
+impl Exit {
+    fn targets(&self) -> Vec<usize> {
+        match *self {
+            Exit::Goto(t) => vec![t],
+            Exit::Branch{ then, otherwise, .. } => vec![then, otherwise],
+            _ => vec![]
+        }
+    }
+    fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Exit::Branch{ cond, .. } => vec![cond],
+            Exit::Jump(e) => vec![e],
+            Exit::LoadProgram{ array, finger } => vec![array, finger],
+            _ => vec![]
+        }
+    }
+}
+
+/// A straight run of instructions, lifted into statements.
+#[derive(Debug, Clone)]
+struct Block {
+    start: usize,
+    end: usize,
+    stmts: Vec<Stmt>,
+    exit: Exit,
+    exit_state: State,
+    // If this block looks like a subroutine call, where we expect
+    // control to return to afterwards:
+    returns_to: Option<usize>
+}
+
+impl Block {
+    fn successors(&self) -> Vec<usize> {
+        let mut succs = self.exit.targets();
+        if let Some(ret) = self.returns_to {
+            succs.push(ret);
+        }
+        succs
+    }
+}
+
+/// Turns the instructions from a given finger into a block. Register contents are
+/// tracked as expressions, and only written out when something needs them.
+struct Lifter {
+    pending: Vec<Expr>,
+    clean: [bool; 8],
+    stmts: Vec<Stmt>,
+    temps: usize
+}
+
+impl Lifter {
+
+    fn new(entry: &State) -> Lifter {
+        let pending = entry.iter().enumerate().map(|(r, v)| match *v {
+            Value::Const(c) => Expr::Const(c),
+            Value::Array(site) => Expr::Array(site),
+            Value::Unknown => Expr::Reg(r)
+        }).collect();
+        Lifter {
+            pending,
+            clean: [true; 8],
+            stmts: vec![],
+            temps: 0
+        }
+    }
+
+    fn get(&self, reg: usize) -> Expr {
+        self.pending[reg].clone()
+    }
+
+    fn set(&mut self, reg: usize, expr: Expr) {
+        if self.pending[reg] != expr {
+            self.pending[reg] = expr;
+            self.clean[reg] = false;
+        }
+    }
+
+    /// Emit an assignment to a register. Anything still waiting on the
+    /// old value of the register is pointed at a temporary copy of it first.
+    fn write(&mut self, reg: usize, expr: Expr, extra: &mut [&mut Expr]) {
+        let needs_old = (0..8).any(|r| r != reg && self.pending[r].uses_reg(reg))
+            || extra.iter().any(|e| e.uses_reg(reg));
+        if needs_old {
+            let temp = Expr::Temp(self.temps);
+            self.stmts.push(Stmt::Assign(Var::Temp(self.temps), Expr::Reg(reg)));
+            self.temps += 1;
+            for r in (0..8).filter(|&r| r != reg) {
+                self.pending[r].replace_reg(reg, &temp);
+            }
+            for e in extra.iter_mut() {
+                e.replace_reg(reg, &temp);
+            }
+        }
+        self.stmts.push(Stmt::Assign(Var::Reg(reg), expr));
+        self.pending[reg] = Expr::Reg(reg);
+        self.clean[reg] = true;
+    }
+
+    /// Make sure the given registers actually hold their pending values.
+    fn materialize(&mut self, live: u8, extra: &mut [&mut Expr]) {
+        for reg in (0..8).filter(|r| live & (1 << r) != 0) {
+            if !self.clean[reg] && self.pending[reg] != Expr::Reg(reg) {
+                let expr = self.get(reg);
+                self.write(reg, expr, extra);
+            }
+        }
+    }
+
+    fn state(&self) -> State {
+        let mut state = [Value::Unknown; 8];
+        for (s, e) in state.iter_mut().zip(self.pending.iter()) {
+            *s = e.value();
+        }
+        state
+    }
+
+}
+
+fn lift_block<F>(code: &[Platter], start: usize, entry: &State, leaders: &BTreeSet<usize>, live_out: F) -> Block
+where F: Fn(&Exit) -> u8 {
+
+    let mut lifter = Lifter::new(entry);
+    let mut finger = start;
+
+    let mut exit = loop {
+
+        let instruction = match code.get(finger) {
+            Some(&platter) => Instruction::decode(platter),
+            None => break Exit::Fault(format!("finger {:#x} is outside of the program", finger))
+        };
+        finger += 1;
+
+        match instruction {
+            Instruction::ConditionalMove{ a, b, c } => {
+                let expr = Expr::select(lifter.get(c), lifter.get(b), lifter.get(a));
+                lifter.set(a, expr);
+            },
+            Instruction::ArrayIndex{ a, b, c } => {
+                let expr = Expr::Load(Box::new(lifter.get(b)), Box::new(lifter.get(c)));
+                lifter.write(a, expr, &mut []);
+            },
+            Instruction::ArrayAmendment{ a, b, c } => {
+                lifter.stmts.push(Stmt::Store{ array: lifter.get(a), index: lifter.get(b), value: lifter.get(c) });
+            },
+            Instruction::Addition{ a, b, c } => {
+                let expr = Expr::add(lifter.get(b), lifter.get(c));
+                lifter.set(a, expr);
+            },
+            Instruction::Multiplication{ a, b, c } => {
+                let expr = Expr::mul(lifter.get(b), lifter.get(c));
+                lifter.set(a, expr);
+            },
+            Instruction::Division{ a, b, c } => {
+                let expr = Expr::div(lifter.get(b), lifter.get(c));
+                lifter.set(a, expr);
+            },
+            Instruction::NotAnd{ a, b, c } => {
+                let expr = Expr::nand(lifter.get(b), lifter.get(c));
+                lifter.set(a, expr);
+            },
+            Instruction::Halt => {
+                break Exit::Halt;
+            },
+            Instruction::Allocation{ b, c } => {
+                lifter.stmts.push(Stmt::Alloc{ site: finger - 1, size: lifter.get(c) });
+                lifter.set(b, Expr::Array(finger - 1));
+            },
+            Instruction::Abandonment{ c } => {
+                lifter.stmts.push(Stmt::Free(lifter.get(c)));
+            },
+            Instruction::Output{ c } => {
+                lifter.stmts.push(Stmt::Output(lifter.get(c)));
+            },
+            Instruction::Input{ c } => {
+                lifter.write(c, Expr::Input, &mut []);
+            },
+            Instruction::LoadProgram{ b, c } => {
+                break match (lifter.get(b), lifter.get(c)) {
+                    (Expr::Const(0), Expr::Const(target)) => Exit::Goto(target as usize),
+                    (Expr::Const(0), Expr::Select(cond, then, otherwise)) => {
+                        match (*then, *otherwise) {
+                            (Expr::Const(then), Expr::Const(otherwise)) => {
+                                Exit::Branch{ cond: *cond, then: then as usize, otherwise: otherwise as usize }
+                            },
+                            (then, otherwise) => Exit::Jump(Expr::Select(cond, Box::new(then), Box::new(otherwise)))
+                        }
+                    },
+                    (Expr::Const(0), target) => Exit::Jump(target),
+                    (array, finger) => Exit::LoadProgram{ array, finger }
+                };
+            },
+            Instruction::Orthography{ a, value } => {
+                lifter.set(a, Expr::Const(value));
+            },
+            Instruction::Invalid{ op } => {
+                break Exit::Fault(format!("invalid operator {}", op));
+            }
+        }
+
+        if leaders.contains(&finger) {
+            break Exit::Goto(finger);
+        }
+
+    };
+
+    // A jump which leaves the address of the next instruction in a register
+    // is most likely a call that we'll return from:
+    let returns_to = match exit {
+        Exit::Goto(target) if target == finger => None,
+        Exit::Goto(_) | Exit::Jump(_) if finger < code.len() => {
+            let ret = Expr::Const(finger as u32);
+            if lifter.pending.iter().any(|e| *e == ret) { Some(finger) } else { None }
+        },
+        _ => None
+    };
+
+    // Work out what we know about registers before writing them out,
+    // and then write out the ones that are needed after the block:
+    let exit_state = lifter.state();
+    let live = live_out(&exit);
+    lifter.materialize(live, &mut exit.exprs_mut());
+
+    Block {
+        start,
+        end: finger,
+        stmts: lifter.stmts,
+        exit,
+        exit_state,
+        returns_to
+    }
+}
+
+/// The registers read and written by an instruction, as bitmasks.
+fn uses_and_defs(instruction: Instruction) -> (u8, u8) {
+    let bit = |r: usize| 1u8 << r;
+    match instruction {
+        Instruction::ConditionalMove{ a, b, c } => (bit(a) | bit(b) | bit(c), 0),
+        Instruction::ArrayIndex{ a, b, c } => (bit(b) | bit(c), bit(a)),
+        Instruction::ArrayAmendment{ a, b, c } => (bit(a) | bit(b) | bit(c), 0),
+        Instruction::Addition{ a, b, c }
+            | Instruction::Multiplication{ a, b, c }
+            | Instruction::Division{ a, b, c }
+            | Instruction::NotAnd{ a, b, c } => (bit(b) | bit(c), bit(a)),
+        Instruction::Halt => (0, 0),
+        Instruction::Allocation{ b, c } => (bit(c), bit(b)),
+        Instruction::Abandonment{ c } => (bit(c), 0),
+        Instruction::Output{ c } => (bit(c), 0),
+        Instruction::Input{ c } => (0, bit(c)),
+        Instruction::LoadProgram{ b, c } => (bit(b) | bit(c), 0),
+        Instruction::Orthography{ a, .. } => (0, bit(a)),
+        Instruction::Invalid{ .. } => (0, 0)
+    }
+}
+
+struct Decompiler<'a> {
+    code: &'a [Platter],
+    blocks: Vec<Block>,
+    index: BTreeMap<usize, usize>,
+    // Loops that can be printed structurally, by index of
+    // header block to index of last block in the loop:
+    loops: BTreeMap<usize, usize>
+}
+
+impl <'a> Decompiler<'a> {
+
+    fn new(code: &'a [Platter]) -> Decompiler<'a> {
+
+        let (leaders, entries) = discover(code);
+
+        // Work out which registers are live on entry to each block:
+        let live_in = liveness(code, &leaders, &entries);
+        let live_out = |exit: &Exit| live_out(exit, &live_in);
+
+        // Now we know that, we can lift each block properly:
+        let blocks: Vec<Block> = entries.iter()
+            .map(|(&start, entry)| lift_block(code, start, entry, &leaders, &live_out))
+            .collect();
+        let index = blocks.iter().enumerate().map(|(i, b)| (b.start, i)).collect();
+
+        let mut decompiler = Decompiler {
+            code,
+            blocks,
+            index,
+            loops: BTreeMap::new()
+        };
+        decompiler.loops = decompiler.find_loops();
+        decompiler
+    }
+
+    /// Find natural loops, keeping those whose blocks are laid out
+    /// contiguously and nest properly, since we can print those as loops.
+    fn find_loops(&self) -> BTreeMap<usize, usize> {
+
+        let preds = self.predecessors();
+        let idom = self.dominators(&preds);
+        let dominates = |a: usize, mut b: usize| {
+            loop {
+                if a == b { return true; }
+                match idom[b] {
+                    Some(d) if d != b => { b = d; },
+                    _ => return false
+                }
+            }
+        };
+
+        let mut bodies: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        for (latch, block) in self.blocks.iter().enumerate() {
+            for header in block.exit.targets().into_iter().filter_map(|t| self.index.get(&t).cloned()) {
+                if idom[latch].is_none() || !dominates(header, latch) {
+                    continue;
+                }
+                // Walk backwards from the latch to collect the loop body:
+                let body = bodies.entry(header).or_insert_with(BTreeSet::new);
+                body.insert(header);
+                let mut todo = vec![latch];
+                while let Some(n) = todo.pop() {
+                    if body.insert(n) {
+                        todo.extend(preds[n].iter().cloned());
+                    }
+                }
+            }
+        }
+
+        let mut loops: BTreeMap<usize, usize> = BTreeMap::new();
+        for (header, body) in bodies {
+            let last = *body.iter().next_back().unwrap();
+            let contiguous = body.iter().next() == Some(&header) && body.len() == last - header + 1;
+            let nests = loops.iter().all(|(&h, &l)| last < h || header > l || (header >= h && last <= l));
+            if contiguous && nests {
+                loops.insert(header, last);
+            }
+        }
+        loops
+    }
+
+    fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut preds = vec![vec![]; self.blocks.len()];
+        for (i, block) in self.blocks.iter().enumerate() {
+            for succ in block.successors().into_iter().filter_map(|s| self.index.get(&s)) {
+                preds[*succ].push(i);
+            }
+        }
+        preds
+    }
+
+    /// Immediate dominators of each block reachable from the
+    /// entry block (Cooper, Harvey & Kennedy).
+    fn dominators(&self, preds: &[Vec<usize>]) -> Vec<Option<usize>> {
+
+        let n = self.blocks.len();
+        let mut idom = vec![None; n];
+        let entry = match self.index.get(&0) {
+            Some(&e) => e,
+            None => return idom
+        };
+
+        // Reverse postorder from the entry block:
+        let mut order = vec![];
+        let mut seen = vec![false; n];
+        let mut stack = vec![(entry, 0)];
+        seen[entry] = true;
+        while let Some((node, next)) = stack.pop() {
+            let succs: Vec<usize> = self.blocks[node].successors().iter().filter_map(|s| self.index.get(s).cloned()).collect();
+            if next < succs.len() {
+                stack.push((node, next + 1));
+                let succ = succs[next];
+                if !seen[succ] {
+                    seen[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(node);
+            }
+        }
+        order.reverse();
+        let mut rpo = vec![0; n];
+        for (i, &node) in order.iter().enumerate() {
+            rpo[node] = i;
+        }
+
+        idom[entry] = Some(entry);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in order.iter().skip(1) {
+                let mut new_idom: Option<usize> = None;
+                for &p in preds[node].iter().filter(|&&p| idom[p].is_some()) {
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(mut a) => {
+                            let mut b = p;
+                            while a != b {
+                                while rpo[a] > rpo[b] { a = idom[a].unwrap(); }
+                                while rpo[b] > rpo[a] { b = idom[b].unwrap(); }
+                            }
+                            a
+                        }
+                    });
+                }
+                if new_idom.is_some() && idom[node] != new_idom {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "// {} platters, {} blocks, {} loops", self.code.len(), self.blocks.len(), self.loops.len());
+        self.render_blocks(0, self.blocks.len(), &mut vec![], 0, out);
+        if let Some(last) = self.blocks.last() {
+            self.render_gap(last.end, self.code.len(), 0, out);
+        }
+    }
+
+    fn render_blocks(&self, from: usize, to: usize, enclosing: &mut Vec<(usize, usize)>, depth: usize, out: &mut String) {
+        let mut i = from;
+        while i < to {
+            if let Some(&last) = self.loops.get(&i) {
+                if enclosing.iter().all(|&(h, _)| h != i) {
+                    let _ = writeln!(out, "{}loop L_{:06x} {{", indent(depth), self.blocks[i].start);
+                    enclosing.push((i, last));
+                    self.render_blocks(i, last + 1, enclosing, depth + 1, out);
+                    enclosing.pop();
+                    let _ = writeln!(out, "{}}}", indent(depth));
+                    if last + 1 < to {
+                        self.render_gap(self.blocks[last].end, self.blocks[last + 1].start, depth, out);
+                    }
+                    i = last + 1;
+                    continue;
+                }
+            }
+            self.render_block(i, enclosing, depth, out);
+            if i + 1 < to {
+                self.render_gap(self.blocks[i].end, self.blocks[i + 1].start, depth, out);
+            }
+            i += 1;
+        }
+    }
+
+    fn render_gap(&self, from: usize, to: usize, depth: usize, out: &mut String) {
+        if from < to {
+            let _ = writeln!(out, "{}// {:06x}-{:06x}: {} platters not reached", indent(depth), from, to - 1, to - from);
+        }
+    }
+
+    fn render_block(&self, i: usize, enclosing: &[(usize, usize)], depth: usize, out: &mut String) {
+
+        let block = &self.blocks[i];
+        let _ = writeln!(out, "{}L_{:06x}:", indent(depth), block.start);
+
+        // Runs of constant output are shown as strings:
+        let pad = indent(depth + 1);
+        let mut text = String::new();
+        for stmt in &block.stmts {
+            if let Stmt::Output(Expr::Const(c)) = stmt {
+                if let Some(escaped) = escape_char(*c) {
+                    text.push_str(&escaped);
+                    continue;
+                }
+            }
+            flush_text(&mut text, &pad, out);
+            let _ = writeln!(out, "{}{}", pad, stmt);
+        }
+        flush_text(&mut text, &pad, out);
+
+        match &block.exit {
+            Exit::Goto(target) => {
+                if let Some(jump) = self.jump(i, *target, enclosing) {
+                    let _ = writeln!(out, "{}{};", pad, jump);
+                }
+            },
+            Exit::Branch{ cond, then, otherwise } => {
+                match (self.jump(i, *then, enclosing), self.jump(i, *otherwise, enclosing)) {
+                    (Some(then), None) => { let _ = writeln!(out, "{}if ({}) {};", pad, cond, then); },
+                    (None, Some(otherwise)) => { let _ = writeln!(out, "{}if (!{}) {};", pad, Operand(cond), otherwise); },
+                    (Some(then), Some(otherwise)) => { let _ = writeln!(out, "{}if ({}) {}; else {};", pad, cond, then, otherwise); },
+                    (None, None) => {}
+                }
+            },
+            Exit::Jump(target) => { let _ = writeln!(out, "{}goto *{};", pad, Operand(target)); },
+            Exit::LoadProgram{ array, finger } => { let _ = writeln!(out, "{}load_program({}, {});", pad, ArrayName(array), finger); },
+            Exit::Halt => { let _ = writeln!(out, "{}halt;", pad); },
+            Exit::Fault(reason) => { let _ = writeln!(out, "{}fault(\"{}\");", pad, reason); }
+        }
+
+        if let Some(ret) = block.returns_to {
+            let _ = writeln!(out, "{}// returns to L_{:06x}", pad, ret);
+        }
+    }
+
+    /// How to describe a jump from a block to some target given the loops we're
+    /// in. None is returned if we can simply fall through to the target.
+    fn jump(&self, from: usize, target: usize, enclosing: &[(usize, usize)]) -> Option<String> {
+
+        let target_idx = self.index.get(&target).cloned();
+
+        // We can fall through to the next block if nothing lies between us and it.
+        // Falling off the end of a loop body goes back to the top of it though:
+        let falls_through = target_idx == Some(from + 1) && self.blocks[from].end == target;
+        if let Some(&(header, last)) = enclosing.last() {
+            if from == last {
+                if target_idx == Some(header) { return None; }
+            } else if falls_through {
+                return None;
+            }
+        } else if falls_through {
+            return None;
+        }
+
+        for &(header, last) in enclosing.iter().rev() {
+            let label = self.blocks[header].start;
+            if target_idx == Some(header) {
+                return Some(format!("continue L_{:06x}", label));
+            }
+            if target_idx == Some(last + 1) {
+                return Some(format!("break L_{:06x}", label));
+            }
+        }
+
+        Some(format!("goto L_{:06x}", target))
+    }
+
+}
+
+fn escape_char(c: u32) -> Option<String> {
+    match c {
+        0x0A => Some("\\n".to_string()),
+        0x09 => Some("\\t".to_string()),
+        0x22 => Some("\\\"".to_string()),
+        0x5C => Some("\\\\".to_string()),
+        0x20 ..= 0x7E => Some((c as u8 as char).to_string()),
+        _ => None
+    }
+}
+
+fn flush_text(text: &mut String, pad: &str, out: &mut String) {
+    if !text.is_empty() {
+        let _ = writeln!(out, "{}puts(\"{}\");", pad, text);
+        text.clear();
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+/// Find the start of every block we can reach, and what we know about
+/// registers on entry to each. Whenever a jump into the middle of a block
+/// we've already looked at turns up, that block is looked at again, so that
+/// it ends where the new one begins.
+///
+/// We assume that computed jumps return from calls, so registers arriving
+/// at a return point are whatever the call or any computed jump left them as.
+fn discover(code: &[Platter]) -> (BTreeSet<usize>, BTreeMap<usize, State>) {
+
+    let mut leaders = BTreeSet::new();
+    let mut entries: BTreeMap<usize, State> = BTreeMap::new();
+    // Where each block we've lifted so far ends:
+    let mut ends: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut todo = VecDeque::new();
+    let mut return_points: BTreeMap<usize, State> = BTreeMap::new();
+    let mut jump_state: Option<State> = None;
+    leaders.insert(0);
+    entries.insert(0, [Value::Const(0); 8]);
+    todo.push_back(0);
+
+    while let Some(start) = todo.pop_front() {
+
+        let entry = entries[&start];
+        let block = lift_block(code, start, &entry, &leaders, |_| ALL_REGISTERS);
+        ends.insert(start, block.end);
+
+        let mut succs: Vec<(usize, State)> = block.exit.targets().into_iter()
+            .map(|t| (t, block.exit_state))
+            .collect();
+
+        if let Some(ret) = block.returns_to {
+            let call_state = match return_points.get(&ret) {
+                Some(existing) => join(existing, &block.exit_state),
+                None => block.exit_state
+            };
+            return_points.insert(ret, call_state);
+            let state = jump_state.map(|j| join(&j, &call_state)).unwrap_or(call_state);
+            succs.push((ret, state));
+        }
+
+        if let Exit::Jump(..) = block.exit {
+            let new_state = jump_state.map(|j| join(&j, &block.exit_state)).unwrap_or(block.exit_state);
+            if jump_state != Some(new_state) {
+                jump_state = Some(new_state);
+                for (&ret, call_state) in return_points.iter() {
+                    succs.push((ret, join(call_state, &new_state)));
+                }
+            }
+        }
+
+        for (succ, state) in succs.into_iter().filter(|(s, _)| *s < code.len()) {
+            // A new leader splits whichever block it lands in. Any other block running
+            // over it also runs over that block's start, so has already been queued:
+            if leaders.insert(succ) {
+                if let Some((&split, _)) = ends.range(..succ).next_back().filter(|(_, &end)| end > succ) {
+                    todo.push_back(split);
+                }
+            }
+            let joined = match entries.get(&succ) {
+                Some(existing) => join(existing, &state),
+                None => state
+            };
+            if entries.get(&succ) != Some(&joined) {
+                entries.insert(succ, joined);
+                todo.push_back(succ);
+            }
+        }
+
+    }
+
+    (leaders, entries)
+}
+
+/// Registers live on entry to each block, as bitmasks.
+fn liveness(code: &[Platter], leaders: &BTreeSet<usize>, entries: &BTreeMap<usize, State>) -> BTreeMap<usize, u8> {
+
+    // Gather the registers used before being defined, and defined,
+    // in each block, alongside where control goes next:
+    let mut summaries = vec![];
+    for (&start, entry) in entries.iter() {
+        let block = lift_block(code, start, entry, leaders, |_| ALL_REGISTERS);
+        let (mut uses, mut defs) = (0u8, 0u8);
+        for finger in start..block.end.min(code.len()) {
+            let (u, d) = uses_and_defs(Instruction::decode(code[finger]));
+            uses |= u & !defs;
+            defs |= d;
+        }
+        summaries.push((start, uses, defs, block.exit));
+    }
+
+    let mut live_in: BTreeMap<usize, u8> = summaries.iter().map(|s| (s.0, 0)).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (start, uses, defs, exit) in summaries.iter().rev() {
+            let new = uses | (live_out(exit, &live_in) & !defs);
+            if live_in[start] != new {
+                live_in.insert(*start, new);
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+/// Registers live after a block exits, given those live on entry to each block.
+fn live_out(exit: &Exit, live_in: &BTreeMap<usize, u8>) -> u8 {
+    match exit {
+        Exit::Goto(..) | Exit::Branch{..} => {
+            exit.targets().iter().fold(0, |acc, t| acc | live_in.get(t).cloned().unwrap_or(ALL_REGISTERS))
+        },
+        Exit::Jump(..) | Exit::LoadProgram{..} => ALL_REGISTERS,
+        Exit::Halt | Exit::Fault(..) => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn assemble(instructions: &[Instruction]) -> Vec<Platter> {
+        instructions.iter().map(|i| i.encode()).collect()
+    }
+
+    #[test]
+    fn folds_constants() {
+        let code = assemble(&[
+            Instruction::Orthography{ a: 1, value: 13 },
+            Instruction::Orthography{ a: 2, value: 5 },
+            Instruction::Multiplication{ a: 3, b: 1, c: 2 },
+            Instruction::Output{ c: 3 },
+            Instruction::Halt
+        ]);
+        let out = decompile(&code);
+        assert!(out.contains("puts(\"A\");"), "{}", out);
+        assert!(!out.contains("r3"), "{}", out);
+    }
+
+    #[test]
+    fn recognises_subtraction() {
+        // r1 + ~r2 + 1, the usual way of spelling r1 - r2:
+        let code = assemble(&[
+            Instruction::Input{ c: 1 },
+            Instruction::Input{ c: 2 },
+            Instruction::NotAnd{ a: 3, b: 2, c: 2 },
+            Instruction::Addition{ a: 4, b: 1, c: 3 },
+            Instruction::Orthography{ a: 5, value: 1 },
+            Instruction::Addition{ a: 4, b: 4, c: 5 },
+            Instruction::Output{ c: 4 },
+            Instruction::Halt
+        ]);
+        let out = decompile(&code);
+        assert!(out.contains("putc(r1 - r2);"), "{}", out);
+    }
+
+    #[test]
+    fn finds_loops() {
+        // Jumping back into the middle of the first block splits it in two:
+        let code = assemble(&[
+            Instruction::Orthography{ a: 1, value: 65 },
+            Instruction::Orthography{ a: 2, value: 2 },
+            Instruction::Output{ c: 1 },
+            Instruction::LoadProgram{ b: 0, c: 2 }
+        ]);
+        let out = decompile(&code);
+        assert!(out.starts_with("// 4 platters, 2 blocks, 1 loops"), "{}", out);
+        assert!(out.contains("loop L_000002 {"), "{}", out);
+        assert!(out.contains("puts(\"A\");"), "{}", out);
+
+        let (leaders, entries) = discover(&code);
+        assert_eq!(leaders.into_iter().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(entries[&2][1], Value::Const(65));
+    }
+
+}
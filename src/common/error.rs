@@ -5,6 +5,13 @@ pub fn err<S: Into<String>>(s: S) -> Error {
     Error(s.into())
 }
 
+impl Error {
+    /// What went wrong, without any decoration.
+    pub fn message(&self) -> &str {
+        &self.0
+    }
+}
+
 // quick macro to stringify an error into our Error type:
 macro_rules! err_from {
     ($ty:ty) => {
@@ -16,7 +23,6 @@ macro_rules! err_from {
     }
 }
 
-err_from!(futures::sync::mpsc::SendError<u8>);
 err_from!(std::net::AddrParseError);
 err_from!(std::io::Error);
 err_from!(&str);
@@ -0,0 +1,50 @@
+use bytes::Bytes;
+
+/// Something which happened, besides the program producing output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Halted,
+    Faulted(String),
+    Stopped,
+    Joined(String),
+    Left(String),
+    Paused,
+    Resumed
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Event::Halted => write!(f, "the program has halted"),
+            Event::Faulted(reason) => write!(f, "the program has faulted: {}", reason),
+            Event::Stopped => write!(f, "the program has been stopped"),
+            Event::Joined(name) => write!(f, "{} joined", name),
+            Event::Left(name) => write!(f, "{} left", name),
+            Event::Paused => write!(f, "the program has been paused"),
+            Event::Resumed => write!(f, "the program has been resumed")
+        }
+    }
+}
+
+/// What anything listening to the program is sent: its output,
+/// or news of something which happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Output(Bytes),
+    Event(Event)
+}
+
+impl Message {
+    /// How much output this is.
+    pub fn output_len(&self) -> usize {
+        match self {
+            Message::Output(bytes) => bytes.len(),
+            Message::Event(..) => 0
+        }
+    }
+}
+
+/// Render an event as a line of text to show alongside output.
+pub fn render(event: &Event) -> Vec<u8> {
+    format!("\r\n[{}]\r\n", event).into_bytes()
+}
@@ -0,0 +1,310 @@
+use crate::program::{ Program, StepResult, Inputter };
+use crate::input_fuzz::Ending;
+use crossbeam::channel;
+use regex::bytes::Regex;
+use std::sync::Arc;
+use std::thread;
+use std::cmp::Ordering;
+use std::collections::{ BinaryHeap, HashSet };
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+
+/// What we make of the output a program produced in response to a line of input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Judgement {
+    // This is what we're looking for:
+    Reached,
+    // Keep exploring from here. Scores add up along a path,
+    // and higher scoring paths are explored first:
+    Score(i64),
+    // Not worth exploring any further:
+    Prune
+}
+
+/// Decides how promising a state is, given the output produced in reaching it.
+pub trait Judge: Send + Sync {
+    fn judge(&self, output: &[u8]) -> Judgement;
+}
+
+impl <F> Judge for F where F: Fn(&[u8]) -> Judgement + Send + Sync {
+    fn judge(&self, output: &[u8]) -> Judgement {
+        self(output)
+    }
+}
+
+/// Judge output using regular expressions. The goal is reached once output matches
+/// `goal`, states whose output matches `avoid` are pruned, and each match of `prefer`
+/// adds one to the score of a path.
+pub struct Patterns {
+    pub goal: Regex,
+    pub avoid: Option<Regex>,
+    pub prefer: Option<Regex>
+}
+
+impl Judge for Patterns {
+    fn judge(&self, output: &[u8]) -> Judgement {
+        if self.goal.is_match(output) {
+            Judgement::Reached
+        } else if self.avoid.as_ref().map(|r| r.is_match(output)).unwrap_or(false) {
+            Judgement::Prune
+        } else {
+            Judgement::Score(self.prefer.as_ref().map(|r| r.find_iter(output).count() as i64).unwrap_or(0))
+        }
+    }
+}
+
+/// How far and wide to search.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub threads: usize,
+    pub max_depth: usize,
+    pub max_states: usize,
+    pub max_steps: usize
+}
+
+/// The shortest (or, when scoring, the most promising) sequence of candidates which
+/// reached the goal, and the state the program was left in. The program will not be
+/// waiting on input if it halted or faulted.
+pub struct Found {
+    pub inputs: Vec<usize>,
+    pub output: Vec<u8>,
+    pub program: Program,
+    pub waiting: Option<Inputter>
+}
+
+/// What happened along the way.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub states: usize,
+    pub duplicates: usize,
+    pub pruned: usize,
+    pub halted: usize,
+    pub faulted: usize,
+    pub out_of_steps: usize
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} states explored, {} duplicates, {} pruned", self.states, self.duplicates, self.pruned)?;
+        writeln!(f, "{} halted, {} faulted, {} ran out of steps", self.halted, self.faulted, self.out_of_steps)
+    }
+}
+
+/// A state waiting on input, and how we got there.
+struct Node {
+    program: Program,
+    waiting: Inputter,
+    inputs: Vec<usize>,
+    score: i64,
+    order: usize
+}
+
+/// Nodes are explored highest score first, then shortest path first, then in the
+/// order they were found. With no scoring, this is a breadth first search.
+struct Queued(Arc<Node>);
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Queued) -> Ordering {
+        self.0.score.cmp(&other.0.score)
+            .then_with(|| other.0.inputs.len().cmp(&self.0.inputs.len()))
+            .then_with(|| other.0.order.cmp(&self.0.order))
+    }
+}
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Queued {}
+
+/// Feeding a candidate to a node, and what came of it.
+struct Job {
+    node: Arc<Node>,
+    candidate: usize
+}
+
+struct Expanded {
+    node: Arc<Node>,
+    candidate: usize,
+    ending: Ending,
+    output: Vec<u8>,
+    judgement: Judgement,
+    program: Program,
+    waiting: Option<(Inputter, u64)>
+}
+
+/// Search for a sequence of candidate inputs which takes a program, waiting on input,
+/// to a state that the judge is happy with. Candidates are fed to states on a pool of
+/// worker threads, and states which have been seen before are not explored again.
+pub fn search(start: Program, waiting: Inputter, candidates: Vec<Vec<u8>>, judge: Arc<dyn Judge>, options: &Options) -> (Option<Found>, Summary) {
+
+    let candidates = Arc::new(candidates);
+    let (send_job, recv_job) = channel::unbounded::<Job>();
+    let (send_expanded, recv_expanded) = channel::unbounded::<Expanded>();
+    for _ in 0..options.threads.max(1) {
+        let (recv_job, send_expanded) = (recv_job.clone(), send_expanded.clone());
+        let (candidates, judge, max_steps) = (candidates.clone(), judge.clone(), options.max_steps);
+        thread::spawn(move || {
+            while let Some(job) = recv_job.recv() {
+                send_expanded.send(expand(job, &candidates, &*judge, max_steps));
+            }
+        });
+    }
+
+    let mut summary = Summary { states: 1, ..Summary::default() };
+    let mut seen = HashSet::new();
+    seen.insert(state_hash(&start, waiting));
+
+    let mut queue = BinaryHeap::new();
+    queue.push(Queued(Arc::new(Node { program: start, waiting, inputs: vec![], score: 0, order: 0 })));
+
+    // Expand a batch of the most promising nodes at a time, so that every thread has
+    // something to do, and then handle the results in a fixed order so that the
+    // search is deterministic however the threads are scheduled:
+    while !queue.is_empty() && summary.states < options.max_states {
+
+        let mut jobs = 0;
+        while let Some(Queued(node)) = queue.pop() {
+            if node.inputs.len() >= options.max_depth {
+                continue;
+            }
+            for candidate in 0..candidates.len() {
+                send_job.send(Job { node: node.clone(), candidate });
+                jobs += 1;
+            }
+            if jobs >= options.threads.max(1) {
+                break;
+            }
+        }
+
+        let mut results: Vec<Expanded> = (0..jobs).filter_map(|_| recv_expanded.recv()).collect();
+        results.sort_by_key(|e| (e.node.order, e.candidate));
+
+        let mut found: Option<Found> = None;
+        for expanded in results {
+
+            let mut inputs = expanded.node.inputs.clone();
+            inputs.push(expanded.candidate);
+
+            match expanded.ending {
+                Ending::Halted => summary.halted += 1,
+                Ending::Fault(..) => summary.faulted += 1,
+                Ending::StepLimit => summary.out_of_steps += 1,
+                Ending::InputExhausted => {}
+            }
+
+            let score = match expanded.judgement {
+                Judgement::Reached => {
+                    if found.as_ref().map(|f| inputs.len() < f.inputs.len()).unwrap_or(true) {
+                        found = Some(Found {
+                            inputs,
+                            output: expanded.output,
+                            program: expanded.program,
+                            waiting: expanded.waiting.map(|w| w.0)
+                        });
+                    }
+                    continue;
+                },
+                Judgement::Prune => {
+                    summary.pruned += 1;
+                    continue;
+                },
+                Judgement::Score(score) => score
+            };
+
+            if let Some((waiting, hash)) = expanded.waiting {
+                if !seen.insert(hash) {
+                    summary.duplicates += 1;
+                    continue;
+                }
+                summary.states += 1;
+                queue.push(Queued(Arc::new(Node {
+                    program: expanded.program,
+                    waiting,
+                    inputs,
+                    score: expanded.node.score + score,
+                    order: summary.states
+                })));
+            }
+        }
+
+        if found.is_some() {
+            return (found, summary);
+        }
+    }
+
+    (None, summary)
+}
+
+/// Feed a candidate to a copy of the program in some node, and judge what it outputs.
+fn expand(job: Job, candidates: &[Vec<u8>], judge: &dyn Judge, max_steps: usize) -> Expanded {
+
+    let mut program = job.node.program.fork();
+    let mut input = candidates[job.candidate].iter().cloned();
+    let mut output = vec![];
+    let mut waiting = Some(job.node.waiting);
+
+    let ending = match input.next() {
+        None => Ending::InputExhausted,
+        Some(byte) => {
+            program.provide_input(job.node.waiting, Some(byte));
+            waiting = None;
+            let mut ending = Ending::StepLimit;
+            for _ in 0..max_steps {
+                match program.step() {
+                    Err(e) => { ending = Ending::Fault(format!("{}", e)); break },
+                    Ok(StepResult::Halted) => { ending = Ending::Halted; break },
+                    Ok(StepResult::Output{ ascii }) => output.push(ascii),
+                    Ok(StepResult::InputNeeded{ inputter }) => {
+                        match input.next() {
+                            Some(byte) => program.provide_input(inputter, Some(byte)),
+                            None => {
+                                ending = Ending::InputExhausted;
+                                waiting = Some(inputter);
+                                break
+                            }
+                        }
+                    },
+                    Ok(StepResult::Continue) => {}
+                }
+            }
+            ending
+        }
+    };
+
+    let judgement = judge.judge(&output);
+    let waiting = waiting.map(|w| (w, state_hash(&program, w)));
+    Expanded {
+        node: job.node,
+        candidate: job.candidate,
+        ending,
+        output,
+        judgement,
+        program,
+        waiting
+    }
+}
+
+/// A hash of everything that affects how a program waiting on input will go on to run.
+pub fn state_hash(program: &Program, waiting: Inputter) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    program.instruction_index().hash(&mut hasher);
+    waiting.register().hash(&mut hasher);
+    for reg in program.registers() {
+        reg.to_u32().hash(&mut hasher);
+    }
+    for (id, array) in program.arrays() {
+        id.hash(&mut hasher);
+        array.len().hash(&mut hasher);
+        for platter in array {
+            platter.to_u32().hash(&mut hasher);
+        }
+    }
+    program.abandoned().hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,484 @@
+use crate::input_queue::{ InputQueue, Room };
+use std::sync::{ Arc, Mutex };
+
+/// What a connection is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    // Can send input to the program (when the floor allows it):
+    Controller,
+    // Only sees output:
+    Spectator
+}
+
+impl Default for Role {
+    fn default() -> Role {
+        Role::Controller
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Role, String> {
+        match s {
+            "controller" => Ok(Role::Controller),
+            "spectator" => Ok(Role::Spectator),
+            _ => Err(format!("'{}' is not a role (expected 'controller' or 'spectator')", s))
+        }
+    }
+}
+
+/// How input from several controllers is merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    // Any controller can send input, but only whole lines at a time, so
+    // that lines typed at the same time don't get mixed up:
+    Shared,
+    // Only the controller holding the floor can send input. Typing while nobody
+    // holds it takes the floor, and otherwise it must be asked for:
+    Exclusive
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::Shared
+    }
+}
+
+impl std::str::FromStr for Policy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Policy, String> {
+        match s {
+            "shared" => Ok(Policy::Shared),
+            "exclusive" => Ok(Policy::Exclusive),
+            _ => Err(format!("'{}' is not a floor policy (expected 'shared' or 'exclusive')", s))
+        }
+    }
+}
+
+// Lines longer than this are sent on in pieces when input is shared:
+const MAX_LINE: usize = 4096;
+
+const HELP: &str = "commands: ~control (take the floor), ~release (give it up), ~flush (throw away input the program hasn't read yet), ~status, ~help; start a line with ~~ to send a ~";
+
+struct Inner {
+    policy: Policy,
+    holder: Option<(usize, String)>,
+    next_id: usize,
+    // How many seats are taken:
+    seated: usize,
+    input: InputQueue
+}
+
+/// Once nobody can send input, the program is told that no more is coming.
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.input.close();
+    }
+}
+
+/// Decides whose input reaches the program. Every source of input
+/// (a connection, or stdin) takes a seat at the floor.
+#[derive(Clone)]
+pub struct Floor {
+    inner: Arc<Mutex<Inner>>
+}
+
+impl Floor {
+
+    pub fn new(policy: Policy, input: InputQueue) -> Floor {
+        Floor {
+            inner: Arc::new(Mutex::new(Inner {
+                policy,
+                holder: None,
+                next_id: 0,
+                seated: 0,
+                input
+            }))
+        }
+    }
+
+    /// A seat for a new source of input. If no name is given, one is made up.
+    pub fn seat(&self, role: Role, name: Option<&str>) -> Seat {
+        self.take_seat(role, name, false)
+    }
+
+    /// A seat for the console. Whatever is typed there is passed on as it comes,
+    /// without waiting for whole lines or looking for commands in it.
+    pub fn console(&self) -> Seat {
+        self.take_seat(Role::Controller, Some("console"), true)
+    }
+
+    fn take_seat(&self, role: Role, name: Option<&str>, console: bool) -> Seat {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.next_id += 1;
+            inner.seated += 1;
+            inner.next_id
+        };
+        Seat {
+            floor: self.inner.clone(),
+            id,
+            name: name.map(|n| n.to_string()).unwrap_or_else(|| format!("connection {}", id)),
+            role,
+            line: vec![],
+            command: None,
+            at_line_start: true,
+            refused: false,
+            console,
+            sent: 0
+        }
+    }
+
+    /// How many sources of input currently have a seat.
+    pub fn seated(&self) -> usize {
+        self.inner.lock().unwrap().seated
+    }
+
+    /// Stop taking input, so that the program is told that no more is coming.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().input.close();
+    }
+
+}
+
+/// One source of input's place at the floor. Lines which begin with `~` are
+/// commands about the floor itself, rather than input for the program (except
+/// at the console, which has its own way of giving them).
+pub struct Seat {
+    floor: Arc<Mutex<Inner>>,
+    id: usize,
+    name: String,
+    role: Role,
+    line: Vec<u8>,
+    command: Option<Vec<u8>>,
+    at_line_start: bool,
+    refused: bool,
+    console: bool,
+    // How many bytes have been passed on to the program:
+    sent: usize
+}
+
+impl Seat {
+
+    /// What this seat is called, for telling others about it.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How many bytes of input this seat has passed on to the program.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Resolves once there's room for more input. Wait for this before reading
+    /// more from a source, so that a flood of input is held up there.
+    pub fn room(&self) -> Room {
+        self.floor.lock().unwrap().input.room()
+    }
+
+    /// Handle a byte of input. Any messages for whoever sent it are added to `notices`.
+    pub fn receive(&mut self, byte: u8, notices: &mut Vec<u8>) {
+
+        if let Some(mut command) = self.command.take() {
+            if byte == b'\n' {
+                self.at_line_start = true;
+                self.run_command(&command, notices);
+            } else {
+                command.push(byte);
+                self.command = Some(command);
+            }
+            return;
+        }
+
+        if self.at_line_start && byte == b'~' && !self.console {
+            self.at_line_start = false;
+            self.command = Some(vec![]);
+            return;
+        }
+
+        self.at_line_start = byte == b'\n';
+        self.accept(byte, notices);
+    }
+
+    /// Handle input which shouldn't be looked at for commands (the contents of a file, say).
+    pub fn send(&mut self, byte: u8, notices: &mut Vec<u8>) {
+        self.at_line_start = byte == b'\n';
+        self.accept(byte, notices);
+    }
+
+    fn accept(&mut self, byte: u8, notices: &mut Vec<u8>) {
+
+        let end_of_line = byte == b'\n';
+        let floor = self.floor.clone();
+        let mut inner = floor.lock().unwrap();
+
+        let allowed = match (self.role, inner.policy) {
+            (Role::Spectator, _) => {
+                self.refuse("spectators can't send input", notices);
+                false
+            },
+            (Role::Controller, Policy::Shared) => {
+                self.line.push(byte);
+                if end_of_line || self.console || self.line.len() >= MAX_LINE {
+                    inner.input.push(&self.line);
+                    self.sent += self.line.len();
+                    self.line.clear();
+                }
+                false
+            },
+            (Role::Controller, Policy::Exclusive) => {
+                if inner.holder.is_none() {
+                    inner.holder = Some((self.id, self.name.clone()));
+                    notice(notices, "you have control");
+                }
+                match &inner.holder {
+                    Some((id, _)) if *id == self.id => true,
+                    Some((_, name)) => {
+                        let reason = format!("{} has control of input", name);
+                        self.refuse(&reason, notices);
+                        false
+                    },
+                    None => false
+                }
+            }
+        };
+
+        if allowed {
+            inner.input.push(&[byte]);
+            self.sent += 1;
+        }
+        if end_of_line {
+            self.refused = false;
+        }
+    }
+
+    /// Pass on whatever was left of the last line of input, which
+    /// is otherwise held back until the line is finished.
+    pub fn finish(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        if let Ok(inner) = self.floor.lock() {
+            inner.input.push(&self.line);
+            self.sent += self.line.len();
+            self.line.clear();
+        }
+    }
+
+    /// Refuse input, but only say so once per line.
+    fn refuse(&mut self, reason: &str, notices: &mut Vec<u8>) {
+        if !self.refused {
+            notice(notices, reason);
+            self.refused = true;
+        }
+    }
+
+    fn run_command(&mut self, command: &[u8], notices: &mut Vec<u8>) {
+
+        // A doubled up ~ is sent on as input:
+        if command.starts_with(b"~") {
+            for &byte in command.iter().chain(b"\n") {
+                self.accept(byte, notices);
+            }
+            return;
+        }
+
+        self.command(&String::from_utf8_lossy(command), notices);
+    }
+
+    /// Carry out a command about the floor (without its leading `~`).
+    pub fn command(&mut self, command: &str, notices: &mut Vec<u8>) {
+
+        let mut inner = self.floor.lock().unwrap();
+        match command.trim() {
+            "control" => {
+                if self.role == Role::Spectator {
+                    notice(notices, "spectators can't take control");
+                } else if inner.policy == Policy::Shared {
+                    notice(notices, "input is shared; there's no need to take control");
+                } else {
+                    match inner.holder.clone() {
+                        Some((id, _)) if id == self.id => notice(notices, "you already have control"),
+                        Some((_, name)) => notice(notices, &format!("{} has control; they need to ~release it first", name)),
+                        None => {
+                            inner.holder = Some((self.id, self.name.clone()));
+                            notice(notices, "you have control");
+                        }
+                    }
+                }
+            },
+            "release" => {
+                if inner.holder.as_ref().map(|h| h.0) == Some(self.id) {
+                    inner.holder = None;
+                    notice(notices, "you have released control");
+                } else {
+                    notice(notices, "you don't have control");
+                }
+            },
+            "flush" => {
+                if self.role == Role::Spectator {
+                    notice(notices, "spectators can't flush input");
+                } else {
+                    let dropped = inner.input.clear();
+                    notice(notices, &format!("threw away {} bytes of pending input", dropped));
+                }
+            },
+            "status" => {
+                let status = match (inner.policy, &inner.holder) {
+                    (Policy::Shared, _) => "input is shared between controllers, a line at a time".to_string(),
+                    (Policy::Exclusive, Some((id, _))) if *id == self.id => "you have control".to_string(),
+                    (Policy::Exclusive, Some((_, name))) => format!("{} has control", name),
+                    (Policy::Exclusive, None) => "nobody has control".to_string()
+                };
+                let role = match self.role {
+                    Role::Controller => "controller",
+                    Role::Spectator => "spectator"
+                };
+                let pending = inner.input.pending();
+                notice(notices, &format!("{} (you are {}, a {}); {} bytes of input pending", status, self.name, role, pending));
+            },
+            "help" => notice(notices, HELP),
+            other => notice(notices, &format!("unknown command '~{}'; {}", other, HELP))
+        }
+    }
+
+}
+
+/// Letting go of a seat gives up the floor, and passes on
+/// whatever was left of the last line of input.
+impl Drop for Seat {
+    fn drop(&mut self) {
+        self.finish();
+        if let Ok(mut inner) = self.floor.lock() {
+            inner.seated -= 1;
+            if inner.holder.as_ref().map(|h| h.0) == Some(self.id) {
+                inner.holder = None;
+            }
+        }
+    }
+}
+
+/// Add a message for whoever sent some input to `notices`.
+pub fn notice(notices: &mut Vec<u8>, message: &str) {
+    notices.extend_from_slice(format!("[{}]\r\n", message).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Everything that's reached the program so far:
+    fn read(input: &InputQueue) -> Vec<u8> {
+        (0..input.pending()).filter_map(|_| input.pop()).collect()
+    }
+
+    // Type some input at a seat, returning any notices given back:
+    fn type_in(seat: &mut Seat, bytes: &[u8]) -> String {
+        let mut notices = vec![];
+        for &byte in bytes {
+            seat.receive(byte, &mut notices);
+        }
+        String::from_utf8(notices).unwrap()
+    }
+
+    #[test]
+    fn shared_input_is_passed_on_a_line_at_a_time() {
+        let input = InputQueue::new(1024);
+        let floor = Floor::new(Policy::Shared, input.clone());
+        let (mut alice, mut bob) = (floor.seat(Role::Controller, Some("alice")), floor.seat(Role::Controller, None));
+        assert_eq!(floor.seated(), 2);
+
+        type_in(&mut alice, b"hel");
+        type_in(&mut bob, b"bob\n");
+        type_in(&mut alice, b"lo\n");
+        assert_eq!(read(&input), b"bob\nhello\n".to_vec());
+        assert_eq!((alice.sent(), bob.sent()), (6, 4));
+
+        // Whatever's left is passed on when a seat is given up:
+        type_in(&mut alice, b"bye");
+        assert_eq!(read(&input), vec![]);
+        drop(alice);
+        assert_eq!(read(&input), b"bye".to_vec());
+        assert_eq!(floor.seated(), 1);
+    }
+
+    #[test]
+    fn long_shared_lines_are_split() {
+        let input = InputQueue::new(MAX_LINE * 2);
+        let floor = Floor::new(Policy::Shared, input.clone());
+        let mut seat = floor.seat(Role::Controller, None);
+        type_in(&mut seat, &vec![b'a'; MAX_LINE + 1]);
+        assert_eq!(read(&input).len(), MAX_LINE);
+        type_in(&mut seat, b"\n");
+        assert_eq!(read(&input), b"a\n".to_vec());
+    }
+
+    #[test]
+    fn spectators_are_refused_once_per_line() {
+        let input = InputQueue::new(1024);
+        let floor = Floor::new(Policy::Shared, input.clone());
+        let mut seat = floor.seat(Role::Spectator, None);
+        assert_eq!(type_in(&mut seat, b"abc\n"), "[spectators can't send input]\r\n");
+        assert_eq!(type_in(&mut seat, b"de"), "[spectators can't send input]\r\n");
+        assert_eq!(read(&input), vec![]);
+    }
+
+    #[test]
+    fn exclusive_input_goes_to_whoever_has_control() {
+        let input = InputQueue::new(1024);
+        let floor = Floor::new(Policy::Exclusive, input.clone());
+        let (mut alice, mut bob) = (floor.seat(Role::Controller, Some("alice")), floor.seat(Role::Controller, Some("bob")));
+
+        // Typing while nobody has control takes it, and input is passed on as it comes:
+        assert_eq!(type_in(&mut alice, b"a"), "[you have control]\r\n");
+        assert_eq!(read(&input), b"a".to_vec());
+
+        let refused = "[alice has control of input]\r\n";
+        assert_eq!(type_in(&mut bob, b"bb\n"), refused);
+        assert_eq!(type_in(&mut bob, b"~control\n"), "[alice has control; they need to ~release it first]\r\n");
+        assert_eq!(type_in(&mut bob, b"b\nb\n"), refused.repeat(2));
+        assert_eq!(read(&input), vec![]);
+
+        assert_eq!(type_in(&mut alice, b"\n~release\n"), "[you have released control]\r\n");
+        assert_eq!(type_in(&mut bob, b"~control\n"), "[you have control]\r\n");
+        type_in(&mut bob, b"b\n");
+        assert_eq!(read(&input), b"\nb\n".to_vec());
+
+        // Giving up a seat gives up control too:
+        drop(bob);
+        assert_eq!(type_in(&mut alice, b"x"), "[you have control]\r\n");
+    }
+
+    #[test]
+    fn commands_are_not_input() {
+        let input = InputQueue::new(1024);
+        let floor = Floor::new(Policy::Shared, input.clone());
+        let mut seat = floor.seat(Role::Controller, Some("alice"));
+        assert_eq!(type_in(&mut seat, b"~control\n"), "[input is shared; there's no need to take control]\r\n");
+        assert_eq!(type_in(&mut seat, b"~release\n"), "[you don't have control]\r\n");
+        assert!(type_in(&mut seat, b"~nonsense\n").starts_with("[unknown command '~nonsense'"));
+        assert_eq!(type_in(&mut seat, b"~~tilde\n"), "");
+        assert_eq!(read(&input), b"~tilde\n".to_vec());
+    }
+
+    #[test]
+    fn flushes_pending_input() {
+        let input = InputQueue::new(1024);
+        let floor = Floor::new(Policy::Shared, input.clone());
+        let mut seat = floor.seat(Role::Controller, None);
+        type_in(&mut seat, b"one\ntwo\n");
+        assert_eq!(type_in(&mut seat, b"~flush\n"), "[threw away 8 bytes of pending input]\r\n");
+        assert_eq!(input.pending(), 0);
+    }
+
+    #[test]
+    fn console_input_is_passed_straight_on() {
+        let input = InputQueue::new(1024);
+        let floor = Floor::new(Policy::Shared, input.clone());
+        let mut console = floor.console();
+        assert_eq!(type_in(&mut console, b"~c"), "");
+        assert_eq!(read(&input), b"~c".to_vec());
+    }
+
+}
@@ -0,0 +1,475 @@
+use crate::platter::Platter;
+use crate::program::{ Program, StepResult, platters_from_scrolls };
+use crate::instruction::Instruction;
+use crate::rng::Rng;
+
+/// A single fuzz case: the program image to run and the input bytes it will
+/// be given (further input requests see the end of input).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub program: Vec<Platter>,
+    pub input: Vec<u8>
+}
+
+impl Case {
+
+    /// Generate a random case. Programs are biased towards small constants and
+    /// valid operators, so that they tend to get some way before faulting.
+    pub fn generate(rng: &mut Rng) -> Case {
+
+        let len = 1 + rng.below(48);
+        let mut program = Vec::with_capacity(len + 1);
+
+        for _ in 0..len {
+            let a = rng.below(8);
+            let b = rng.below(8);
+            let c = rng.below(8);
+            let instruction = match rng.below(100) {
+                0 ..= 19 => {
+                    let value = if rng.one_in(4) { rng.next_u32() & 0x01FF_FFFF } else { rng.below(len + 4) as u32 };
+                    Instruction::Orthography{ a, value }
+                },
+                20 ..= 29 => Instruction::Addition{ a, b, c },
+                30 ..= 34 => Instruction::Multiplication{ a, b, c },
+                35 ..= 37 => Instruction::Division{ a, b, c },
+                38 ..= 45 => Instruction::NotAnd{ a, b, c },
+                46 ..= 49 => Instruction::ConditionalMove{ a, b, c },
+                50 ..= 57 => Instruction::ArrayIndex{ a, b, c },
+                58 ..= 64 => Instruction::ArrayAmendment{ a, b, c },
+                65 ..= 69 => Instruction::Allocation{ b, c },
+                70 ..= 72 => Instruction::Abandonment{ c },
+                73 ..= 79 => Instruction::Output{ c },
+                80 ..= 84 => Instruction::Input{ c },
+                85 ..= 92 => Instruction::LoadProgram{ b, c },
+                93 => Instruction::Halt,
+                94 => Instruction::Invalid{ op: 14 + rng.below(2) as u32 },
+                _ => Instruction::decode(Platter::from(rng.next_u32()))
+            };
+            program.push(instruction.encode());
+        }
+        if rng.one_in(2) {
+            program.push(Instruction::Halt.encode());
+        }
+
+        let input = (0..rng.below(16)).map(|_| rng.next_u32() as u8).collect();
+        Case { program, input }
+    }
+
+    /// Interpret arbitrary bytes as a case, so that anything a fuzzer hands us
+    /// is usable. The first byte is the number of input bytes that follow, and
+    /// everything after that is the big-endian program image.
+    pub fn from_bytes(data: &[u8]) -> Case {
+        let (input_len, rest) = match data.split_first() {
+            Some((&len, rest)) => (len as usize, rest),
+            None => (0, data)
+        };
+        let input_len = input_len.min(rest.len());
+        let (input, image) = rest.split_at(input_len);
+        Case {
+            program: platters_from_scrolls(image),
+            input: input.to_vec()
+        }
+    }
+
+    /// The inverse of `Case::from_bytes`. At most 255 bytes of input are kept.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let input = &self.input[..self.input.len().min(255)];
+        let mut out = Vec::with_capacity(1 + input.len() + self.program.len() * 4);
+        out.push(input.len() as u8);
+        out.extend_from_slice(input);
+        for platter in &self.program {
+            let val = platter.to_u32();
+            out.extend_from_slice(&[(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]);
+        }
+        out
+    }
+
+}
+
+/// Where `Program` and the reference interpreter first disagreed.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub step: usize,
+    pub finger: u32,
+    pub instruction: Option<Instruction>,
+    pub what: String,
+    pub output: Vec<u8>
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "divergence at step {} (finger {:#x}", self.step, self.finger)?;
+        if let Some(instruction) = self.instruction {
+            write!(f, ": {}", instruction)?;
+        }
+        write!(f, "): {}\noutput so far: {:?}", self.what, String::from_utf8_lossy(&self.output))
+    }
+}
+
+/// Run a case in `Program` and the reference interpreter in lockstep for up to `max_steps`
+/// steps, comparing registers, output and faults after every step, and any array that a
+/// step touches. Every array is compared once the run is over.
+///
+/// The spec leaves the machine free to do anything once it has failed, so as soon as
+/// the reference faults we stop comparing. `Program` faulting on its own is a divergence.
+pub fn check(case: &Case, max_steps: usize) -> Result<(), Divergence> {
+
+    let mut program = Program::new();
+    program.load_platters(case.program.clone());
+    let mut reference = Reference::new(case.program.iter().map(|p| p.to_u32()).collect());
+
+    let mut input = case.input.iter().cloned();
+    let mut output = vec![];
+
+    for step in 0..max_steps {
+
+        let finger = reference.finger;
+        let instruction = reference.array(0).ok()
+            .and_then(|code| code.get(finger as usize))
+            .map(|&op| Instruction::decode(Platter::from(op)));
+        let diverged = |what: String, output: &[u8]| Divergence {
+            step,
+            finger,
+            instruction,
+            what,
+            output: output.to_vec()
+        };
+
+        // Which array (if any) this step will change:
+        let regs = reference.registers;
+        let touched = match instruction {
+            Some(Instruction::ArrayAmendment{ a, .. }) => Some(regs[a]),
+            Some(Instruction::Abandonment{ c }) => Some(regs[c]),
+            Some(Instruction::LoadProgram{ .. }) => Some(0),
+            _ => None
+        };
+
+        let expected = match reference.step() {
+            Ok(res) => res,
+            Err(_) => return Ok(())
+        };
+        let actual = match program.step() {
+            Ok(res) => res,
+            Err(e) => return Err(diverged(format!("Program faulted ({}) but the reference did not", e), &output))
+        };
+
+        match (expected, actual) {
+            (RefStep::Halted, StepResult::Halted) => {
+                return compare_arrays(&program, &reference).map_or(Ok(()), |what| Err(diverged(what, &output)));
+            },
+            (RefStep::Continue, StepResult::Continue) => {},
+            (RefStep::Output(expected), StepResult::Output{ ascii }) => {
+                if expected != ascii {
+                    return Err(diverged(format!("Program output {:#x} but the reference output {:#x}", ascii, expected), &output));
+                }
+                output.push(ascii);
+            },
+            (RefStep::Input(register), StepResult::InputNeeded{ inputter }) => {
+                let byte = input.next();
+                reference.provide_input(register, byte);
+                program.provide_input(inputter, byte);
+            },
+            (expected, actual) => {
+                return Err(diverged(format!("Program stepped with {} but the reference with {:?}", describe(&actual), expected), &output));
+            }
+        }
+
+        let touched = match instruction {
+            Some(Instruction::Allocation{ b, .. }) => Some(reference.registers[b]),
+            _ => touched
+        };
+        let difference = compare_registers(&program, &reference)
+            .or_else(|| touched.and_then(|id| compare_array(&program, &reference, id as usize)));
+        if let Some(what) = difference {
+            return Err(diverged(what, &output));
+        }
+
+    }
+
+    match compare_arrays(&program, &reference) {
+        Some(what) => Err(Divergence { step: max_steps, finger: reference.finger, instruction: None, what, output }),
+        None => Ok(())
+    }
+}
+
+fn describe(res: &StepResult) -> String {
+    match res {
+        StepResult::Halted => "Halted".to_string(),
+        StepResult::Output{ ascii } => format!("Output({:#x})", ascii),
+        StepResult::InputNeeded{ .. } => "Input".to_string(),
+        StepResult::Continue => "Continue".to_string()
+    }
+}
+
+fn compare_registers(program: &Program, reference: &Reference) -> Option<String> {
+
+    if program.instruction_index() != reference.finger as usize {
+        return Some(format!("finger is {:#x} but the reference has {:#x}", program.instruction_index(), reference.finger));
+    }
+
+    for (r, (actual, &expected)) in program.registers().iter().zip(reference.registers.iter()).enumerate() {
+        if actual.to_u32() != expected {
+            return Some(format!("r{} is {:#x} but the reference has {:#x}", r, actual.to_u32(), expected));
+        }
+    }
+
+    None
+}
+
+fn compare_array(program: &Program, reference: &Reference, id: usize) -> Option<String> {
+    let expected = reference.arrays.get(id).and_then(|a| a.as_ref());
+    match (program.array(id), expected) {
+        (None, None) => None,
+        (Some(_), None) => Some(format!("array {} is active but not in the reference", id)),
+        (None, Some(_)) => Some(format!("array {} is active in the reference but not in Program", id)),
+        (Some(actual), Some(expected)) => {
+            if actual.len() != expected.len() {
+                return Some(format!("array {} has {} platters but the reference has {}", id, actual.len(), expected.len()));
+            }
+            actual.iter().zip(expected.iter()).position(|(a, &e)| a.to_u32() != e).map(|offset| {
+                format!("array {} offset {} is {:#x} but the reference has {:#x}", id, offset, actual[offset].to_u32(), expected[offset])
+            })
+        }
+    }
+}
+
+fn compare_arrays(program: &Program, reference: &Reference) -> Option<String> {
+    let ids = program.arrays().map(|(id, _)| id)
+        .chain(reference.arrays.iter().enumerate().filter(|(_, a)| a.is_some()).map(|(id, _)| id));
+    for id in ids {
+        if let Some(what) = compare_array(program, reference, id) {
+            return Some(what);
+        }
+    }
+    None
+}
+
+/// Larger allocations are treated as failures by the reference interpreter,
+/// so that a stray multiplication doesn't have us allocating gigabytes.
+const MAX_ALLOCATION: u32 = 1 << 12;
+
+/// What happened when the reference interpreter took a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefStep {
+    Continue,
+    Halted,
+    Output(u8),
+    Input(usize)
+}
+
+/// A deliberately simple UM, written straight from the spec with no regard for speed,
+/// and sharing no code with `Program`. Every condition the spec describes as a failure
+/// is reported as one, as are very large allocations. Arrays are handed out in the
+/// same order that `Program` uses, since identifiers are visible in registers.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub registers: [u32; 8],
+    pub arrays: Vec<Option<Vec<u32>>>,
+    pub finger: u32,
+    abandoned: Vec<u32>
+}
+
+impl Reference {
+
+    pub fn new(program: Vec<u32>) -> Reference {
+        Reference {
+            registers: [0; 8],
+            arrays: vec![Some(program)],
+            finger: 0,
+            abandoned: vec![]
+        }
+    }
+
+    fn array(&self, id: u32) -> Result<&Vec<u32>, String> {
+        match self.arrays.get(id as usize) {
+            Some(Some(array)) => Ok(array),
+            _ => Err(format!("array {} is not active", id))
+        }
+    }
+
+    fn array_mut(&mut self, id: u32) -> Result<&mut Vec<u32>, String> {
+        match self.arrays.get_mut(id as usize) {
+            Some(Some(array)) => Ok(array),
+            _ => Err(format!("array {} is not active", id))
+        }
+    }
+
+    pub fn provide_input(&mut self, register: usize, byte: Option<u8>) {
+        self.registers[register] = match byte {
+            Some(byte) => byte as u32,
+            None => 0xFFFF_FFFF
+        };
+    }
+
+    pub fn step(&mut self) -> Result<RefStep, String> {
+
+        let finger = self.finger;
+        let op = match self.array(0)?.get(finger as usize) {
+            Some(&op) => op,
+            None => return Err(format!("finger {:#x} is outside of the program", finger))
+        };
+        self.finger = finger.wrapping_add(1);
+
+        let a = ((op >> 6) & 7) as usize;
+        let b = ((op >> 3) & 7) as usize;
+        let c = (op & 7) as usize;
+        let reg = self.registers;
+
+        match op >> 28 {
+            0 => {
+                if reg[c] != 0 {
+                    self.registers[a] = reg[b];
+                }
+            },
+            1 => {
+                let array = self.array(reg[b])?;
+                match array.get(reg[c] as usize) {
+                    Some(&val) => self.registers[a] = val,
+                    None => return Err(format!("index {} is outside of array {}", reg[c], reg[b]))
+                }
+            },
+            2 => {
+                let array = self.array_mut(reg[a])?;
+                match array.get_mut(reg[b] as usize) {
+                    Some(val) => *val = reg[c],
+                    None => return Err(format!("index {} is outside of array {}", reg[b], reg[a]))
+                }
+            },
+            3 => {
+                self.registers[a] = reg[b].wrapping_add(reg[c]);
+            },
+            4 => {
+                self.registers[a] = reg[b].wrapping_mul(reg[c]);
+            },
+            5 => {
+                if reg[c] == 0 {
+                    return Err("division by zero".to_string());
+                }
+                self.registers[a] = reg[b] / reg[c];
+            },
+            6 => {
+                self.registers[a] = !(reg[b] & reg[c]);
+            },
+            7 => {
+                return Ok(RefStep::Halted);
+            },
+            8 => {
+                if reg[c] > MAX_ALLOCATION {
+                    return Err(format!("allocation of {} platters is too large to fuzz", reg[c]));
+                }
+                let array = Some(vec![0; reg[c] as usize]);
+                let id = match self.abandoned.pop() {
+                    Some(id) => {
+                        self.arrays[id as usize] = array;
+                        id
+                    },
+                    None => {
+                        self.arrays.push(array);
+                        (self.arrays.len() - 1) as u32
+                    }
+                };
+                self.registers[b] = id;
+            },
+            9 => {
+                if reg[c] == 0 {
+                    return Err("abandoned array 0".to_string());
+                }
+                self.array(reg[c])?;
+                self.arrays[reg[c] as usize] = None;
+                self.abandoned.push(reg[c]);
+            },
+            10 => {
+                if reg[c] > 255 {
+                    return Err(format!("output {:#x} is not a byte", reg[c]));
+                }
+                return Ok(RefStep::Output(reg[c] as u8));
+            },
+            11 => {
+                return Ok(RefStep::Input(c));
+            },
+            12 => {
+                let program = self.array(reg[b])?.clone();
+                self.arrays[0] = Some(program);
+                self.finger = reg[c];
+            },
+            13 => {
+                self.registers[((op >> 25) & 7) as usize] = op & 0x01FF_FFFF;
+            },
+            op => {
+                return Err(format!("invalid operator {}", op));
+            }
+        }
+
+        Ok(RefStep::Continue)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn case(instructions: &[Instruction], input: &[u8]) -> Case {
+        Case {
+            program: instructions.iter().map(|i| i.encode()).collect(),
+            input: input.to_vec()
+        }
+    }
+
+    #[test]
+    fn cases_round_trip() {
+        let original = case(&[Instruction::Input{ c: 1 }, Instruction::Output{ c: 1 }, Instruction::Halt], b"hi");
+        assert_eq!(Case::from_bytes(&original.to_bytes()), original);
+    }
+
+    #[test]
+    fn any_bytes_are_a_case() {
+        assert_eq!(Case::from_bytes(&[]), case(&[], b""));
+        // More input is asked for than there is, and a trailing partial platter is dropped:
+        assert_eq!(Case::from_bytes(&[9, 1, 2]), case(&[], &[1, 2]));
+        assert_eq!(Case::from_bytes(&[0, 0x70, 0, 0, 0, 0xFF]), case(&[Instruction::Halt], b""));
+    }
+
+    #[test]
+    fn agrees_with_the_reference() {
+        let echo = case(&[
+            Instruction::Input{ c: 1 },
+            Instruction::Output{ c: 1 },
+            Instruction::Orthography{ a: 2, value: 2 },
+            Instruction::Allocation{ b: 3, c: 2 },
+            Instruction::ArrayAmendment{ a: 3, b: 0, c: 1 },
+            Instruction::Abandonment{ c: 3 },
+            Instruction::Halt
+        ], b"x");
+        assert!(check(&echo, 100).is_ok());
+
+        let mut rng = Rng::new(1);
+        for _ in 0..1000 {
+            let case = Case::generate(&mut rng);
+            if let Err(divergence) = check(&case, 1000) {
+                panic!("{}", divergence);
+            }
+        }
+    }
+
+    #[test]
+    fn reference_faults_where_the_spec_says() {
+        let fault = |instructions: &[Instruction]| {
+            let mut reference = Reference::new(instructions.iter().map(|i| i.encode().to_u32()).collect());
+            loop {
+                match reference.step() {
+                    Ok(RefStep::Halted) => return false,
+                    Ok(_) => {},
+                    Err(_) => return true
+                }
+            }
+        };
+        assert!(fault(&[Instruction::Abandonment{ c: 0 }]));
+        assert!(fault(&[Instruction::Division{ a: 0, b: 0, c: 0 }]));
+        assert!(fault(&[Instruction::Orthography{ a: 1, value: 256 }, Instruction::Output{ c: 1 }]));
+        assert!(fault(&[Instruction::Orthography{ a: 1, value: 9 }, Instruction::ArrayIndex{ a: 0, b: 0, c: 1 }, Instruction::Halt]));
+        assert!(!fault(&[Instruction::Halt]));
+    }
+
+}
@@ -0,0 +1,337 @@
+use crate::program::{ Program, StepResult, Inputter };
+use crate::rng::Rng;
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+
+/// Which fingers of which code arrays have been executed. Code arrays are
+/// identified by the array that they were loaded from.
+#[derive(Default)]
+pub struct Coverage {
+    maps: Vec<(usize, Vec<u64>)>,
+    current: usize,
+    total: usize
+}
+
+impl Coverage {
+
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Record that a finger was executed, handing back true if it never had been before.
+    pub fn record(&mut self, source: usize, finger: usize) -> bool {
+
+        // The code array rarely changes, so check the one we used last time first:
+        if self.maps.get(self.current).map(|m| m.0) != Some(source) {
+            self.current = match self.maps.iter().position(|m| m.0 == source) {
+                Some(idx) => idx,
+                None => {
+                    self.maps.push((source, vec![]));
+                    self.maps.len() - 1
+                }
+            };
+        }
+
+        let bits = &mut self.maps[self.current].1;
+        let (word, bit) = (finger / 64, 1u64 << (finger % 64));
+        if word >= bits.len() {
+            bits.resize(word + 1, 0);
+        }
+        if bits[word] & bit == 0 {
+            bits[word] |= bit;
+            self.total += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The number of distinct fingers executed.
+    pub fn len(&self) -> usize {
+        self.total
+    }
+
+}
+
+/// Why a run came to an end.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ending {
+    Halted,
+    Fault(String),
+    InputExhausted,
+    StepLimit
+}
+
+/// The outcome of running a program with some input.
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub ending: Ending,
+    pub output: Vec<u8>,
+    pub steps: usize,
+    pub new_coverage: usize
+}
+
+/// Run a program, which is waiting on the given inputter, with some input. The run
+/// ends once the program asks for more input than we have. Coverage is recorded as
+/// we go.
+pub fn run(program: &mut Program, inputter: Inputter, input: &[u8], max_steps: usize, coverage: &mut Coverage) -> Run {
+
+    let mut input = input.iter().cloned();
+    let mut output = vec![];
+    let mut new_coverage = 0;
+
+    let ending = match input.next() {
+        Some(byte) => {
+            program.provide_input(inputter, Some(byte));
+            run_until_input(program, &mut input, &mut output, max_steps, |source, finger| {
+                if coverage.record(source, finger) {
+                    new_coverage += 1;
+                }
+            })
+        },
+        None => (Ending::InputExhausted, 0)
+    };
+
+    Run {
+        ending: ending.0,
+        output,
+        steps: ending.1,
+        new_coverage
+    }
+}
+
+/// Step a program, feeding it bytes from some input, until it needs input that we don't
+/// have or stops for some other reason. If the input runs out, the program is left waiting
+/// on the returned inputter.
+fn run_until_input<I, F>(program: &mut Program, input: &mut I, output: &mut Vec<u8>, max_steps: usize, mut on_step: F) -> (Ending, usize)
+where I: Iterator<Item=u8>, F: FnMut(usize, usize) {
+    for steps in 0..max_steps {
+        on_step(program.code_source(), program.instruction_index());
+        match program.step() {
+            Err(e) => return (Ending::Fault(format!("{}", e)), steps),
+            Ok(StepResult::Halted) => return (Ending::Halted, steps),
+            Ok(StepResult::Output{ ascii }) => output.push(ascii),
+            Ok(StepResult::InputNeeded{ inputter }) => {
+                match input.next() {
+                    Some(byte) => program.provide_input(inputter, Some(byte)),
+                    None => return (Ending::InputExhausted, steps)
+                }
+            },
+            Ok(StepResult::Continue) => {}
+        }
+    }
+    (Ending::StepLimit, max_steps)
+}
+
+/// Feed a prelude of input to a freshly loaded program, and run it until it wants more.
+/// The program and the inputter it's waiting on are handed back, to be used as the
+/// starting point for every fuzzing run.
+pub fn prepare(mut program: Program, prelude: &[u8], max_steps: usize) -> Result<(Program, Inputter, Vec<u8>), String> {
+    let mut input = prelude.iter().cloned();
+    let mut output = vec![];
+    for _ in 0..max_steps {
+        match program.step() {
+            Err(e) => return Err(format!("program faulted during the prelude: {}", e)),
+            Ok(StepResult::Halted) => return Err("program halted during the prelude".to_string()),
+            Ok(StepResult::Output{ ascii }) => output.push(ascii),
+            Ok(StepResult::InputNeeded{ inputter }) => {
+                match input.next() {
+                    Some(byte) => program.provide_input(inputter, Some(byte)),
+                    None => return Ok((program, inputter, output))
+                }
+            },
+            Ok(StepResult::Continue) => {}
+        }
+    }
+    Err("program did not ask for input within the step limit".to_string())
+}
+
+/// Something interesting that a fuzzing run turned up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    NewCoverage(usize),
+    Fault(String),
+    NewOutput(Vec<u8>)
+}
+
+/// Coverage guided fuzzing of the input given to a UM program. Inputs which reach
+/// new code are kept in the corpus and mutated further, and words that the program
+/// outputs are collected to be tried as input.
+pub struct Fuzzer {
+    base: Program,
+    inputter: Inputter,
+    max_steps: usize,
+    corpus: Vec<Vec<u8>>,
+    dictionary: Vec<Vec<u8>>,
+    known_words: HashSet<Vec<u8>>,
+    seen_lines: HashSet<u64>,
+    coverage: Coverage,
+    rng: Rng
+}
+
+const MAX_INPUT_LEN: usize = 4096;
+
+impl Fuzzer {
+
+    pub fn new(base: Program, inputter: Inputter, max_steps: usize, rng: Rng) -> Fuzzer {
+        Fuzzer {
+            base,
+            inputter,
+            max_steps,
+            corpus: vec![],
+            dictionary: vec![],
+            known_words: HashSet::new(),
+            seen_lines: HashSet::new(),
+            coverage: Coverage::new(),
+            rng
+        }
+    }
+
+    pub fn corpus_len(&self) -> usize {
+        self.corpus.len()
+    }
+
+    pub fn coverage_len(&self) -> usize {
+        self.coverage.len()
+    }
+
+    /// Run an input as-is, and keep it in the corpus regardless of
+    /// what it finds. This is how seed inputs are provided.
+    pub fn add_seed(&mut self, input: Vec<u8>) -> (Run, Vec<Finding>) {
+        let (run, findings) = self.try_input(&input);
+        self.corpus.push(input);
+        (run, findings)
+    }
+
+    /// Mutate an input from the corpus and run it.
+    pub fn fuzz_one(&mut self) -> (Vec<u8>, Run, Vec<Finding>) {
+        let input = self.mutated_input();
+        let (run, findings) = self.try_input(&input);
+        if run.new_coverage > 0 {
+            self.corpus.push(input.clone());
+        }
+        (input, run, findings)
+    }
+
+    fn try_input(&mut self, input: &[u8]) -> (Run, Vec<Finding>) {
+
+        let mut program = self.base.fork();
+        let run = run(&mut program, self.inputter, input, self.max_steps, &mut self.coverage);
+
+        let mut findings = vec![];
+        if run.new_coverage > 0 {
+            findings.push(Finding::NewCoverage(run.new_coverage));
+        }
+        if let Ending::Fault(reason) = &run.ending {
+            findings.push(Finding::Fault(reason.clone()));
+        }
+
+        // Note any lines of output we haven't seen before, and any words
+        // in them to try feeding back in:
+        let mut new_lines = vec![];
+        for line in run.output.split(|&b| b == b'\n') {
+            let mut hasher = DefaultHasher::new();
+            line.hash(&mut hasher);
+            if self.seen_lines.insert(hasher.finish()) {
+                new_lines.extend_from_slice(line);
+                new_lines.push(b'\n');
+                self.learn_words(line);
+            }
+        }
+        if !new_lines.is_empty() {
+            findings.push(Finding::NewOutput(new_lines));
+        }
+
+        (run, findings)
+    }
+
+    fn learn_words(&mut self, line: &[u8]) {
+        for word in line.split(|b| !b.is_ascii_alphanumeric() && *b != b'_' && *b != b'-' && *b != b'.') {
+            if word.len() >= 2 && word.len() <= 24 && self.known_words.insert(word.to_vec()) {
+                self.dictionary.push(word.to_vec());
+            }
+        }
+    }
+
+    fn mutated_input(&mut self) -> Vec<u8> {
+
+        let mut input = if self.corpus.is_empty() {
+            vec![]
+        } else {
+            let idx = self.rng.below(self.corpus.len());
+            self.corpus[idx].clone()
+        };
+
+        for _ in 0..1 + self.rng.below(4) {
+            self.mutate(&mut input);
+        }
+
+        input.truncate(MAX_INPUT_LEN);
+        input
+    }
+
+    fn mutate(&mut self, input: &mut Vec<u8>) {
+        let rng = &mut self.rng;
+        match rng.below(8) {
+            // Change a byte to something printable:
+            0 if !input.is_empty() => {
+                let idx = rng.below(input.len());
+                input[idx] = random_printable(rng);
+            },
+            // Flip a bit:
+            1 if !input.is_empty() => {
+                let idx = rng.below(input.len());
+                input[idx] ^= 1 << rng.below(8);
+            },
+            // Insert a printable byte:
+            2 => {
+                let idx = rng.below(input.len() + 1);
+                input.insert(idx, random_printable(rng));
+            },
+            // Delete a run of bytes:
+            3 if !input.is_empty() => {
+                let start = rng.below(input.len());
+                let end = (start + 1 + rng.below(8)).min(input.len());
+                input.drain(start..end);
+            },
+            // Insert a word that we've seen in output:
+            4 | 5 if !self.dictionary.is_empty() => {
+                let word = self.dictionary[rng.below(self.dictionary.len())].clone();
+                let idx = rng.below(input.len() + 1);
+                let sep = if rng.one_in(2) { b' ' } else { b'\n' };
+                let mut insertion = word;
+                insertion.push(sep);
+                input.splice(idx..idx, insertion);
+            },
+            // Splice with another input from the corpus:
+            6 if !self.corpus.is_empty() => {
+                let other = &self.corpus[rng.below(self.corpus.len())];
+                let keep = rng.below(input.len() + 1);
+                let from = rng.below(other.len() + 1);
+                input.truncate(keep);
+                input.extend_from_slice(&other[from..]);
+            },
+            // Finish off a line:
+            _ => {
+                input.push(b'\n');
+            }
+        }
+    }
+
+}
+
+fn random_printable(rng: &mut Rng) -> u8 {
+    match rng.below(10) {
+        0 => b'\n',
+        1 => b' ',
+        _ => 0x21 + rng.below(0x5E) as u8
+    }
+}
+
+/// A short hash of some bytes, handy for naming files after their contents.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,144 @@
+use futures::{ Async, Future, Poll };
+use futures::task::{ self, Task };
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex, Condvar };
+use std::time::{ Duration, Instant };
+
+struct State {
+    bytes: VecDeque<u8>,
+    limit: usize,
+    // Sources waiting for there to be room again:
+    waiting: Vec<Task>,
+    closed: bool
+}
+
+/// Input waiting for the program to ask for it. Sources of input are expected
+/// to wait for `room()` before reading more, so that the queue (and the memory
+/// it uses) stays bounded, and anything further is held up at its source.
+#[derive(Clone)]
+pub struct InputQueue {
+    shared: Arc<(Mutex<State>, Condvar)>
+}
+
+impl InputQueue {
+
+    pub fn new(limit: usize) -> InputQueue {
+        InputQueue {
+            shared: Arc::new((Mutex::new(State {
+                bytes: VecDeque::new(),
+                limit: std::cmp::max(limit, 1),
+                waiting: vec![],
+                closed: false
+            }), Condvar::new()))
+        }
+    }
+
+    /// Add some input to the queue. This is always accepted, so that lines
+    /// can be added in one go; it's up to sources not to add much more
+    /// once the queue is full.
+    pub fn push(&self, bytes: &[u8]) {
+        let (state, ready) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.bytes.extend(bytes);
+        ready.notify_one();
+    }
+
+    /// Block until there's a byte of input, or None if no more input is coming.
+    pub fn pop(&self) -> Option<u8> {
+        let (state, ready) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        loop {
+            if let Some(byte) = take(&mut state) {
+                return Some(byte);
+            }
+            if state.closed {
+                return None;
+            }
+            state = ready.wait(state).unwrap();
+        }
+    }
+
+    /// Like `pop`, but gives up (returning None) if nothing arrives in time.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<Option<u8>> {
+        let (state, ready) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(byte) = take(&mut state) {
+                return Some(Some(byte));
+            }
+            if state.closed {
+                return Some(None);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = ready.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// How many bytes of input are waiting to be read.
+    pub fn pending(&self) -> usize {
+        self.shared.0.lock().unwrap().bytes.len()
+    }
+
+    /// Throw away any input waiting to be read, returning how much there was.
+    pub fn clear(&self) -> usize {
+        let mut state = self.shared.0.lock().unwrap();
+        let pending = state.bytes.len();
+        state.bytes.clear();
+        for task in state.waiting.drain(..) {
+            task.notify();
+        }
+        pending
+    }
+
+    /// No more input is coming. Anything already queued can still be read.
+    pub fn close(&self) {
+        let (state, ready) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.closed = true;
+        for task in state.waiting.drain(..) {
+            task.notify();
+        }
+        ready.notify_all();
+    }
+
+    /// Resolves once there's room in the queue for more input.
+    pub fn room(&self) -> Room {
+        Room{ shared: self.shared.clone() }
+    }
+
+}
+
+// Take the next byte of input, if there is one, letting sources
+// carry on once there's a good amount of room:
+fn take(state: &mut State) -> Option<u8> {
+    let byte = state.bytes.pop_front()?;
+    if state.bytes.len() <= state.limit / 2 {
+        for task in state.waiting.drain(..) {
+            task.notify();
+        }
+    }
+    Some(byte)
+}
+
+/// Resolves once there's room in an input queue (or it's been closed).
+pub struct Room {
+    shared: Arc<(Mutex<State>, Condvar)>
+}
+
+impl Future for Room {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        let mut state = self.shared.0.lock().unwrap();
+        if state.closed || state.bytes.len() < state.limit {
+            Ok(Async::Ready(()))
+        } else {
+            state.waiting.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
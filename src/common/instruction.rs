@@ -0,0 +1,131 @@
+use crate::platter::Platter;
+
+/// A decoded UM operator. Register operands are indexes into the
+/// eight general purpose registers (0-7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    ConditionalMove{ a: usize, b: usize, c: usize },
+    ArrayIndex{ a: usize, b: usize, c: usize },
+    ArrayAmendment{ a: usize, b: usize, c: usize },
+    Addition{ a: usize, b: usize, c: usize },
+    Multiplication{ a: usize, b: usize, c: usize },
+    Division{ a: usize, b: usize, c: usize },
+    NotAnd{ a: usize, b: usize, c: usize },
+    Halt,
+    Allocation{ b: usize, c: usize },
+    Abandonment{ c: usize },
+    Output{ c: usize },
+    Input{ c: usize },
+    LoadProgram{ b: usize, c: usize },
+    Orthography{ a: usize, value: u32 },
+    Invalid{ op: u32 }
+}
+
+impl Instruction {
+
+    /// Decode a single platter into the instruction it represents. Any
+    /// platter decodes to something; unknown operator numbers are returned
+    /// as `Instruction::Invalid`.
+    pub fn decode(platter: Platter) -> Instruction {
+
+        let op_val = platter.to_u32();
+        let op_num = (op_val >> 28) & 15;
+
+        let a = ((op_val >> 6) & 7) as usize;
+        let b = ((op_val >> 3) & 7) as usize;
+        let c = (op_val & 7) as usize;
+
+        match op_num {
+            0 => Instruction::ConditionalMove{ a, b, c },
+            1 => Instruction::ArrayIndex{ a, b, c },
+            2 => Instruction::ArrayAmendment{ a, b, c },
+            3 => Instruction::Addition{ a, b, c },
+            4 => Instruction::Multiplication{ a, b, c },
+            5 => Instruction::Division{ a, b, c },
+            6 => Instruction::NotAnd{ a, b, c },
+            7 => Instruction::Halt,
+            8 => Instruction::Allocation{ b, c },
+            9 => Instruction::Abandonment{ c },
+            10 => Instruction::Output{ c },
+            11 => Instruction::Input{ c },
+            12 => Instruction::LoadProgram{ b, c },
+            13 => Instruction::Orthography{
+                a: ((op_val >> 25) & 7) as usize,
+                value: op_val & 0b0000_000_1111111111111111111111111
+            },
+            op => Instruction::Invalid{ op }
+        }
+    }
+
+    /// Encode this instruction back into a platter. Decoding the
+    /// result gives back an identical instruction.
+    pub fn encode(&self) -> Platter {
+        let standard = |op: u32, a: usize, b: usize, c: usize| {
+            Platter::from(op << 28 | (a as u32) << 6 | (b as u32) << 3 | c as u32)
+        };
+        match *self {
+            Instruction::ConditionalMove{ a, b, c } => standard(0, a, b, c),
+            Instruction::ArrayIndex{ a, b, c } => standard(1, a, b, c),
+            Instruction::ArrayAmendment{ a, b, c } => standard(2, a, b, c),
+            Instruction::Addition{ a, b, c } => standard(3, a, b, c),
+            Instruction::Multiplication{ a, b, c } => standard(4, a, b, c),
+            Instruction::Division{ a, b, c } => standard(5, a, b, c),
+            Instruction::NotAnd{ a, b, c } => standard(6, a, b, c),
+            Instruction::Halt => standard(7, 0, 0, 0),
+            Instruction::Allocation{ b, c } => standard(8, 0, b, c),
+            Instruction::Abandonment{ c } => standard(9, 0, 0, c),
+            Instruction::Output{ c } => standard(10, 0, 0, c),
+            Instruction::Input{ c } => standard(11, 0, 0, c),
+            Instruction::LoadProgram{ b, c } => standard(12, 0, b, c),
+            Instruction::Orthography{ a, value } => {
+                Platter::from(13 << 28 | (a as u32) << 25 | (value & 0x01FF_FFFF))
+            },
+            Instruction::Invalid{ op } => standard(op & 15, 0, 0, 0)
+        }
+    }
+
+    /// The operator number (0-15) of this instruction.
+    pub fn opcode(&self) -> u32 {
+        match *self {
+            Instruction::ConditionalMove{..} => 0,
+            Instruction::ArrayIndex{..} => 1,
+            Instruction::ArrayAmendment{..} => 2,
+            Instruction::Addition{..} => 3,
+            Instruction::Multiplication{..} => 4,
+            Instruction::Division{..} => 5,
+            Instruction::NotAnd{..} => 6,
+            Instruction::Halt => 7,
+            Instruction::Allocation{..} => 8,
+            Instruction::Abandonment{..} => 9,
+            Instruction::Output{..} => 10,
+            Instruction::Input{..} => 11,
+            Instruction::LoadProgram{..} => 12,
+            Instruction::Orthography{..} => 13,
+            Instruction::Invalid{ op } => op
+        }
+    }
+
+}
+
+/// Display an instruction as a line of assembly, eg `add r1 r2 r3`.
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Instruction::ConditionalMove{ a, b, c } => write!(f, "cmov r{} r{} r{}", a, b, c),
+            Instruction::ArrayIndex{ a, b, c } => write!(f, "index r{} r{} r{}", a, b, c),
+            Instruction::ArrayAmendment{ a, b, c } => write!(f, "amend r{} r{} r{}", a, b, c),
+            Instruction::Addition{ a, b, c } => write!(f, "add r{} r{} r{}", a, b, c),
+            Instruction::Multiplication{ a, b, c } => write!(f, "mul r{} r{} r{}", a, b, c),
+            Instruction::Division{ a, b, c } => write!(f, "div r{} r{} r{}", a, b, c),
+            Instruction::NotAnd{ a, b, c } => write!(f, "nand r{} r{} r{}", a, b, c),
+            Instruction::Halt => write!(f, "halt"),
+            Instruction::Allocation{ b, c } => write!(f, "alloc r{} r{}", b, c),
+            Instruction::Abandonment{ c } => write!(f, "free r{}", c),
+            Instruction::Output{ c } => write!(f, "out r{}", c),
+            Instruction::Input{ c } => write!(f, "in r{}", c),
+            Instruction::LoadProgram{ b, c } => write!(f, "load r{} r{}", b, c),
+            Instruction::Orthography{ a, value } => write!(f, "ortho r{} {:#x}", a, value),
+            Instruction::Invalid{ op } => write!(f, "invalid({})", op)
+        }
+    }
+}
@@ -1,28 +1,130 @@
-use crate::error::{Error};
+use crate::error::{ err, Error };
 use crate::io_extra;
-use crate::broadcaster::Broadcaster;
+use crate::broadcaster::{ Broadcaster, Backpressure, Overflow };
+use crate::telnet;
+use crate::tls::Tls;
+use crate::connection::{ self, Context, Protocol };
+use crate::auth::{ Auth, Peer };
+use crate::web;
+use crate::api::{ self, Api };
+use crate::metrics::{ self, Exporter };
+use crate::control::Controller;
+use crate::console::{ self, Escape };
+use crate::line_discipline::{ Discipline, LineDiscipline };
+use crate::floor::{ self, Floor, Role };
+use crate::input_queue::InputQueue;
+use crate::event::{ self, Event, Message };
 use std::{ thread };
+use std::sync::{ Arc, Mutex, Condvar };
+use std::time::{ Duration, Instant };
 use crossbeam::{ channel };
 use tokio::prelude::*;
-use tokio::net::TcpListener;
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::reactor::Handle;
+use tokio::timer::Delay;
 use futures::sync::mpsc;
+use bytes::Bytes;
+
+// How many bytes of output can be waiting to be written to stdout:
+const STDOUT_QUEUE: usize = 64 * 1024;
+
+// How many bytes of input can be waiting for the program, unless told otherwise:
+const INPUT_BUFFER: usize = 64 * 1024;
+
+// Output is sent on in chunks of up to this size...
+const OUTPUT_CHUNK: usize = 16 * 1024;
+
+// ...or at least this often, if there's any:
+const OUTPUT_INTERVAL: Duration = Duration::from_millis(10);
+
+// How much output can be waiting to be sent on before the program has to wait:
+const OUTBOX_LIMIT: usize = 4 * OUTPUT_CHUNK;
+
+/// Where, besides stdin and stdout, input and output should be available from.
+#[derive(Debug, Clone, Default)]
+pub struct IoOptions {
+    /// Listen for TCP connections on this address:
+    pub address: Option<std::net::SocketAddr>,
+    /// Speak telnet to TCP connections, rather than passing bytes on as-is:
+    pub telnet: Option<telnet::Mode>,
+    /// Terminate TLS on TCP connections:
+    pub tls: Option<Tls>,
+    /// Network connections must send this password before anything else:
+    pub auth: Option<Auth>,
+    /// Serve a web terminal on this address, which talks to us over a WebSocket:
+    pub web: Option<std::net::SocketAddr>,
+    /// Serve a JSON API for inspecting and controlling the program:
+    pub api: Option<Api>,
+    /// Serve metrics in Prometheus' text format:
+    pub metrics: Option<Exporter>,
+    /// Listen for connections on a unix domain socket at this path:
+    pub unix: Option<std::path::PathBuf>,
+    /// The permissions to give the unix domain socket (eg 0o600):
+    pub unix_mode: Option<u32>,
+    /// How to tidy up input from stdin:
+    pub stdin: Discipline,
+    /// How to tidy up input from network connections:
+    pub network: Discipline,
+    /// Whether network connections can send input, or only watch:
+    pub role: Role,
+    /// How input from several controllers is merged:
+    pub floor: floor::Policy,
+    /// How many bytes of recent output to replay to each new connection:
+    pub scrollback: usize,
+    /// What to do with connections which can't keep up with output:
+    pub backpressure: Backpressure,
+    /// How many bytes of input can be waiting for the program before
+    /// we stop reading more:
+    pub input_buffer: Option<usize>,
+    /// Show events (like the program halting, or clients connecting) on stdout:
+    pub show_events: bool,
+    /// Take meta-commands from stdin, to control the program with:
+    pub console: Option<Controller>,
+    /// The character which begins a meta-command at the start of a line (if any):
+    pub escape: Option<u8>,
+    /// Leave stdin and stdout alone, for when there's no terminal to use:
+    pub detached: bool
+}
 
 /// This provides a way of sending to and receiving input to/from the interpreter. If
 /// a socket address is provided, it will also allow an arbitrary number of network connections
 /// to send/receive input. Allows things like `nc localhost 8080 > output` to save output:
 pub struct IoHandler {
-    sender: mpsc::UnboundedSender<u8>,
-    receiver: channel::Receiver<u8>,
+    // Output collects here until it's sent on as a chunk (and we're told when there's room):
+    outbox: Arc<(Mutex<Vec<u8>>, Condvar)>,
+    // This asks for it to be sent on:
+    kick: mpsc::UnboundedSender<Wakeup>,
+    input: InputQueue,
+    // What anything else attaching connections to us needs:
+    context: Context,
     on_closed: channel::Receiver<()>
 }
 
 impl IoHandler {
 
-    pub fn start(addr: Option<std::net::SocketAddr>) -> IoHandler {
+    pub fn start(options: IoOptions) -> Result<IoHandler, Error> {
+
+        // Everything we listen on is bound up front, so that we can say if we can't:
+        let tcp_listener = bind(options.address.as_ref())?;
+        let web_listener = bind(options.web.as_ref())?;
+        let api_listener = bind(options.api.as_ref().map(|api| &api.address))?;
+        let metrics_listener = bind(options.metrics.as_ref().map(|exporter| &exporter.address))?;
+        #[cfg(unix)]
+        let unix_listener = match &options.unix {
+            Some(path) => Some(io_extra::bind_unix(path, options.unix_mode)
+                .map_err(|e| err(format!("couldn't listen on {}: {}", path.display(), e)))?),
+            None => None
+        };
 
         let (finished_input, finished_output) = channel::bounded::<()>(0);
-        let (send_input, recv_input) = channel::unbounded::<u8>();
-        let (send_output, mut recv_output) = mpsc::unbounded::<u8>();
+        let input = InputQueue::new(options.input_buffer.unwrap_or(INPUT_BUFFER));
+        let send_input = input.clone();
+        let console_input = input.clone();
+        let outbox = Arc::new((Mutex::new(Vec::with_capacity(OUTPUT_CHUNK)), Condvar::new()));
+        let (send_kick, recv_kick) = mpsc::unbounded::<Wakeup>();
+        let output = outbox.clone();
+        let (send_context, recv_context) = channel::bounded::<Context>(1);
+        let unix_path = options.unix.clone();
 
         thread::spawn(move || {
 
@@ -30,67 +132,230 @@ impl IoHandler {
             block_on_async(&mut rt, async move {
 
                 // This guy sends off any input he receives to all interested parties:
-                let (mut broadcaster, mut broadcaster_done) = Broadcaster::new();
+                let (mut broadcaster, mut broadcaster_done) = Broadcaster::new(options.scrollback, options.backpressure);
+
+                // And this guy decides whose input makes it to the program:
+                let floor = Floor::new(options.floor, send_input);
+
+                // Network connections of every kind are treated alike, besides how we talk to them:
+                let context = Context {
+                    floor: floor.clone(),
+                    role: options.role,
+                    broadcaster: broadcaster.clone(),
+                    protocol: Protocol::Raw,
+                    discipline: options.network,
+                    auth: options.auth.clone(),
+                    show_events: options.show_events
+                };
+                let _ = send_context.send(context.clone());
 
                 // if a network addy is provided, spin up a TCP listener to connect to
                 // stdin and stdout from the program:
-                if let Some(addr) = addr {
-                    let broadcaster = broadcaster.clone();
-                    let send_input = send_input.clone();
-                    tokio::spawn_async(async move {
-
-                        let mut tcp_connections = TcpListener::bind(&addr)
-                            .expect("listener cant bind to address")
-                            .incoming();
+                if let Some(listener) = tcp_listener {
+                    let protocol = options.telnet.map(Protocol::Telnet).unwrap_or(Protocol::Raw);
+                    let context = Context{ protocol, ..context.clone() };
+                    let tls = options.tls.clone();
+                    listen(listener, move |sock, peer| {
 
-                        while let Some(sock) = await!(tcp_connections.next()) {
+                        // With TLS, the handshake happens first (without holding up
+                        // anybody else connecting), and then it's business as usual:
+                        if let Some(tls) = &tls {
+                            let handshake = tls.acceptor.accept(sock);
+                            let context = context.clone();
+                            tokio::spawn_async(async move {
+                                match await!(handshake) {
+                                    Ok(stream) => {
+                                        let (reader, writer) = stream.split();
+                                        let input = io_extra::stream_bytes(reader);
+                                        connection::serve(input, writer, vec![], peer, context);
+                                    },
+                                    Err(e) => eprintln!("Error during TLS handshake: {:?}", e)
+                                }
+                            });
+                            return;
+                        }
 
-                            let sock = match sock {
-                                Err(e) => {
-                                    eprintln!("Error opening socket: {:?}", e);
-                                    continue;
-                                },
-                                Ok(s) => s
-                            };
+                        let (reader, writer) = sock.split();
+                        let input = io_extra::stream_bytes(reader);
+                        connection::serve(input, writer, vec![], peer, context.clone());
+                    });
+                }
 
-                            let (reader, writer) = sock.split();
-                            let send_input = send_input.clone();
+                // Local connections can come in on a unix domain socket instead (which
+                // is removed again once we're done):
+                #[cfg(unix)]
+                {
+                    if let Some(listener) = unix_listener {
+                        let context = context.clone();
+                        tokio::spawn_async(async move {
 
-                            // listen for input and send to the main thread:
-                            tokio::spawn_async(async move {
-                                let mut input = io_extra::stream_bytes(reader);
-                                while let Some(byte) = await!(input.next()) {
-                                    if let Ok(byte) = byte {
-                                        send_input.send(byte);
+                            let mut unix_connections = listener.incoming();
+                            while let Some(sock) = await!(unix_connections.next()) {
+                                match sock {
+                                    Err(e) => eprintln!("Error opening socket: {:?}", e),
+                                    Ok(sock) => {
+                                        let peer = sock.peer_cred().map(|cred| Peer::Local(cred.uid)).unwrap_or(Peer::Unknown);
+                                        let (reader, writer) = sock.split();
+                                        let input = io_extra::stream_bytes(reader);
+                                        connection::serve(input, writer, vec![], peer, context.clone());
                                     }
                                 }
-                            });
+                            }
+                        });
+                    }
+                }
 
-                            // subscribe to output:
-                            let mut broadcaster = broadcaster.clone();
-                            tokio::spawn_async(async move {
-                                let output = io_extra::sink_bytes(writer).sink_map_err(|_| ());
-                                await!(broadcaster.subscribe(output));
-                            })
+                // Likewise, serve up a web terminal if asked:
+                if let Some(listener) = web_listener {
+                    let context = context.clone();
+                    listen(listener, move |sock, peer| {
+                        let (reader, writer) = sock.split();
+                        web::serve(reader, writer, peer, context.clone());
+                    });
+                }
+
+                // And the API, for tools that want to poke at the program:
+                if let (Some(listener), Some(Api{ controller, .. })) = (api_listener, options.api.clone()) {
+                    let context = context.clone();
+                    listen(listener, move |sock, peer| {
+                        let (reader, writer) = sock.split();
+                        api::serve(reader, writer, peer, context.clone(), controller.clone());
+                    });
+                }
 
+                // And metrics, for anybody keeping an eye on things:
+                if let (Some(listener), Some(Exporter{ metrics, .. })) = (metrics_listener, options.metrics.clone()) {
+                    let context = context.clone();
+                    listen(listener, move |sock, peer| {
+                        let (reader, writer) = sock.split();
+                        metrics::serve(reader, writer, peer, context.clone(), metrics.clone());
+                    });
+                }
+
+                // Unless we're detached, stdin and stdout are hooked up too:
+                if !options.detached {
+
+                    // Stream input from stdin to the program. Anything to echo is sent to
+                    // stdout alone (in between chunks of output), as the terminal is the only
+                    // one that wants it, and anything the floor has to say goes to stderr.
+                    // Meta-commands are picked out first, if there's a program to control and
+                    // a character to begin them with. Unless there's anybody else to share it
+                    // with, input goes straight to the program, and once stdin ends, so does input:
+                    let (send_echo, recv_echo) = mpsc::unbounded::<Bytes>();
+                    let mut discipline = LineDiscipline::new(options.stdin);
+                    let shared = options.address.is_some() || options.unix.is_some() || options.web.is_some() || options.api.is_some();
+                    let mut seat = if shared {
+                        floor.console()
+                    } else {
+                        Floor::new(floor::Policy::Shared, console_input).console()
+                    };
+                    // Either way, stdin is counted among those connected until it ends:
+                    let counted = if shared { None } else { Some(floor.seat(Role::Spectator, Some("stdin"))) };
+                    let mut console = match (options.console.clone(), options.escape) {
+                        (Some(controller), Some(escape)) => Some((controller, escape, Escape::new(escape))),
+                        _ => None
+                    };
+                    tokio::spawn_async(async move {
+                        let (mut input, mut echo, mut notices) = (vec![], vec![], vec![]);
+                        let mut unescaped = vec![];
+                        let mut stdin_future = io_extra::stream_bytes(tokio::io::stdin());
+                        loop {
+                            let _ = await!(seat.room());
+                            let byte = match await!(stdin_future.next()) {
+                                Some(Ok(byte)) => Some(byte),
+                                _ => None
+                            };
+                            match byte {
+                                Some(byte) => discipline.receive(byte, &mut input, &mut echo),
+                                // Whatever was still being typed is passed on before we stop:
+                                None => discipline.finish(&mut input)
+                            }
+                            for byte in input.drain(..) {
+                                match &mut console {
+                                    Some((controller, escape, escaper)) => match escaper.receive(byte, &mut unescaped) {
+                                        Some(Ok(meta)) => notices.extend(await!(console::run(meta, *escape, controller.clone(), &mut seat))),
+                                        Some(Err(reason)) => floor::notice(&mut notices, &reason),
+                                        None => {}
+                                    },
+                                    None => unescaped.push(byte)
+                                }
+                                for byte in unescaped.drain(..) {
+                                    seat.receive(byte, &mut notices);
+                                }
+                            }
+                            if !echo.is_empty() {
+                                let _ = send_echo.unbounded_send(Bytes::from(echo.split_off(0)));
+                            }
+                            if !notices.is_empty() {
+                                let _ = std::io::stderr().write_all(&notices);
+                                notices.clear();
+                            }
+                            if byte.is_none() {
+                                break;
+                            }
                         }
+                        drop(counted);
                     });
+
+                    // Stream output from our broadcaster to stdout, once we've subscribed to it,
+                    // along with any echo. Each is written whole, so that echo never ends up in
+                    // the middle of a chunk of output. Once the broadcaster is done with us, so
+                    // are we, and until then we hold it up from finishing:
+                    let show_events = options.show_events;
+                    let (send_stdout, recv_stdout) = mpsc::channel::<Message>(0);
+                    let output = recv_stdout
+                        .filter_map(move |message| match message {
+                            Message::Output(chunk) => Some(Some(chunk)),
+                            Message::Event(event) => if show_events { Some(Some(Bytes::from(event::render(&event)))) } else { None }
+                        })
+                        .chain(stream::once(Ok(None)));
+                    let writes = output
+                        .select(recv_echo.map(Some))
+                        .take_while(|write| Ok(write.is_some()))
+                        .filter_map(|write| write);
+                    let hold = broadcaster.hold();
+                    tokio::spawn_async(async move {
+                        let stdout = io_extra::sink_chunks(tokio::io::stdout()).sink_map_err(|_| ());
+                        let _ = await!(writes.forward(stdout));
+                        drop(hold);
+                    });
+
+                    // Stdout should see everything, so we wait for it if it falls behind:
+                    let stdout_backpressure = Backpressure{ overflow: Overflow::Block, limit: STDOUT_QUEUE };
+                    await!(broadcaster.subscribe_with(send_stdout.sink_map_err(|_| ()), stdout_backpressure));
                 }
 
-                // Stream input from stdin to the program:
-                tokio::spawn_async(async move {
-                    let mut stdin_future = io_extra::stream_bytes(tokio::io::stdin());
-                    while let Some(Ok(byte)) = await!(stdin_future.next()) {
-                        send_input.send(byte);
+                // Output that's been collected is sent on whenever we're asked to, and
+                // shortly after it starts collecting anyway (so we only wake up when
+                // there's something to do). Events follow the output sent before them.
+                // Once nobody can ask any more, we send what's left:
+                let (send_tick, recv_tick) = mpsc::unbounded::<()>();
+                let kicks = recv_kick.chain(stream::once(Ok(Wakeup::Finished)));
+                let ticks = recv_tick.map(|_| Wakeup::Kick(None));
+                let mut wakeups = kicks.select(ticks);
+                loop {
+                    let wakeup = match await!(wakeups.next()) {
+                        Some(Ok(wakeup)) => wakeup,
+                        _ => Wakeup::Finished
+                    };
+                    if let Wakeup::Soon = wakeup {
+                        let send_tick = send_tick.clone();
+                        let tick = Delay::new(Instant::now() + OUTPUT_INTERVAL)
+                            .then(move |_| send_tick.unbounded_send(()))
+                            .map_err(|_| ());
+                        tokio::spawn(tick);
+                        continue;
+                    }
+                    let chunk = take_output(&output);
+                    if !chunk.is_empty() {
+                        if let Err(e) = await!(broadcaster.send_async(chunk)) {
+                            eprintln!("Error sending output to outputters: {:?}", e);
+                        }
                     }
-                });
-
-                // Stream output from stdout to our broadcaster, once we've subscribed to it:
-                let stdout_sink = io_extra::sink_bytes(tokio::io::stdout()).sink_map_err(|_| ());
-                await!(broadcaster.subscribe(stdout_sink));
-                while let Some(Ok(byte)) = await!(recv_output.next()) {
-                    if let Err(e) = await!(broadcaster.send_async(byte)) {
-                        eprintln!("Error sending byte to outputters: {:?}", e);
+                    match wakeup {
+                        Wakeup::Kick(Some(event)) => await!(broadcaster.event(event)),
+                        Wakeup::Kick(None) | Wakeup::Soon => {},
+                        Wakeup::Finished => break
                     }
                 }
 
@@ -101,40 +366,153 @@ impl IoHandler {
 
             });
 
-            // Tokio has finished; send "done":
+            // Tokio has finished, so nobody else will be connecting; send "done":
+            if let Some(path) = unix_path {
+                let _ = std::fs::remove_file(path);
+            }
             finished_input.send(());
         });
 
         // Return a struct which provides access to these things:
-        IoHandler {
-            sender: send_output,
-            receiver: recv_input,
+        Ok(IoHandler {
+            outbox,
+            kick: send_kick,
+            input,
+            context: recv_context.recv().expect("io thread stopped before it started"),
             on_closed: finished_output
-        }
+        })
 
     }
 
-    /// Send a byte to the output(s):
+    /// Send a byte to the output(s). Output is collected up and sent on in chunks,
+    /// once enough has built up, input is needed, or a short while has passed. If
+    /// too much is waiting to be sent on, this blocks until there's room:
     pub fn send(&self, byte: u8) -> Result<(), Error> {
-        self.sender.unbounded_send(byte)?;
+        let len = {
+            let (outbox, room) = &*self.outbox;
+            let mut outbox = outbox.lock().unwrap();
+            while outbox.len() >= OUTBOX_LIMIT {
+                outbox = room.wait(outbox).unwrap();
+            }
+            outbox.push(byte);
+            outbox.len()
+        };
+        if len == 1 {
+            self.wake(Wakeup::Soon)?;
+        } else if len % OUTPUT_CHUNK == 0 {
+            self.flush()?;
+        }
         Ok(())
     }
 
-    /// Block until we receive a byte from an input source:
-    pub fn recv(&self) -> Result<u8, Error> {
-        let byte = self.receiver.recv()?;
-        Ok(byte)
+    /// Send on any output that's been collected up, without waiting:
+    pub fn flush(&self) -> Result<(), Error> {
+        self.wake(Wakeup::Kick(None))
+    }
+
+    /// Let everything listening to output know that something happened,
+    /// once the output before it has been sent on:
+    pub fn event(&self, event: Event) -> Result<(), Error> {
+        self.wake(Wakeup::Kick(Some(event)))
+    }
+
+    fn wake(&self, wakeup: Wakeup) -> Result<(), Error> {
+        self.kick.unbounded_send(wakeup).map_err(|_| "output has been closed")?;
+        Ok(())
+    }
+
+    /// Block until we receive a byte from an input source, or None once no
+    /// more input is coming. Whatever was output before asking is sent on first:
+    pub fn recv(&self) -> Result<Option<u8>, Error> {
+        if !self.outbox.0.lock().unwrap().is_empty() {
+            self.flush()?;
+        }
+        Ok(self.input.pop())
+    }
+
+    /// Like `recv`, but gives up (returning None) if no input arrives in time:
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Option<u8>>, Error> {
+        if !self.outbox.0.lock().unwrap().is_empty() {
+            self.flush()?;
+        }
+        Ok(self.input.pop_timeout(timeout))
+    }
+
+    /// How many bytes of input are waiting to be read:
+    pub fn pending_input(&self) -> usize {
+        self.input.pending()
+    }
+
+    /// What a connection accepted elsewhere needs in order to be served
+    /// like any other (see `connection::serve`):
+    pub fn context(&self) -> Context {
+        self.context.clone()
     }
 
     /// Shutdown, and block until all output has been flushed:
     pub fn block_until_closed(self) -> () {
-        drop(self.sender);
-        drop(self.receiver);
+        drop(self.kick);
+        drop(self.input);
+        drop(self.context);
         let _ = self.on_closed.recv();
     }
 
 }
 
+// Bind to an address to listen for TCP connections on, if we've been given one:
+fn bind(addr: Option<&std::net::SocketAddr>) -> Result<Option<std::net::TcpListener>, Error> {
+    match addr {
+        Some(addr) => std::net::TcpListener::bind(addr)
+            .map(Some)
+            .map_err(|e| err(format!("couldn't listen on {}: {}", addr, e))),
+        None => Ok(None)
+    }
+}
+
+// Accept connections on a bound listener, handing each one to `serve` along with
+// where it came from. This needs to be called from within the runtime:
+fn listen<F>(listener: std::net::TcpListener, serve: F)
+where F: Fn(TcpStream, Peer) + Send + 'static {
+    tokio::spawn_async(async move {
+
+        let mut connections = match TcpListener::from_std(listener, &Handle::default()) {
+            Ok(listener) => listener.incoming(),
+            Err(e) => return eprintln!("Error listening for connections: {:?}", e)
+        };
+
+        while let Some(sock) = await!(connections.next()) {
+            match sock {
+                Err(e) => eprintln!("Error opening socket: {:?}", e),
+                Ok(sock) => {
+                    let peer = Peer::remote(sock.peer_addr());
+                    serve(sock, peer);
+                }
+            }
+        }
+    });
+}
+
+// What wakes up the task sending output on:
+enum Wakeup {
+    // Send output on now, followed by an event if one is given:
+    Kick(Option<Event>),
+    // Output has started collecting, so send it on shortly:
+    Soon,
+    Finished
+}
+
+// Take whatever output has been collected up, to send on as a chunk,
+// letting anybody waiting for room know that there is some:
+fn take_output(outbox: &(Mutex<Vec<u8>>, Condvar)) -> Bytes {
+    let (outbox, room) = outbox;
+    let mut outbox = outbox.lock().unwrap();
+    if outbox.is_empty() {
+        return Bytes::new();
+    }
+    room.notify_all();
+    Bytes::from(std::mem::replace(&mut *outbox, Vec::with_capacity(OUTPUT_CHUNK)))
+}
+
 // A shim borrowed from how run_async is implemented to allow us to
 // tell a reactor to run only until its async block resolved, not
 // worrying about spawned things:
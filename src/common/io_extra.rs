@@ -1,159 +1,254 @@
 use tokio::prelude::*;
 use futures::try_ready;
-use bytes::BytesMut;
+use bytes::{ Bytes, BytesMut, BufMut };
+
+// How much to try reading in one go:
+const READ_SIZE: usize = 8 * 1024;
+
+// How much written output to hold on to before writing it out,
+// if we aren't asked to (or left idle enough to) before then:
+const WRITE_THRESHOLD: usize = 8 * 1024;
+
+/// Turn an AsyncRead into a Stream which emits chunks of bytes as they are read.
+pub fn stream_chunks<R: AsyncRead>(reader: R) -> impl Stream<Item = Bytes, Error = tokio::io::Error> {
+    ChunkStream::new(reader)
+}
 
 /// Turn an AsyncRead into a Stream which emits 1 byte at a time.
 pub fn stream_bytes<R: AsyncRead>(reader: R) -> impl Stream<Item = u8, Error = tokio::io::Error> {
     ByteStream::new(reader)
 }
 
-struct ByteStream<R>{
+struct ChunkStream<R> {
     r: R,
-    mode: ReadMode
-}
-
-#[derive(PartialEq,Eq)]
-enum ReadMode {
-    Reading,
-    Draining{ buf: BytesMut, pos: usize }
+    // Read into this, and hand out what's read without copying it. Once the
+    // bytes handed out are dropped, the space is reused for the next read:
+    buf: BytesMut
 }
 
-impl <R: AsyncRead> ByteStream<R> {
-    fn new(reader: R) -> ByteStream<R> {
-        ByteStream {
+impl <R: AsyncRead> ChunkStream<R> {
+    fn new(reader: R) -> ChunkStream<R> {
+        ChunkStream {
             r: reader,
-            mode: ReadMode::Reading
+            buf: BytesMut::with_capacity(READ_SIZE)
         }
     }
 }
 
-impl <R: AsyncRead> ByteStream<R> {
+impl <R: AsyncRead> Stream for ChunkStream<R> {
 
-    fn try_drain(&mut self) -> Option<u8> {
-        if let ReadMode::Draining{ buf, pos } = &mut self.mode {
-            if let Some(byte) = buf.get(*pos) {
-                *pos = *pos + 1;
-                return Some(*byte);
-            } else {
-                self.mode = ReadMode::Reading;
-            }
-        }
-        None
-    }
-
-}
-
-impl <R: AsyncRead> Stream for ByteStream<R> {
-
-    type Item = u8;
+    type Item = Bytes;
     type Error = tokio::io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
 
-        // If it's possible to drain bytes from buffer, do so:
-        if let Some(byte) = self.try_drain() {
-            return Ok(Async::Ready(Some(byte)));
+        if self.buf.remaining_mut() < READ_SIZE {
+            self.buf.reserve(READ_SIZE);
         }
 
-        let mut buf = BytesMut::with_capacity(31); // This size avoids allocation on 64bit
-
         // Try to read any available bytes into the buffer:
-        let n = try_ready!(self.r.read_buf(&mut buf));
+        let n = try_ready!(self.r.read_buf(&mut self.buf));
         if n == 0 {
             // no bytes left; assume the stream is done:
             return Ok(Async::Ready(None));
         }
 
-        // having read into the buffer, ready it for draining:
-        debug_assert!(self.mode == ReadMode::Reading);
-        self.mode = ReadMode::Draining{ buf, pos: 0 };
-
-        // Give back what we can straight away:
-        if let Some(byte) = self.try_drain() {
-            Ok(Async::Ready(Some(byte)))
-        } else {
-            Ok(Async::NotReady)
-        }
+        Ok(Async::Ready(Some(self.buf.take().freeze())))
 
     }
 
 }
 
-/// Turn an AsyncWrite into a Sink which takes 1 byte at a time.
-pub fn sink_bytes<W: AsyncWrite>(writer: W) -> impl Sink<SinkItem = u8, SinkError = tokio::io::Error> {
-    ByteSink::new(writer)
+struct ByteStream<R>{
+    chunks: ChunkStream<R>,
+    // The chunk we're handing out bytes from, and how far through it we are:
+    chunk: Bytes,
+    pos: usize
 }
 
-#[derive(Debug)]
-struct ByteSink<W> {
-    w: W,
-    mode: WriteMode
+impl <R: AsyncRead> ByteStream<R> {
+    fn new(reader: R) -> ByteStream<R> {
+        ByteStream {
+            chunks: ChunkStream::new(reader),
+            chunk: Bytes::new(),
+            pos: 0
+        }
+    }
 }
 
-#[derive(PartialEq, Debug)]
-enum WriteMode {
-    Writing,
-    Flushing
-}
+impl <R: AsyncRead> Stream for ByteStream<R> {
 
-impl <W: AsyncWrite> ByteSink<W> {
+    type Item = u8;
+    type Error = tokio::io::Error;
 
-    fn new(writer: W) -> ByteSink<W> {
-        ByteSink {
-            w: writer,
-            mode: WriteMode::Writing
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+
+        // If it's possible to drain bytes from the current chunk, do so,
+        // and otherwise read another (chunks are never empty):
+        if self.pos == self.chunk.len() {
+            match try_ready!(self.chunks.poll()) {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                None => return Ok(Async::Ready(None))
+            }
         }
+
+        let byte = self.chunk[self.pos];
+        self.pos += 1;
+        Ok(Async::Ready(Some(byte)))
+
     }
 
 }
 
+/// Turn an AsyncWrite into a Sink which takes 1 byte at a time. Bytes are buffered
+/// up, and written out once enough have been, or when the sink is flushed (which
+/// `forward` does whenever it runs out of bytes to send).
+pub fn sink_bytes<W: AsyncWrite>(writer: W) -> impl Sink<SinkItem = u8, SinkError = tokio::io::Error> {
+    ByteSink(BufferedWriter::new(writer))
+}
+
+/// Turn an AsyncWrite into a Sink which takes a chunk of bytes at a time, buffering
+/// them in the same way as `sink_bytes`.
+pub fn sink_chunks<W: AsyncWrite, C: AsRef<[u8]>>(writer: W) -> impl Sink<SinkItem = C, SinkError = tokio::io::Error> {
+    ChunkSink(BufferedWriter::new(writer), std::marker::PhantomData)
+}
+
+struct ByteSink<W>(BufferedWriter<W>);
+
 impl <W: AsyncWrite> Sink for ByteSink<W> {
 
     type SinkItem = u8;
     type SinkError = tokio::io::Error;
 
     fn start_send(&mut self, item: u8) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
-
-        // try flushing if needbe. If this succeeds we end up
-        // in writing mode and can write our byte. if it fails
-        // we'll be woken up to try again at some point.
-        //
-        // NOTE: It is important to ensure that if NotReady is
-        // returned, the task will be notified to try again.
-        // Having this first ensures that.
-        self.poll_complete()?;
-
-        // if we are in write mode, try writing:
-        if let WriteMode::Writing = self.mode {
-            if let Async::Ready(_) = self.w.poll_write(&[item;1])? {
-                self.mode = WriteMode::Flushing;
-                return Ok(AsyncSink::Ready);
-            }
+        if self.0.make_room()?.is_ready() {
+            self.0.buf.put_u8(item);
+            Ok(AsyncSink::Ready)
+        } else {
+            Ok(AsyncSink::NotReady(item))
         }
+    }
 
-        // We didn't succeed in writing our byte, or we are
-        // stuck in Flushing mode. Both of which will wake us again:
-        Ok(AsyncSink::NotReady(item))
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.poll_flush()
+    }
 
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.poll_flush()
+    }
+
+}
+
+struct ChunkSink<W, C>(BufferedWriter<W>, std::marker::PhantomData<C>);
+
+impl <W: AsyncWrite, C: AsRef<[u8]>> Sink for ChunkSink<W, C> {
+
+    type SinkItem = C;
+    type SinkError = tokio::io::Error;
+
+    fn start_send(&mut self, item: C) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
+        if self.0.make_room()?.is_ready() {
+            self.0.buf.extend_from_slice(item.as_ref());
+            Ok(AsyncSink::Ready)
+        } else {
+            Ok(AsyncSink::NotReady(item))
+        }
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.poll_flush()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.poll_flush()
+    }
+
+}
 
-        // if we are in flushing mode, try flushing. If the flush
-        // works, put us back in write mode. If not, return
-        // NotReady to prompt another flush attempt.
-        if let WriteMode::Flushing = self.mode {
-            if let Async::NotReady = self.w.poll_flush()? {
-                return Ok(Async::NotReady);
-            } else {
-                self.mode = WriteMode::Writing;
+/// Holds on to bytes until there are enough of them to be worth writing out.
+struct BufferedWriter<W> {
+    w: W,
+    buf: BytesMut,
+    // Written, but not yet flushed:
+    dirty: bool
+}
+
+impl <W: AsyncWrite> BufferedWriter<W> {
+
+    fn new(writer: W) -> BufferedWriter<W> {
+        BufferedWriter {
+            w: writer,
+            buf: BytesMut::with_capacity(WRITE_THRESHOLD),
+            dirty: false
+        }
+    }
+
+    /// Ready once there's room to buffer more. If there's a lot buffered up,
+    /// we write it out first (returning NotReady, and so arranging to be woken
+    /// up to try again, if we can't write it all yet).
+    fn make_room(&mut self) -> Poll<(), tokio::io::Error> {
+        if self.buf.len() >= WRITE_THRESHOLD {
+            try_ready!(self.poll_write_all());
+        }
+        if self.buf.remaining_mut() < 1 {
+            self.buf.reserve(WRITE_THRESHOLD);
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Write out everything that's buffered.
+    fn poll_write_all(&mut self) -> Poll<(), tokio::io::Error> {
+        while !self.buf.is_empty() {
+            let n = try_ready!(self.w.poll_write(&self.buf));
+            if n == 0 {
+                return Err(tokio::io::ErrorKind::WriteZero.into());
             }
+            self.buf.advance(n);
+            self.dirty = true;
         }
+        Ok(Async::Ready(()))
+    }
 
-        // if our flush worked or we're in write mode,
-        // we're happy to receive more:
+    /// Write out everything that's buffered, and then flush it.
+    fn poll_flush(&mut self) -> Poll<(), tokio::io::Error> {
+        try_ready!(self.poll_write_all());
+        if self.dirty {
+            try_ready!(self.w.poll_flush());
+            self.dirty = false;
+        }
         Ok(Async::Ready(()))
+    }
 
+}
+
+/// Listen on a unix domain socket at `path`, clearing away any socket left over there
+/// from a previous run. The socket is bound inside a directory that only we can get
+/// into, and only moved into place once it has the permissions asked for (if any), so
+/// that nobody gets a chance to connect before then.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path, mode: Option<u32>) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{ DirBuilderExt, FileTypeExt, PermissionsExt };
+
+    if std::fs::metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+        std::fs::remove_file(path)?;
     }
 
-}
\ No newline at end of file
+    let name = path.file_name().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "socket path has no file name"))?;
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let temp_path = dir.join("sock");
+    let listener = tokio::net::UnixListener::bind(&temp_path).and_then(|listener| {
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(mode))?;
+        }
+        std::fs::rename(&temp_path, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_dir(&dir);
+    listener
+}
@@ -4,7 +4,32 @@
 
 pub mod platter;
 pub mod program;
+pub mod instruction;
+pub mod decompiler;
+pub mod rng;
+pub mod fuzz;
+pub mod input_fuzz;
+pub mod symbolic;
+pub mod snapshot;
+pub mod explore;
 pub mod error;
 pub mod io;
 pub mod io_extra;
+pub mod telnet;
+pub mod line_discipline;
+pub mod floor;
+pub mod input_queue;
+pub mod connection;
+pub mod tls;
+pub mod auth;
+pub mod daemon;
+pub mod control;
+pub mod api;
+pub mod metrics;
+pub mod mi;
+pub mod console;
+pub mod dap;
+pub mod websocket;
+pub mod web;
+pub mod event;
 pub mod broadcaster;
\ No newline at end of file
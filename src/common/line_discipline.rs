@@ -0,0 +1,193 @@
+/// How input from some source should be tidied up before the program sees it.
+/// With nothing turned on, bytes are passed through untouched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Discipline {
+    /// Turn CR LF, and lone CRs, into the LF that the program expects:
+    pub crlf: bool,
+    /// Hold on to input until a whole line has been typed, allowing
+    /// it to be edited with backspace (and erased with ctrl-U):
+    pub edit: bool,
+    /// Echo input back to where it came from:
+    pub echo: bool
+}
+
+impl Discipline {
+    pub fn raw() -> Discipline {
+        Discipline::default()
+    }
+}
+
+/// Parse either "raw", or a comma separated list of "crlf", "edit" and "echo".
+impl std::str::FromStr for Discipline {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Discipline, String> {
+        let mut discipline = Discipline::raw();
+        for option in s.split(',').map(|o| o.trim()) {
+            match option {
+                "raw" => {},
+                "crlf" => discipline.crlf = true,
+                "edit" => discipline.edit = true,
+                "echo" => discipline.echo = true,
+                _ => return Err(format!("'{}' is not a line discipline option (expected raw, crlf, edit or echo)", option))
+            }
+        }
+        Ok(discipline)
+    }
+}
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const KILL_LINE: u8 = 0x15;
+
+/// Applies a discipline to the input from one source.
+#[derive(Debug, Clone)]
+pub struct LineDiscipline {
+    discipline: Discipline,
+    line: Vec<u8>,
+    last_was_cr: bool
+}
+
+impl LineDiscipline {
+
+    pub fn new(discipline: Discipline) -> LineDiscipline {
+        LineDiscipline {
+            discipline,
+            line: vec![],
+            last_was_cr: false
+        }
+    }
+
+    /// Echo input back whether or not the discipline asked for it (for
+    /// instance because we've told a telnet client that we will).
+    pub fn set_echo(&mut self, echo: bool) {
+        self.discipline.echo = echo;
+    }
+
+    /// Handle a byte of input. Anything that's ready for the program is added
+    /// to `input`, and anything to be echoed back is added to `echo`.
+    pub fn receive(&mut self, byte: u8, input: &mut Vec<u8>, echo: &mut Vec<u8>) {
+
+        let byte = if self.discipline.crlf {
+            let was_cr = self.last_was_cr;
+            self.last_was_cr = byte == b'\r';
+            match byte {
+                b'\n' if was_cr => return,
+                b'\r' => b'\n',
+                byte => byte
+            }
+        } else {
+            byte
+        };
+
+        if !self.discipline.edit {
+            input.push(byte);
+            self.echo(byte, echo);
+            return;
+        }
+
+        match byte {
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() && self.discipline.echo {
+                    echo.extend_from_slice(b"\x08 \x08");
+                }
+            },
+            KILL_LINE => {
+                for _ in self.line.drain(..) {
+                    if self.discipline.echo {
+                        echo.extend_from_slice(b"\x08 \x08");
+                    }
+                }
+            },
+            b'\n' => {
+                self.line.push(byte);
+                input.extend(self.line.drain(..));
+                self.echo(byte, echo);
+            },
+            byte => {
+                self.line.push(byte);
+                self.echo(byte, echo);
+            }
+        }
+    }
+
+    /// Hand on anything still being edited once input has come to an end,
+    /// so that a last line without a newline isn't lost.
+    pub fn finish(&mut self, input: &mut Vec<u8>) {
+        input.extend(self.line.drain(..));
+    }
+
+    fn echo(&self, byte: u8, echo: &mut Vec<u8>) {
+        if !self.discipline.echo {
+            return;
+        }
+        match byte {
+            b'\r' | b'\n' => echo.extend_from_slice(b"\r\n"),
+            byte => echo.push(byte)
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Feed some bytes through, returning what would reach the program and what would be echoed:
+    fn feed(discipline: &str, bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut line_discipline = LineDiscipline::new(discipline.parse().unwrap());
+        let (mut input, mut echo) = (vec![], vec![]);
+        for &byte in bytes {
+            line_discipline.receive(byte, &mut input, &mut echo);
+        }
+        (input, echo)
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!("raw".parse(), Ok(Discipline::raw()));
+        assert_eq!("crlf".parse(), Ok(Discipline{ crlf: true, edit: false, echo: false }));
+        assert_eq!("edit, echo".parse(), Ok(Discipline{ crlf: false, edit: true, echo: true }));
+        assert_eq!("crlf,edit,echo".parse(), Ok(Discipline{ crlf: true, edit: true, echo: true }));
+        assert!("crlf,bogus".parse::<Discipline>().is_err());
+        assert!("".parse::<Discipline>().is_err());
+    }
+
+    #[test]
+    fn raw_passes_everything_on() {
+        assert_eq!(feed("raw", b"a\r\nb\x08\x7F"), (b"a\r\nb\x08\x7F".to_vec(), vec![]));
+    }
+
+    #[test]
+    fn folds_crlf() {
+        assert_eq!(feed("crlf", b"a\r\nb\rc\n\r\r\n").0, b"a\nb\nc\n\n\n".to_vec());
+    }
+
+    #[test]
+    fn edits_lines() {
+        // Nothing is passed on until the line is finished:
+        assert_eq!(feed("edit", b"abc").0, vec![]);
+        assert_eq!(feed("edit", b"abx\x08c\n").0, b"abc\n".to_vec());
+        assert_eq!(feed("edit", b"ab\x7F\x7F\x7Fd\n").0, b"d\n".to_vec());
+        assert_eq!(feed("edit", b"oops\x15fine\n").0, b"fine\n".to_vec());
+    }
+
+    #[test]
+    fn echoes() {
+        assert_eq!(feed("echo", b"hi\n"), (b"hi\n".to_vec(), b"hi\r\n".to_vec()));
+        assert_eq!(feed("edit,echo", b"ab\x08\x08\x08c\n"), (b"c\n".to_vec(), b"ab\x08 \x08\x08 \x08c\r\n".to_vec()));
+        assert_eq!(feed("edit,echo", b"ab\x15").1, b"ab\x08 \x08\x08 \x08".to_vec());
+    }
+
+    #[test]
+    fn finishes_partial_lines() {
+        let mut line_discipline = LineDiscipline::new("edit".parse().unwrap());
+        let (mut input, mut echo) = (vec![], vec![]);
+        for &byte in b"last" {
+            line_discipline.receive(byte, &mut input, &mut echo);
+        }
+        line_discipline.finish(&mut input);
+        assert_eq!(input, b"last".to_vec());
+    }
+
+}
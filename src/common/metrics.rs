@@ -0,0 +1,219 @@
+use crate::api;
+use crate::auth::Peer;
+use crate::connection::Context;
+use crate::program::Program;
+use crate::io_extra;
+use crate::web::response;
+use tokio::prelude::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, Instant };
+
+// Requests longer than this are given up on:
+const MAX_REQUEST_LEN: usize = 8192;
+
+// Rates, and anything that's costly to count, are worked out this often:
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Names of the operators, by number, for labelling their counts:
+const OPERATORS: [&str; 14] = [
+    "conditional_move", "array_index", "array_amendment", "addition",
+    "multiplication", "division", "not_and", "halt", "allocation",
+    "abandonment", "output", "input", "load_program", "orthography"
+];
+
+/// Where to serve metrics from, and the metrics to serve.
+#[derive(Debug, Clone)]
+pub struct Exporter {
+    pub address: SocketAddr,
+    pub metrics: Metrics
+}
+
+/// How a running program is getting on, shared between whatever is running
+/// it (via a `Recorder`) and anything serving the numbers up.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counts: Arc<Counts>
+}
+
+#[derive(Default)]
+struct Counts {
+    cycles: AtomicUsize,
+    per_second: AtomicUsize,
+    operators: [AtomicUsize; 14],
+    arrays: AtomicUsize,
+    platters: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+    pending_input: AtomicUsize
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Metrics {{ .. }}")
+    }
+}
+
+impl Metrics {
+
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Something to count with, which hands its counts on every so often.
+    pub fn recorder(&self) -> Recorder {
+        Recorder {
+            metrics: self.clone(),
+            operators: [0; 16],
+            bytes_in: 0,
+            bytes_out: 0,
+            sampled_at: Instant::now(),
+            sampled_cycles: 0
+        }
+    }
+
+    /// The metrics in Prometheus' text format. How many clients are connected
+    /// is given, as it's known by the floor rather than the program.
+    pub fn render(&self, clients: usize) -> String {
+        let counts = &self.counts;
+        let get = |count: &AtomicUsize| count.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: usize| {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        };
+        metric("um_cycles_total", "counter", "Instructions executed.", get(&counts.cycles));
+        metric("um_instructions_per_second", "gauge", "Instructions executed per second, over the last second or so.", get(&counts.per_second));
+        metric("um_live_arrays", "gauge", "Active arrays, including the program itself.", get(&counts.arrays));
+        metric("um_platters", "gauge", "Platters held by active arrays.", get(&counts.platters));
+        metric("um_input_bytes_total", "counter", "Bytes of input read by the program.", get(&counts.bytes_in));
+        metric("um_output_bytes_total", "counter", "Bytes of output written by the program.", get(&counts.bytes_out));
+        metric("um_connected_clients", "gauge", "Sources of input currently connected, including stdin.", clients);
+        metric("um_input_queue_bytes", "gauge", "Bytes of input waiting for the program to read them.", get(&counts.pending_input));
+        out.push_str("# HELP um_operators_total Instructions executed, by operator.\n# TYPE um_operators_total counter\n");
+        for (name, count) in OPERATORS.iter().zip(counts.operators.iter()) {
+            out.push_str(&format!("um_operators_total{{operator=\"{}\"}} {}\n", name, get(count)));
+        }
+        out
+    }
+
+}
+
+/// Counts what a program does as it runs. Counts are kept locally, so that
+/// counting is cheap, and handed on whenever `record` is called.
+pub struct Recorder {
+    metrics: Metrics,
+    operators: [usize; 16],
+    bytes_in: usize,
+    bytes_out: usize,
+    sampled_at: Instant,
+    sampled_cycles: usize
+}
+
+impl Recorder {
+
+    /// Count the instruction that the program is about to execute.
+    pub fn step(&mut self, program: &Program) {
+        if let Some(op) = program.next_operator() {
+            self.operators[op] += 1;
+        }
+    }
+
+    pub fn input(&mut self) {
+        self.bytes_in += 1;
+    }
+
+    pub fn output(&mut self) {
+        self.bytes_out += 1;
+    }
+
+    /// Hand counts on. Rates, and the memory in use, are only worked out
+    /// every so often, as counting arrays can take a while.
+    pub fn record(&mut self, program: &Program, cycles: usize, pending_input: usize) {
+        let counts = &self.metrics.counts;
+        counts.cycles.store(cycles, Ordering::Relaxed);
+        counts.bytes_in.store(self.bytes_in, Ordering::Relaxed);
+        counts.bytes_out.store(self.bytes_out, Ordering::Relaxed);
+        counts.pending_input.store(pending_input, Ordering::Relaxed);
+        for (count, &n) in counts.operators.iter().zip(self.operators.iter()) {
+            count.store(n, Ordering::Relaxed);
+        }
+
+        let elapsed = self.sampled_at.elapsed();
+        if elapsed >= SAMPLE_INTERVAL {
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            let per_second = (cycles - self.sampled_cycles) as f64 / secs;
+            counts.per_second.store(per_second as usize, Ordering::Relaxed);
+            counts.arrays.store(program.active_arrays(), Ordering::Relaxed);
+            counts.platters.store(program.platter_count(), Ordering::Relaxed);
+            self.sampled_at = Instant::now();
+            self.sampled_cycles = cycles;
+        }
+    }
+
+}
+
+/// Answer an HTTP connection with the metrics, if it asks for them. If network
+/// connections need a password, so does this (given like it is to the API).
+pub fn serve<R, W>(reader: R, writer: W, peer: Peer, context: Context, metrics: Metrics)
+where R: AsyncRead + Send + 'static, W: AsyncWrite + Send + 'static {
+    tokio::spawn_async(async move {
+
+        let mut input = io_extra::stream_bytes(reader);
+        let mut request = vec![];
+        while !request.ends_with(b"\r\n\r\n") {
+            match await!(input.next()) {
+                Some(Ok(byte)) if request.len() < MAX_REQUEST_LEN => request.push(byte),
+                _ => return
+            }
+        }
+
+        let allowed = match (&context.auth, api::parse_head(&request).password) {
+            (None, _) => true,
+            (Some(auth), Some(password)) => auth.check(peer, password.as_bytes()),
+            (Some(_), None) => false
+        };
+
+        let first = request.split(|&b| b == b'\r').next().unwrap_or(&[]);
+        let reply = if !allowed {
+            response("401 Unauthorized", "text/plain", b"A password is required")
+        } else if first.starts_with(b"GET /metrics ") {
+            let body = metrics.render(context.floor.seated());
+            response("200 OK", "text/plain; version=0.0.4", body.as_bytes())
+        } else {
+            response("404 Not Found", "text/plain", b"Not found")
+        };
+        let _ = await!(tokio::io::write_all(writer, reply));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.counts.cycles.store(1234, Ordering::Relaxed);
+        metrics.counts.arrays.store(3, Ordering::Relaxed);
+        metrics.counts.operators[3].store(7, Ordering::Relaxed);
+        let text = metrics.render(2);
+
+        assert!(text.contains("# HELP um_cycles_total Instructions executed.\n# TYPE um_cycles_total counter\num_cycles_total 1234\n"));
+        assert!(text.contains("# TYPE um_live_arrays gauge\num_live_arrays 3\n"));
+        assert!(text.contains("# TYPE um_connected_clients gauge\num_connected_clients 2\n"));
+        assert!(text.contains("um_input_bytes_total 0\n"));
+        assert!(text.contains("um_operators_total{operator=\"addition\"} 7\n"));
+        assert!(text.contains("um_operators_total{operator=\"orthography\"} 0\n"));
+
+        // Every line is a comment or a sample, and every sample has been described:
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(|c| c == ' ' || c == '{').next().unwrap();
+            assert!(text.contains(&format!("# TYPE {} ", name)), "{} has no type", name);
+            assert!(line.rsplit(' ').next().unwrap().parse::<usize>().is_ok(), "{} has no value", line);
+        }
+        assert_eq!(text.lines().filter(|line| line.starts_with("um_operators_total")).count(), OPERATORS.len());
+    }
+
+}
@@ -0,0 +1,417 @@
+//! A machine interface: JSON-lines commands in, and JSON-lines replies and events
+//! out, so that tools can drive a program without parsing a terminal stream.
+//!
+//! Commands look like `{"command": "step", "count": 10, "id": 1}`; the `id` (if
+//! any) is echoed back in the reply to each. While a program is running, events
+//! are sent for its output and for why it stopped, each with the machine's state.
+
+use crate::program::{ Program, StepResult, Inputter };
+use crate::snapshot;
+use crate::api::byte_string;
+use crate::error::Error;
+use crossbeam::channel;
+use serde_json::{ json, Value };
+use std::collections::{ HashSet, VecDeque };
+use std::io::{ BufRead, Write };
+use std::path::PathBuf;
+use std::thread;
+
+// Output is sent on in chunks of up to this size:
+const OUTPUT_CHUNK: usize = 4096;
+
+// While running, we check for new commands this often:
+const CHECK_EVERY: usize = 4096;
+
+// The most platters that can be read in one go:
+const MAX_READ: usize = 64 * 1024;
+
+/// Something we've been asked to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Input(Vec<u8>),
+    Eof,
+    Step(usize),
+    Run,
+    Interrupt,
+    Break(usize),
+    Clear(usize),
+    Snapshot(Option<PathBuf>),
+    Read{ array: usize, start: usize, len: usize },
+    State,
+    Quit
+}
+
+/// Parse a line into a command, along with the id to reply with.
+pub fn parse(line: &str) -> Result<(Value, Command), String> {
+
+    let value: Value = serde_json::from_str(line).map_err(|e| format!("invalid JSON: {}", e))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let number = |key: &str| value.get(key).and_then(|n| n.as_u64()).map(|n| n as usize);
+    let required = |key: &str| number(key).ok_or_else(|| format!("expected a number for '{}'", key));
+
+    let command = match value.get("command").and_then(|c| c.as_str()) {
+        Some("input") => {
+            // Each character stands for the byte with the same code:
+            let data = value.get("data").and_then(|d| d.as_str()).ok_or("expected a string for 'data'")?;
+            let bytes = data.chars().map(|c| if (c as u32) < 256 { Ok(c as u8) } else { Err(c) }).collect::<Result<Vec<u8>, char>>()
+                .map_err(|c| format!("'{}' can't be sent as a single byte", c))?;
+            Command::Input(bytes)
+        },
+        Some("eof") => Command::Eof,
+        Some("step") => Command::Step(number("count").unwrap_or(1)),
+        Some("run") => Command::Run,
+        Some("interrupt") => Command::Interrupt,
+        Some("break") => Command::Break(required("finger")?),
+        Some("clear") => Command::Clear(required("finger")?),
+        Some("snapshot") => Command::Snapshot(value.get("path").and_then(|p| p.as_str()).map(PathBuf::from)),
+        Some("read") => Command::Read {
+            array: required("array")?,
+            start: number("start").unwrap_or(0),
+            len: std::cmp::min(number("len").unwrap_or(MAX_READ), MAX_READ)
+        },
+        Some("state") => Command::State,
+        Some("quit") => Command::Quit,
+        Some(other) => return Err(format!("'{}' is not a command I know", other)),
+        None => return Err("expected a string for 'command'".to_string())
+    };
+    Ok((id, command))
+}
+
+/// Why the program stopped running.
+enum Stop {
+    Halted,
+    Faulted(String),
+    InputNeeded,
+    Breakpoint,
+    Stepped,
+    Interrupted
+}
+
+struct Session<W> {
+    program: Program,
+    waiting: Option<Inputter>,
+    input: VecDeque<u8>,
+    // Whether to send EOF once the input before it has been read:
+    eof: bool,
+    breakpoints: HashSet<usize>,
+    cycles: usize,
+    // Once the program has halted or faulted, why it can't carry on:
+    ended: Option<String>,
+    output: Vec<u8>,
+    out: W,
+    lines: channel::Receiver<String>,
+    // Commands which arrived while running, to handle once we've stopped:
+    deferred: VecDeque<String>
+}
+
+/// Drive a program (which may be waiting on input, if restored from a snapshot)
+/// with commands read from one place, sending replies and events to another,
+/// until there are no more commands or we're asked to quit.
+pub fn run<R, W>(program: Program, waiting: Option<Inputter>, commands: R, out: W) -> Result<(), Error>
+where R: BufRead + Send + 'static, W: Write {
+
+    // Commands are read on a thread of their own, so that
+    // they can be looked at while the program is running:
+    let (send_line, lines) = channel::unbounded();
+    thread::spawn(move || {
+        for line in commands.lines() {
+            match line {
+                Ok(line) => send_line.send(line),
+                Err(_) => break
+            }
+        }
+    });
+
+    let mut session = Session {
+        program,
+        waiting,
+        input: VecDeque::new(),
+        eof: false,
+        breakpoints: HashSet::new(),
+        cycles: 0,
+        ended: None,
+        output: vec![],
+        out,
+        lines,
+        deferred: VecDeque::new()
+    };
+    while let Some(line) = session.deferred.pop_front().or_else(|| session.lines.recv()) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !session.handle(&line)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl <W: Write> Session<W> {
+
+    /// Handle a command, returning whether to carry on afterwards.
+    fn handle(&mut self, line: &str) -> Result<bool, Error> {
+
+        let (id, command) = match parse(line) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.error(&Value::Null, &e)?;
+                return Ok(true);
+            }
+        };
+
+        match command {
+            Command::Input(bytes) => {
+                self.input.extend(bytes);
+                self.reply(&id, "input", json!({}))?;
+            },
+            Command::Eof => {
+                self.eof = true;
+                self.reply(&id, "eof", json!({}))?;
+            },
+            Command::Step(_) | Command::Run if self.ended.is_some() => {
+                let reason = self.ended.clone().unwrap_or_default();
+                self.error(&id, &reason)?;
+            },
+            Command::Step(count) => {
+                let stop = self.advance(Some(count))?;
+                self.stopped(stop)?;
+                self.reply(&id, "step", json!({}))?;
+            },
+            Command::Run => {
+                let stop = self.advance(None)?;
+                self.stopped(stop)?;
+                self.reply(&id, "run", json!({}))?;
+            },
+            Command::Interrupt => {
+                // Nothing is running, so there's nothing to interrupt:
+                self.reply(&id, "interrupt", json!({}))?;
+            },
+            Command::Break(finger) => {
+                self.breakpoints.insert(finger);
+                self.reply(&id, "break", json!({ "finger": finger }))?;
+            },
+            Command::Clear(finger) => {
+                self.breakpoints.remove(&finger);
+                self.reply(&id, "clear", json!({ "finger": finger }))?;
+            },
+            Command::Snapshot(path) => {
+                let bytes = snapshot::save(&self.program, self.waiting);
+                match path {
+                    Some(path) => match std::fs::write(&path, &bytes) {
+                        Ok(()) => self.reply(&id, "snapshot", json!({ "path": path.to_string_lossy() }))?,
+                        Err(e) => self.error(&id, &format!("couldn't save the snapshot: {}", e))?
+                    },
+                    None => self.reply(&id, "snapshot", json!({ "data": base64::encode(&bytes) }))?
+                }
+            },
+            Command::Read{ array, start, len } => {
+                let platters = self.program.array(array).map(|platters| {
+                    platters.iter().skip(start).take(len).map(|p| p.to_u32()).collect::<Vec<_>>()
+                });
+                match platters {
+                    Some(platters) => self.reply(&id, "read", json!({ "array": array, "start": start, "platters": platters }))?,
+                    None => self.error(&id, &format!("array {} isn't active", array))?
+                }
+            },
+            Command::State => {
+                let state = self.state();
+                self.reply(&id, "state", json!({ "state": state }))?;
+            },
+            Command::Quit => {
+                self.reply(&id, "quit", json!({}))?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Run the program until it stops, for up to `limit` steps if one is given.
+    /// Breakpoints are only stopped at after taking a step, so that running
+    /// again from one carries on past it.
+    fn advance(&mut self, limit: Option<usize>) -> Result<Stop, Error> {
+        let mut steps = 0;
+        loop {
+            if let Some(inputter) = self.waiting {
+                match self.input.pop_front() {
+                    Some(byte) => self.program.provide_input(inputter, Some(byte)),
+                    None if self.eof => {
+                        self.program.provide_input(inputter, None);
+                        self.eof = false;
+                    },
+                    None => return Ok(Stop::InputNeeded)
+                }
+                self.waiting = None;
+            }
+            if limit == Some(steps) {
+                return Ok(Stop::Stepped);
+            }
+            if steps > 0 && self.breakpoints.contains(&self.program.instruction_index()) {
+                return Ok(Stop::Breakpoint);
+            }
+            if steps > 0 && steps % CHECK_EVERY == 0 && self.check_commands()? {
+                return Ok(Stop::Interrupted);
+            }
+
+            steps += 1;
+            self.cycles += 1;
+            match self.program.step() {
+                Ok(StepResult::Halted) => return Ok(Stop::Halted),
+                Ok(StepResult::Output{ ascii }) => {
+                    self.output.push(ascii);
+                    if self.output.len() >= OUTPUT_CHUNK {
+                        self.send_output()?;
+                    }
+                },
+                Ok(StepResult::InputNeeded{ inputter }) => self.waiting = Some(inputter),
+                Ok(StepResult::Continue) => {},
+                Err(e) => return Ok(Stop::Faulted(e.message().to_string()))
+            }
+        }
+    }
+
+    /// Look at anything that's arrived while running. Input is queued up straight away,
+    /// and anything else is handled once we've stopped. Returns whether to stop now.
+    fn check_commands(&mut self) -> Result<bool, Error> {
+        let mut interrupted = false;
+        while let Some(line) = self.lines.try_recv() {
+            match parse(&line) {
+                Ok((id, Command::Input(bytes))) => {
+                    self.input.extend(bytes);
+                    self.reply(&id, "input", json!({}))?;
+                },
+                Ok((id, Command::Eof)) => {
+                    self.eof = true;
+                    self.reply(&id, "eof", json!({}))?;
+                },
+                Ok((id, Command::Interrupt)) => {
+                    self.reply(&id, "interrupt", json!({}))?;
+                    interrupted = true;
+                },
+                _ => self.deferred.push_back(line)
+            }
+        }
+        Ok(interrupted)
+    }
+
+    // Say why we stopped, after any output from before then:
+    fn stopped(&mut self, stop: Stop) -> Result<(), Error> {
+        self.send_output()?;
+        let state = self.state();
+        let event = match stop {
+            Stop::Halted => {
+                self.ended = Some("the program has halted".to_string());
+                json!({ "event": "halted", "state": state })
+            },
+            Stop::Faulted(reason) => {
+                self.ended = Some(format!("the program has faulted: {}", reason));
+                json!({ "event": "fault", "message": reason, "state": state })
+            },
+            Stop::InputNeeded => json!({ "event": "input_needed", "state": state }),
+            Stop::Breakpoint => json!({ "event": "stopped", "reason": "breakpoint", "state": state }),
+            Stop::Stepped => json!({ "event": "stopped", "reason": "step", "state": state }),
+            Stop::Interrupted => json!({ "event": "stopped", "reason": "interrupt", "state": state })
+        };
+        self.send(&event)
+    }
+
+    fn send_output(&mut self) -> Result<(), Error> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        let event = json!({ "event": "output", "data": byte_string(&self.output) });
+        self.output.clear();
+        self.send(&event)
+    }
+
+    fn state(&self) -> Value {
+        let registers: Vec<u32> = self.program.registers().iter().map(|r| r.to_u32()).collect();
+        json!({
+            "finger": self.program.instruction_index(),
+            "registers": registers,
+            "arrays": self.program.active_arrays(),
+            "platters": self.program.platter_count(),
+            "cycles": self.cycles,
+            "waiting_for_input": self.waiting.is_some(),
+            "pending_input": self.input.len()
+        })
+    }
+
+    // Reply to a command, with anything else to say about it in `body`:
+    fn reply(&mut self, id: &Value, command: &str, mut body: Value) -> Result<(), Error> {
+        body["reply"] = json!(command);
+        body["id"] = id.clone();
+        self.send(&body)
+    }
+
+    fn error(&mut self, id: &Value, message: &str) -> Result<(), Error> {
+        self.send(&json!({ "reply": "error", "id": id, "message": message }))
+    }
+
+    fn send(&mut self, message: &Value) -> Result<(), Error> {
+        writeln!(self.out, "{}", message)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn command(line: &str) -> Result<Command, String> {
+        parse(line).map(|(_, command)| command)
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(command(r#"{"command": "eof"}"#), Ok(Command::Eof));
+        assert_eq!(command(r#"{"command": "step"}"#), Ok(Command::Step(1)));
+        assert_eq!(command(r#"{"command": "step", "count": 10}"#), Ok(Command::Step(10)));
+        assert_eq!(command(r#"{"command": "run"}"#), Ok(Command::Run));
+        assert_eq!(command(r#"{"command": "interrupt"}"#), Ok(Command::Interrupt));
+        assert_eq!(command(r#"{"command": "break", "finger": 42}"#), Ok(Command::Break(42)));
+        assert_eq!(command(r#"{"command": "clear", "finger": 42}"#), Ok(Command::Clear(42)));
+        assert_eq!(command(r#"{"command": "snapshot"}"#), Ok(Command::Snapshot(None)));
+        assert_eq!(command(r#"{"command": "snapshot", "path": "a.snap"}"#), Ok(Command::Snapshot(Some(PathBuf::from("a.snap")))));
+        assert_eq!(command(r#"{"command": "state"}"#), Ok(Command::State));
+        assert_eq!(command(r#"{"command": "quit"}"#), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(parse(r#"{"command": "run", "id": 7}"#).map(|(id, _)| id), Ok(json!(7)));
+        assert_eq!(parse(r#"{"command": "run", "id": "x"}"#).map(|(id, _)| id), Ok(json!("x")));
+        assert_eq!(parse(r#"{"command": "run"}"#).map(|(id, _)| id), Ok(Value::Null));
+    }
+
+    #[test]
+    fn input() {
+        assert_eq!(command(r#"{"command": "input", "data": "hi\n"}"#), Ok(Command::Input(b"hi\n".to_vec())));
+        assert_eq!(command(r#"{"command": "input", "data": "ÿ"}"#), Ok(Command::Input(vec![255])));
+        assert!(command(r#"{"command": "input", "data": "Ā"}"#).is_err());
+        assert!(command(r#"{"command": "input", "data": 5}"#).is_err());
+        assert!(command(r#"{"command": "input"}"#).is_err());
+    }
+
+    #[test]
+    fn reads() {
+        assert_eq!(command(r#"{"command": "read", "array": 2}"#), Ok(Command::Read{ array: 2, start: 0, len: MAX_READ }));
+        assert_eq!(command(r#"{"command": "read", "array": 0, "start": 10, "len": 5}"#), Ok(Command::Read{ array: 0, start: 10, len: 5 }));
+        assert_eq!(command(r#"{"command": "read", "array": 0, "len": 99999999}"#), Ok(Command::Read{ array: 0, start: 0, len: MAX_READ }));
+        assert!(command(r#"{"command": "read"}"#).is_err());
+        assert!(command(r#"{"command": "read", "array": -1}"#).is_err());
+    }
+
+    #[test]
+    fn bad_commands() {
+        assert!(command("not json").is_err());
+        assert!(command(r#"{"command": "fly"}"#).is_err());
+        assert!(command(r#"{"command": 3}"#).is_err());
+        assert!(command(r#"{}"#).is_err());
+        assert!(command(r#"{"command": "break"}"#).is_err());
+        assert!(command(r#"{"command": "clear", "finger": "x"}"#).is_err());
+    }
+
+}
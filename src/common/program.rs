@@ -1,11 +1,17 @@
 use crate::platter::Platter;
 use crate::error::{err, Error};
+use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct Program {
     registers: [Platter; 8],
-    platters: Vec<Vec<Platter>>,
+    // Arrays are shared copy-on-write between forks of a program:
+    platters: Vec<Arc<Vec<Platter>>>,
+    // Whether each array is active, so that this can be checked cheaply:
+    active: Vec<bool>,
     free: Vec<usize>,
-    finger: usize
+    finger: usize,
+    code_source: usize
 }
 
 impl Program {
@@ -13,9 +19,11 @@ impl Program {
     pub fn new() -> Program {
         Program {
             registers: [Platter::from(0); 8],
-            platters: vec![vec![]],
+            platters: vec![Arc::new(vec![])],
+            active: vec![true],
             free: vec![],
-            finger: 0
+            finger: 0,
+            code_source: 0
         }
     }
 
@@ -27,20 +35,95 @@ impl Program {
         self.platters[0].len()
     }
 
+    /// The identifier of the array that the running program was last
+    /// loaded from, or 0 if it has never been replaced.
+    pub fn code_source(&self) -> usize {
+        self.code_source
+    }
+
     pub fn load_program(&mut self, scrolls: &[u8]) {
-        let mut program_vec = vec![];
-        for chunks in scrolls.chunks(4) {
-            if let &[a,b,c,d] = chunks {
-                let val = (a as u32) << 24
-                        | (b as u32) << 16
-                        | (c as u32) << 8
-                        | (d as u32);
-                program_vec.push(Platter::from(val));
-            } else {
-                continue;
+        self.platters[0] = Arc::new(platters_from_scrolls(scrolls));
+    }
+
+    /// Load a program which has already been decoded into platters.
+    pub fn load_platters(&mut self, platters: Vec<Platter>) {
+        self.platters[0] = Arc::new(platters);
+    }
+
+    /// Build a program from the raw parts of its state. Arrays whose identifiers
+    /// are listed as abandoned must be empty, and each can only be listed once.
+    pub fn from_parts(registers: [Platter; 8], arrays: Vec<Vec<Platter>>, abandoned: Vec<usize>, finger: usize, code_source: usize) -> Result<Program, Error> {
+        let mut platters: Vec<_> = arrays.into_iter().map(Arc::new).collect();
+        if platters.is_empty() {
+            platters.push(Arc::new(vec![]));
+        }
+        let mut active = vec![true; platters.len()];
+        for &idx in &abandoned {
+            if idx == 0 {
+                return Err(err("array 0 can't be abandoned"));
+            }
+            match active.get_mut(idx) {
+                None => return Err(err(format!("abandoned array {} doesn't exist", idx))),
+                Some(false) => return Err(err(format!("array {} is abandoned more than once", idx))),
+                Some(_) if !platters[idx].is_empty() => return Err(err(format!("abandoned array {} isn't empty", idx))),
+                Some(slot) => *slot = false
             }
         }
-        self.platters[0] = program_vec;
+        Ok(Program {
+            registers,
+            platters,
+            active,
+            free: abandoned,
+            finger,
+            code_source
+        })
+    }
+
+    /// An independent copy of this program, which can be run on another thread.
+    /// Arrays are shared with the original until one side writes to them, so this
+    /// is cheap no matter how much memory the program is using.
+    pub fn fork(&self) -> Program {
+        self.clone()
+    }
+
+    /// The current contents of each register.
+    pub fn registers(&self) -> &[Platter; 8] {
+        &self.registers
+    }
+
+    /// The contents of an array, if it is active.
+    pub fn array(&self, idx: usize) -> Option<&[Platter]> {
+        if !self.active.get(idx).cloned().unwrap_or(false) {
+            return None;
+        }
+        self.platters.get(idx).map(|array| &array[..])
+    }
+
+    /// Identifiers of abandoned arrays, in the order they were abandoned.
+    /// The last of these will be the next to be handed out again.
+    pub fn abandoned(&self) -> &[usize] {
+        &self.free
+    }
+
+    /// Iterate over the identifier and contents of each active array,
+    /// including the program itself in array 0.
+    pub fn arrays<'a>(&'a self) -> impl Iterator<Item=(usize, &'a [Platter])> + 'a {
+        self.platters
+            .iter()
+            .enumerate()
+            .filter(move |(idx, _)| self.active[*idx])
+            .map(|(idx, array)| (idx, &array[..]))
+    }
+
+    /// How many arrays are active, including the program itself.
+    pub fn active_arrays(&self) -> usize {
+        self.active.iter().filter(|&&active| active).count()
+    }
+
+    /// How many platters the active arrays hold between them
+    /// (abandoned arrays are always empty).
+    pub fn platter_count(&self) -> usize {
+        self.platters.iter().map(|array| array.len()).sum()
     }
 
     /// If a step asks for input, we are given back an Inputter, which cannot
@@ -54,6 +137,11 @@ impl Program {
         }
     }
 
+    /// The operator number of the instruction the finger is on, if any.
+    pub fn next_operator(&self) -> Option<usize> {
+        self.platters[0].get(self.finger).map(|platter| (platter.to_u32() >> 28) as usize)
+    }
+
     pub fn step(&mut self) -> Result<StepResult,Error> {
         let program = &self.platters[0];
 
@@ -88,7 +176,7 @@ impl Program {
                 self.registers[a()] = *val;
             },
             2 /* Array Amendment */ => {
-                let array = self.platters.get_mut(self.registers[a()].to_pos())?;
+                let array = Arc::make_mut(self.platters.get_mut(self.registers[a()].to_pos())?);
                 let offset = self.registers[b()].to_pos();
                 *array.get_mut(offset)? = self.registers[c()];
             },
@@ -114,18 +202,25 @@ impl Program {
             8 /* Allocation */ => {
                 let size = self.registers[c()].to_pos();
                 let pos = if let Some(idx) = self.free.pop() {
-                    self.platters[idx] = vec![Platter::from(0); size];
+                    self.platters[idx] = Arc::new(vec![Platter::from(0); size]);
+                    self.active[idx] = true;
                     idx
                 } else {
                     let idx = self.platters.len();
-                    self.platters.push(vec![Platter::from(0); size]);
+                    self.platters.push(Arc::new(vec![Platter::from(0); size]));
+                    self.active.push(true);
                     idx
                 };
                 self.registers[b()] = Platter::from(pos as u32);
             },
             9 /* Abandonment */ => {
+                // Abandoning the program, or an array twice, would see it handed out twice:
                 let idx = self.registers[c()].to_pos();
-                *self.platters.get_mut(self.registers[c()].to_pos())? = vec![];
+                if idx == 0 || !self.active.get(idx).cloned().unwrap_or(false) {
+                    return Err(err("abandoned an inactive array"));
+                }
+                self.platters[idx] = Arc::new(vec![]);
+                self.active[idx] = false;
                 self.free.push(idx);
             },
             10 /* Output */ => {
@@ -138,6 +233,7 @@ impl Program {
                 let pos = self.registers[b()].to_pos();
                 if pos != 0 {
                     self.platters[0] = self.platters.get(pos)?.clone();
+                    self.code_source = pos;
                 }
                 self.finger = self.registers[c()].to_pos();
             },
@@ -173,3 +269,137 @@ pub struct Inputter {
     register: usize
 }
 
+impl Inputter {
+    /// Only for recreating an inputter that a program was waiting on
+    /// before it was saved.
+    pub(crate) fn new(register: usize) -> Inputter {
+        Inputter { register }
+    }
+
+    /// The register that input will be written to.
+    pub fn register(&self) -> usize {
+        self.register
+    }
+}
+
+/// Decode the big-endian scrolls of a UM program into platters. Any
+/// trailing bytes that don't make up a whole platter are ignored.
+pub fn platters_from_scrolls(scrolls: &[u8]) -> Vec<Platter> {
+    let mut platters = vec![];
+    for chunks in scrolls.chunks(4) {
+        if let &[a,b,c,d] = chunks {
+            let val = (a as u32) << 24
+                    | (b as u32) << 16
+                    | (c as u32) << 8
+                    | (d as u32);
+            platters.push(Platter::from(val));
+        } else {
+            continue;
+        }
+    }
+    platters
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::instruction::Instruction;
+
+    fn program(instructions: &[Instruction]) -> Program {
+        let mut program = Program::new();
+        program.load_platters(instructions.iter().map(|i| i.encode()).collect());
+        program
+    }
+
+    fn run(program: &mut Program) -> Result<(), Error> {
+        loop {
+            if let StepResult::Halted = program.step()? {
+                return Ok(());
+            }
+        }
+    }
+
+    #[test]
+    fn abandoning_the_program_fails() {
+        let mut program = program(&[
+            Instruction::Abandonment{ c: 0 },
+            Instruction::Halt
+        ]);
+        assert!(run(&mut program).is_err());
+        assert_eq!(program.active_arrays(), 1);
+        assert!(program.abandoned().is_empty());
+    }
+
+    #[test]
+    fn abandoning_twice_fails() {
+        let mut program = program(&[
+            Instruction::Allocation{ b: 1, c: 0 },
+            Instruction::Abandonment{ c: 1 },
+            Instruction::Abandonment{ c: 1 },
+            Instruction::Halt
+        ]);
+        assert!(run(&mut program).is_err());
+        assert_eq!(program.active_arrays(), 1);
+        assert_eq!(program.abandoned(), &[1]);
+    }
+
+    #[test]
+    fn abandoned_arrays_are_reused() {
+        let mut program = program(&[
+            Instruction::Orthography{ a: 2, value: 3 },
+            Instruction::Allocation{ b: 1, c: 2 },
+            Instruction::Allocation{ b: 3, c: 2 },
+            Instruction::Abandonment{ c: 1 },
+            Instruction::Allocation{ b: 4, c: 2 },
+            Instruction::Halt
+        ]);
+        run(&mut program).unwrap();
+        assert_eq!(program.registers()[4].to_u32(), 1);
+        assert_eq!(program.active_arrays(), 3);
+        assert_eq!(program.array(1), Some(&[Platter::from(0); 3][..]));
+    }
+
+    #[test]
+    fn checks_abandoned_arrays() {
+        let parts = |arrays: Vec<Vec<Platter>>, abandoned: Vec<usize>| {
+            Program::from_parts([Platter::from(0); 8], arrays, abandoned, 0, 0)
+        };
+        assert!(parts(vec![vec![], vec![]], vec![1]).is_ok());
+        assert!(parts(vec![vec![], vec![]], vec![0]).is_err());
+        assert!(parts(vec![vec![], vec![]], vec![2]).is_err());
+        assert!(parts(vec![vec![], vec![], vec![]], vec![1, 2, 1]).is_err());
+        assert!(parts(vec![vec![], vec![Platter::from(1)]], vec![1]).is_err());
+    }
+
+    #[test]
+    fn forks_are_independent() {
+        let mut original = program(&[
+            Instruction::Orthography{ a: 1, value: 1 },
+            Instruction::Orthography{ a: 2, value: 7 },
+            Instruction::Allocation{ b: 3, c: 1 },
+            Instruction::ArrayAmendment{ a: 3, b: 0, c: 2 },
+            Instruction::ArrayAmendment{ a: 0, b: 0, c: 2 },
+            Instruction::Halt
+        ]);
+        for _ in 0..3 {
+            original.step().unwrap();
+        }
+        let mut fork = original.fork();
+        run(&mut fork).unwrap();
+
+        // The fork wrote to its arrays and moved on, but the original didn't:
+        assert_eq!(fork.array(1), Some(&[Platter::from(7)][..]));
+        assert_eq!(fork.array(0).unwrap()[0].to_u32(), 7);
+        assert_eq!(fork.instruction_index(), 6);
+        assert_eq!(original.array(1), Some(&[Platter::from(0)][..]));
+        assert_eq!(original.array(0).unwrap()[0], Instruction::Orthography{ a: 1, value: 1 }.encode());
+        assert_eq!(original.instruction_index(), 3);
+
+        // And the original can carry on as if the fork never happened:
+        run(&mut original).unwrap();
+        assert_eq!(original.array(1), Some(&[Platter::from(7)][..]));
+        assert_eq!(original.array(0).unwrap()[0].to_u32(), 7);
+    }
+
+}
@@ -0,0 +1,45 @@
+/// A small, seedable xorshift* generator. It's nowhere near good enough
+/// for anything cryptographic, but is quick and reproducible, which is
+/// what we want when generating and mutating fuzz cases.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+
+    pub fn new(seed: u64) -> Rng {
+        // A zero state would only ever produce zeros:
+        Rng { state: seed ^ 0x9E37_79B9_7F4A_7C15 | 1 }
+    }
+
+    /// Seed a generator from the current time.
+    pub fn from_time() -> Rng {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Rng::new(now.as_secs() ^ (now.subsec_nanos() as u64) << 32)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// A number in the range 0..n (n must be greater than 0).
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// True roughly once in every n calls.
+    pub fn one_in(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+
+}
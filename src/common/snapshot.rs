@@ -0,0 +1,188 @@
+use crate::platter::Platter;
+use crate::program::{ Program, Inputter };
+use crate::error::{err, Error};
+
+/// Snapshots begin with this, so that they can be told apart from UM programs.
+const MAGIC: &[u8] = b"UMSNAP01";
+const NOT_WAITING: u32 = !0;
+
+/// Is this a snapshot (as opposed to a UM program, say)?
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Save the complete state of a program, along with the inputter it's waiting
+/// on if it has asked for input. Everything is stored big-endian, like scrolls:
+///
+/// magic, finger, code source, 8 registers, input register (or !0),
+/// array count, then for each active array its identifier, length and contents,
+/// and finally the count and identifiers of abandoned arrays.
+pub fn save(program: &Program, waiting: Option<Inputter>) -> Vec<u8> {
+
+    let mut out = MAGIC.to_vec();
+    put(&mut out, program.instruction_index() as u32);
+    put(&mut out, program.code_source() as u32);
+    for reg in program.registers() {
+        put(&mut out, reg.to_u32());
+    }
+    put(&mut out, waiting.map(|i| i.register() as u32).unwrap_or(NOT_WAITING));
+
+    let arrays: Vec<(usize, &[Platter])> = program.arrays().collect();
+    put(&mut out, arrays.len() as u32);
+    for (id, array) in arrays {
+        put(&mut out, id as u32);
+        put(&mut out, array.len() as u32);
+        for platter in array {
+            put(&mut out, platter.to_u32());
+        }
+    }
+
+    put(&mut out, program.abandoned().len() as u32);
+    for &id in program.abandoned() {
+        put(&mut out, id as u32);
+    }
+
+    out
+}
+
+/// Restore a program saved with `save`, handing back the inputter it's waiting on, if any.
+pub fn restore(bytes: &[u8]) -> Result<(Program, Option<Inputter>), Error> {
+
+    if !is_snapshot(bytes) {
+        return Err(err("not a snapshot"));
+    }
+    let mut reader = Reader { bytes, pos: MAGIC.len() };
+
+    let finger = reader.next()? as usize;
+    let code_source = reader.next()? as usize;
+    let mut registers = [Platter::from(0); 8];
+    for reg in registers.iter_mut() {
+        *reg = Platter::from(reader.next()?);
+    }
+    let waiting = match reader.next()? {
+        NOT_WAITING => None,
+        reg if reg < 8 => Some(Inputter::new(reg as usize)),
+        _ => return Err(err("snapshot is waiting on an invalid register"))
+    };
+
+    // Every array is either active or abandoned, and each is described by at least
+    // one word of the snapshot, so identifiers can't be any bigger than this:
+    let max_id = bytes.len() / 4;
+
+    let mut arrays = vec![];
+    for _ in 0..reader.next()? {
+        let id = reader.next()? as usize;
+        if id >= max_id {
+            return Err(err("snapshot has an invalid array identifier"));
+        }
+        let len = reader.next()? as usize;
+        if len > bytes.len() / 4 {
+            return Err(err("snapshot is truncated"));
+        }
+        let mut array = Vec::with_capacity(len);
+        for _ in 0..len {
+            array.push(Platter::from(reader.next()?));
+        }
+        if arrays.len() <= id {
+            arrays.resize(id + 1, vec![]);
+        }
+        arrays[id] = array;
+    }
+
+    let mut abandoned = vec![];
+    for _ in 0..reader.next()? {
+        let id = reader.next()? as usize;
+        if id >= max_id {
+            return Err(err("snapshot has an invalid array identifier"));
+        }
+        if arrays.len() <= id {
+            arrays.resize(id + 1, vec![]);
+        }
+        abandoned.push(id);
+    }
+
+    Ok((Program::from_parts(registers, arrays, abandoned, finger, code_source)?, waiting))
+}
+
+fn put(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&[(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl <'a> Reader<'a> {
+    fn next(&mut self) -> Result<u32, Error> {
+        let chunk = self.bytes.get(self.pos..self.pos + 4).ok_or_else(|| err("snapshot is truncated"))?;
+        self.pos += 4;
+        Ok((chunk[0] as u32) << 24 | (chunk[1] as u32) << 16 | (chunk[2] as u32) << 8 | chunk[3] as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn platters(values: &[u32]) -> Vec<Platter> {
+        values.iter().map(|&v| Platter::from(v)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut registers = [Platter::from(0); 8];
+        registers[3] = Platter::from(0xDEAD_BEEF);
+        let arrays = vec![platters(&[1, 2, 3]), vec![], platters(&[4, 5])];
+        let program = Program::from_parts(registers, arrays, vec![1], 2, 0).unwrap();
+
+        let saved = save(&program, Some(Inputter::new(5)));
+        assert!(is_snapshot(&saved));
+        let (restored, waiting) = restore(&saved).unwrap();
+
+        assert_eq!(waiting.map(|i| i.register()), Some(5));
+        assert_eq!(restored.instruction_index(), 2);
+        assert_eq!(restored.registers()[3].to_u32(), 0xDEAD_BEEF);
+        assert_eq!(restored.array(0).unwrap(), &platters(&[1, 2, 3])[..]);
+        assert_eq!(restored.array(1), None);
+        assert_eq!(restored.array(2).unwrap(), &platters(&[4, 5])[..]);
+        assert_eq!(save(&restored, waiting), saved);
+    }
+
+    #[test]
+    fn rejects_truncated_snapshots() {
+        let program = Program::from_parts([Platter::from(0); 8], vec![platters(&[1, 2, 3])], vec![], 0, 0).unwrap();
+        let saved = save(&program, None);
+        for len in MAGIC.len()..saved.len() {
+            assert!(restore(&saved[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_huge_array_identifiers() {
+        let program = Program::from_parts([Platter::from(0); 8], vec![vec![]], vec![], 0, 0).unwrap();
+        let mut saved = save(&program, None);
+        // The identifier of the only array comes straight after the array count:
+        let at = MAGIC.len() + 4 * 12;
+        saved[at..at + 4].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0x00]);
+        assert!(restore(&saved).is_err());
+
+        // Likewise for abandoned arrays:
+        let mut saved = save(&program, None);
+        saved.truncate(saved.len() - 4);
+        saved.extend_from_slice(&[0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0x00]);
+        assert!(restore(&saved).is_err());
+    }
+
+    #[test]
+    fn rejects_arrays_abandoned_twice() {
+        let arrays = vec![vec![], vec![]];
+        let program = Program::from_parts([Platter::from(0); 8], arrays, vec![1], 0, 0).unwrap();
+        let mut saved = save(&program, None);
+        saved.truncate(saved.len() - 8);
+        saved.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert!(restore(&saved).is_err());
+    }
+
+}
@@ -0,0 +1,775 @@
+use crate::platter::Platter;
+use crate::program::{ Program, Inputter };
+use crate::instruction::Instruction;
+use std::rc::Rc;
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::process::{ Command, Stdio };
+
+/// A 32-bit value which is either known, or depends on input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Concrete(u32),
+    Symbolic(Rc<Node>)
+}
+
+/// An operation over values, at least one of which is symbolic.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Node {
+    // The nth byte of input that the program asked for:
+    Input(usize),
+    Add(Value, Value),
+    Mul(Value, Value),
+    Div(Value, Value),
+    Nand(Value, Value),
+    // If the first value is non-zero then the second, else the third:
+    Ite(Value, Value, Value)
+}
+
+impl Value {
+
+    fn node(node: Node) -> Value {
+        Value::Symbolic(Rc::new(node))
+    }
+
+    fn add(a: Value, b: Value) -> Value {
+        match (a, b) {
+            (Value::Concrete(a), Value::Concrete(b)) => Value::Concrete(a.wrapping_add(b)),
+            (x, Value::Concrete(0)) | (Value::Concrete(0), x) => x,
+            (a, b) => Value::node(Node::Add(a, b))
+        }
+    }
+
+    fn mul(a: Value, b: Value) -> Value {
+        match (a, b) {
+            (Value::Concrete(a), Value::Concrete(b)) => Value::Concrete(a.wrapping_mul(b)),
+            (x, Value::Concrete(1)) | (Value::Concrete(1), x) => x,
+            (_, Value::Concrete(0)) | (Value::Concrete(0), _) => Value::Concrete(0),
+            (a, b) => Value::node(Node::Mul(a, b))
+        }
+    }
+
+    fn div(a: Value, b: Value) -> Value {
+        match (a, b) {
+            (Value::Concrete(a), Value::Concrete(b)) => Value::Concrete(a / b),
+            (x, Value::Concrete(1)) => x,
+            (a, b) => Value::node(Node::Div(a, b))
+        }
+    }
+
+    fn nand(a: Value, b: Value) -> Value {
+        match (a, b) {
+            (Value::Concrete(a), Value::Concrete(b)) => Value::Concrete(!(a & b)),
+            (_, Value::Concrete(0)) | (Value::Concrete(0), _) => Value::Concrete(0xFFFF_FFFF),
+            (a, b) => Value::node(Node::Nand(a, b))
+        }
+    }
+
+    fn ite(cond: Value, then: Value, otherwise: Value) -> Value {
+        match cond {
+            Value::Concrete(0) => otherwise,
+            Value::Concrete(_) => then,
+            cond => {
+                if then == otherwise {
+                    then
+                } else {
+                    Value::node(Node::Ite(cond, then, otherwise))
+                }
+            }
+        }
+    }
+
+    /// The values this could take, if it's only ever chosen between known
+    /// values (as happens with conditional jumps).
+    fn possibilities(&self) -> Option<Vec<u32>> {
+        let mut out = vec![];
+        let mut todo = vec![self];
+        while let Some(value) = todo.pop() {
+            match value {
+                Value::Concrete(c) => {
+                    if !out.contains(c) {
+                        out.push(*c);
+                    }
+                },
+                Value::Symbolic(node) => {
+                    match &**node {
+                        Node::Ite(_, a, b) => { todo.push(b); todo.push(a); },
+                        _ => return None
+                    }
+                }
+            }
+            if out.len() > MAX_POSSIBILITIES {
+                return None;
+            }
+        }
+        Some(out)
+    }
+
+}
+
+const MAX_POSSIBILITIES: usize = 16;
+
+/// Something that must hold for execution to have followed a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constraint {
+    NonZero(Value),
+    Equals(Value, u32)
+}
+
+impl Constraint {
+    /// Whether the constraint is known to hold or not, if it doesn't depend on input.
+    fn known(&self) -> Option<bool> {
+        match self {
+            Constraint::NonZero(Value::Concrete(c)) => Some(*c != 0),
+            Constraint::Equals(Value::Concrete(c), v) => Some(c == v),
+            _ => None
+        }
+    }
+}
+
+/// An array whose contents are mostly known. Anything symbolic that's written
+/// to it is kept to one side, and both halves are shared between forked states
+/// until one of them writes to it.
+#[derive(Debug, Clone)]
+struct SymArray {
+    concrete: Rc<Vec<Platter>>,
+    symbolic: Rc<HashMap<usize, Rc<Node>>>
+}
+
+impl SymArray {
+
+    fn new(concrete: Vec<Platter>) -> SymArray {
+        SymArray {
+            concrete: Rc::new(concrete),
+            symbolic: Rc::new(HashMap::new())
+        }
+    }
+
+    fn get(&self, idx: usize) -> Option<Value> {
+        let concrete = self.concrete.get(idx)?;
+        if let Some(node) = self.symbolic.get(&idx) {
+            return Some(Value::Symbolic(node.clone()));
+        }
+        Some(Value::Concrete(concrete.to_u32()))
+    }
+
+    fn set(&mut self, idx: usize, value: Value) -> bool {
+        if idx >= self.concrete.len() {
+            return false;
+        }
+        match value {
+            Value::Concrete(c) => {
+                if self.symbolic.contains_key(&idx) {
+                    Rc::make_mut(&mut self.symbolic).remove(&idx);
+                }
+                Rc::make_mut(&mut self.concrete)[idx] = Platter::from(c);
+            },
+            Value::Symbolic(node) => {
+                Rc::make_mut(&mut self.symbolic).insert(idx, node);
+            }
+        }
+        true
+    }
+
+}
+
+/// What happened when a symbolic state took a step.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Continue,
+    Output,
+    Halted,
+    // The instruction needs a known value in this register to go on:
+    Concretize(usize),
+    // Execution can't continue down this path:
+    Stop(String)
+}
+
+/// The state of a UM where register and array contents may depend on input.
+#[derive(Debug, Clone)]
+pub struct State {
+    registers: [Value; 8],
+    arrays: Vec<Option<SymArray>>,
+    abandoned: Vec<usize>,
+    finger: usize,
+    max_inputs: usize,
+    pub constraints: Vec<Constraint>,
+    pub inputs: usize,
+    pub output: Vec<Value>,
+    pub steps: usize
+}
+
+impl State {
+
+    /// Begin symbolic execution from some concrete program state. If the program is
+    /// waiting on input, that input will be the first symbolic byte. At most `max_inputs`
+    /// bytes of input will be asked for on any path.
+    pub fn from_program(program: &Program, waiting: Option<Inputter>, max_inputs: usize) -> State {
+
+        let len = program.arrays().map(|(idx, _)| idx + 1)
+            .chain(program.abandoned().iter().map(|idx| idx + 1))
+            .max()
+            .unwrap_or(1);
+        let mut arrays = vec![None; len];
+        for (idx, array) in program.arrays() {
+            arrays[idx] = Some(SymArray::new(array.to_vec()));
+        }
+
+        let mut registers = [
+            Value::Concrete(0), Value::Concrete(0), Value::Concrete(0), Value::Concrete(0),
+            Value::Concrete(0), Value::Concrete(0), Value::Concrete(0), Value::Concrete(0)
+        ];
+        for (r, val) in program.registers().iter().enumerate() {
+            registers[r] = Value::Concrete(val.to_u32());
+        }
+
+        let mut state = State {
+            registers,
+            arrays,
+            abandoned: program.abandoned().to_vec(),
+            finger: program.instruction_index(),
+            max_inputs,
+            constraints: vec![],
+            inputs: 0,
+            output: vec![],
+            steps: 0
+        };
+        if let Some(inputter) = waiting {
+            state.registers[inputter.register()] = state.next_input();
+        }
+        state
+    }
+
+    pub fn finger(&self) -> usize {
+        self.finger
+    }
+
+    fn next_input(&mut self) -> Value {
+        self.inputs += 1;
+        Value::node(Node::Input(self.inputs - 1))
+    }
+
+    /// Add a constraint to the path, handing back false if it can't possibly hold.
+    fn constrain(&mut self, constraint: Constraint) -> bool {
+        match constraint.known() {
+            Some(holds) => holds,
+            None => {
+                self.constraints.push(constraint);
+                true
+            }
+        }
+    }
+
+    fn array(&self, id: usize) -> Result<&SymArray, Outcome> {
+        match self.arrays.get(id) {
+            Some(Some(array)) => Ok(array),
+            _ => Err(Outcome::Stop(format!("array {} is not active", id)))
+        }
+    }
+
+    fn concrete(&self, reg: usize) -> Result<u32, Outcome> {
+        match self.registers[reg] {
+            Value::Concrete(c) => Ok(c),
+            Value::Symbolic(..) => Err(Outcome::Concretize(reg))
+        }
+    }
+
+    /// Execute the next instruction.
+    pub fn step(&mut self) -> Outcome {
+
+        let op = match self.array(0).map(|code| code.get(self.finger)) {
+            Ok(Some(Value::Concrete(op))) => op,
+            Ok(Some(Value::Symbolic(..))) => return Outcome::Stop("the program is symbolic here".to_string()),
+            _ => return Outcome::Stop(format!("finger {:#x} is outside of the program", self.finger))
+        };
+
+        // If we need a register to be concrete, the instruction will be run again
+        // once it is, so nothing should be changed before that's known:
+        let finger = self.finger;
+        self.finger += 1;
+        let outcome = match self.apply(Instruction::decode(Platter::from(op))) {
+            Ok(outcome) => outcome,
+            Err(outcome) => outcome
+        };
+        if let Outcome::Concretize(..) = outcome {
+            self.finger = finger;
+        }
+        outcome
+    }
+
+    fn apply(&mut self, instruction: Instruction) -> Result<Outcome, Outcome> {
+
+        let reg = |state: &State, r: usize| state.registers[r].clone();
+
+        match instruction {
+            Instruction::ConditionalMove{ a, b, c } => {
+                self.registers[a] = Value::ite(reg(self, c), reg(self, b), reg(self, a));
+            },
+            Instruction::ArrayIndex{ a, b, c } => {
+                let (id, idx) = (self.concrete(b)?, self.concrete(c)?);
+                let val = self.array(id as usize)?.get(idx as usize)
+                    .ok_or_else(|| Outcome::Stop(format!("index {} is outside of array {}", idx, id)))?;
+                self.registers[a] = val;
+            },
+            Instruction::ArrayAmendment{ a, b, c } => {
+                let (id, idx) = (self.concrete(a)?, self.concrete(b)?);
+                self.array(id as usize)?;
+                let val = reg(self, c);
+                if let Some(Some(array)) = self.arrays.get_mut(id as usize) {
+                    if !array.set(idx as usize, val) {
+                        return Err(Outcome::Stop(format!("index {} is outside of array {}", idx, id)));
+                    }
+                }
+            },
+            Instruction::Addition{ a, b, c } => {
+                self.registers[a] = Value::add(reg(self, b), reg(self, c));
+            },
+            Instruction::Multiplication{ a, b, c } => {
+                self.registers[a] = Value::mul(reg(self, b), reg(self, c));
+            },
+            Instruction::Division{ a, b, c } => {
+                let divisor = reg(self, c);
+                if !self.constrain(Constraint::NonZero(divisor.clone())) {
+                    return Err(Outcome::Stop("division by zero".to_string()));
+                }
+                self.registers[a] = Value::div(reg(self, b), divisor);
+            },
+            Instruction::NotAnd{ a, b, c } => {
+                self.registers[a] = Value::nand(reg(self, b), reg(self, c));
+            },
+            Instruction::Halt => {
+                return Ok(Outcome::Halted);
+            },
+            Instruction::Allocation{ b, c } => {
+                let array = Some(SymArray::new(vec![Platter::from(0); self.concrete(c)? as usize]));
+                let id = match self.abandoned.pop() {
+                    Some(id) => {
+                        self.arrays[id] = array;
+                        id
+                    },
+                    None => {
+                        self.arrays.push(array);
+                        self.arrays.len() - 1
+                    }
+                };
+                self.registers[b] = Value::Concrete(id as u32);
+            },
+            Instruction::Abandonment{ c } => {
+                let id = self.concrete(c)? as usize;
+                self.array(id)?;
+                self.arrays[id] = None;
+                self.abandoned.push(id);
+            },
+            Instruction::Output{ c } => {
+                let val = reg(self, c);
+                self.output.push(val);
+                return Ok(Outcome::Output);
+            },
+            Instruction::Input{ c } => {
+                if self.inputs >= self.max_inputs {
+                    return Err(Outcome::Stop("ran out of symbolic input".to_string()));
+                }
+                self.registers[c] = self.next_input();
+            },
+            Instruction::LoadProgram{ b, c } => {
+                let (id, finger) = (self.concrete(b)?, self.concrete(c)?);
+                if id != 0 {
+                    let program = self.array(id as usize)?.clone();
+                    self.arrays[0] = Some(program);
+                }
+                self.finger = finger as usize;
+            },
+            Instruction::Orthography{ a, value } => {
+                self.registers[a] = Value::Concrete(value);
+            },
+            Instruction::Invalid{ op } => {
+                return Err(Outcome::Stop(format!("invalid operator {}", op)));
+            }
+        }
+
+        Ok(Outcome::Continue)
+    }
+
+    /// Split this state into one for each value that a register could hold,
+    /// if it only holds one of a few known values.
+    pub fn fork_on(&self, reg: usize) -> Option<Vec<State>> {
+        let value = &self.registers[reg];
+        let possibilities = value.possibilities()?;
+        let states = possibilities.into_iter().filter_map(|v| {
+            let mut state = self.clone();
+            state.registers[reg] = Value::Concrete(v);
+            if state.constrain(Constraint::Equals(value.clone(), v)) {
+                Some(state)
+            } else {
+                None
+            }
+        }).collect();
+        Some(states)
+    }
+
+    /// If the output so far could end with the given text, hand back the
+    /// constraints needed for it to do so.
+    pub fn output_ends_with(&self, text: &[u8]) -> Option<Vec<Constraint>> {
+        if self.output.len() < text.len() {
+            return None;
+        }
+        let tail = &self.output[self.output.len() - text.len()..];
+        let mut extra = vec![];
+        for (value, &byte) in tail.iter().zip(text.iter()) {
+            let constraint = Constraint::Equals(value.clone(), byte as u32);
+            match constraint.known() {
+                Some(false) => return None,
+                Some(true) => {},
+                None => extra.push(constraint)
+            }
+        }
+        Some(extra)
+    }
+
+}
+
+/// Where we'd like execution to get to.
+#[derive(Debug, Clone)]
+pub enum Goal {
+    Finger(usize),
+    Output(Vec<u8>)
+}
+
+/// Bounds on how much exploring to do.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_steps: usize,
+    pub max_paths: usize
+}
+
+/// A path which reached the goal, and anything else that needs to hold for it to do so.
+pub struct Reached {
+    pub state: State,
+    pub extra: Vec<Constraint>
+}
+
+/// What happened to the paths that we explored.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub paths: usize,
+    pub reached: usize,
+    pub halted: usize,
+    pub out_of_steps: usize,
+    pub stopped: BTreeMap<String, usize>
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} paths explored, {} reached the goal", self.paths, self.reached)?;
+        writeln!(f, "{} halted, {} ran out of steps", self.halted, self.out_of_steps)?;
+        for (reason, count) in &self.stopped {
+            writeln!(f, "{} stopped: {}", count, reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Explore paths from a starting state depth first, handing each one that reaches the
+/// goal to `on_reached`. Exploration stops early if that returns false.
+pub fn explore<F>(start: State, goal: &Goal, limits: &Limits, mut on_reached: F) -> Summary
+where F: FnMut(Reached) -> bool {
+
+    let mut summary = Summary { paths: 1, ..Summary::default() };
+    let mut todo = vec![start];
+
+    while let Some(mut state) = todo.pop() {
+        loop {
+
+            if let Goal::Finger(finger) = goal {
+                if state.finger == *finger {
+                    summary.reached += 1;
+                    if !on_reached(Reached{ state, extra: vec![] }) {
+                        return summary;
+                    }
+                    break;
+                }
+            }
+
+            if state.steps >= limits.max_steps {
+                summary.out_of_steps += 1;
+                break;
+            }
+            state.steps += 1;
+
+            match state.step() {
+                Outcome::Continue => {},
+                Outcome::Output => {
+                    if let Goal::Output(text) = goal {
+                        if let Some(extra) = state.output_ends_with(text) {
+                            summary.reached += 1;
+                            if !on_reached(Reached{ state, extra }) {
+                                return summary;
+                            }
+                            break;
+                        }
+                    }
+                },
+                Outcome::Halted => {
+                    summary.halted += 1;
+                    break;
+                },
+                Outcome::Concretize(reg) => {
+                    match state.fork_on(reg) {
+                        Some(states) => {
+                            summary.paths += states.len().saturating_sub(1);
+                            if summary.paths > limits.max_paths {
+                                return summary;
+                            }
+                            todo.extend(states.into_iter().rev());
+                        },
+                        None => {
+                            *summary.stopped.entry("a symbolic value was needed as an address".to_string()).or_insert(0) += 1;
+                        }
+                    }
+                    break;
+                },
+                Outcome::Stop(reason) => {
+                    *summary.stopped.entry(reason).or_insert(0) += 1;
+                    break;
+                }
+            }
+
+        }
+    }
+
+    summary
+}
+
+/// Build an SMT-LIB (QF_BV) query asking for input bytes which lead execution down a
+/// path, given any extra constraints. Input bytes are named `in0`, `in1` and so on.
+pub fn smt_query(state: &State, extra: &[Constraint]) -> String {
+
+    let mut out = String::new();
+    let _ = writeln!(out, "(set-logic QF_BV)");
+    for i in 0..state.inputs {
+        let _ = writeln!(out, "(declare-const in{} (_ BitVec 32))", i);
+        let _ = writeln!(out, "(assert (bvule in{} #x000000ff))", i);
+    }
+
+    // Shared subexpressions are defined once, in the order they're needed:
+    let mut names: HashMap<usize, String> = HashMap::new();
+    let constraints: Vec<&Constraint> = state.constraints.iter().chain(extra.iter()).collect();
+    for constraint in &constraints {
+        let value = match constraint {
+            Constraint::NonZero(v) | Constraint::Equals(v, _) => v
+        };
+        define_nodes(value, &mut names, &mut out);
+    }
+
+    for constraint in constraints {
+        let _ = match constraint {
+            Constraint::NonZero(v) => writeln!(out, "(assert (distinct {} {}))", smt_value(v, &names), smt_const(0)),
+            Constraint::Equals(v, c) => writeln!(out, "(assert (= {} {}))", smt_value(v, &names), smt_const(*c))
+        };
+    }
+
+    let _ = writeln!(out, "(check-sat)");
+    if state.inputs > 0 {
+        let vars: Vec<String> = (0..state.inputs).map(|i| format!("in{}", i)).collect();
+        let _ = writeln!(out, "(get-value ({}))", vars.join(" "));
+    }
+    out
+}
+
+fn node_id(node: &Rc<Node>) -> usize {
+    &**node as *const Node as usize
+}
+
+fn smt_const(c: u32) -> String {
+    format!("#x{:08x}", c)
+}
+
+fn smt_value(value: &Value, names: &HashMap<usize, String>) -> String {
+    match value {
+        Value::Concrete(c) => smt_const(*c),
+        Value::Symbolic(node) => names[&node_id(node)].clone()
+    }
+}
+
+/// Emit a definition for every node that a value depends on which hasn't been
+/// defined yet. This walks the graph with an explicit stack, as expressions built
+/// up in loops can be far deeper than we'd like to recurse.
+fn define_nodes(value: &Value, names: &mut HashMap<usize, String>, out: &mut String) {
+
+    let root = match value {
+        Value::Symbolic(node) => node.clone(),
+        Value::Concrete(..) => return
+    };
+
+    let mut stack = vec![(root, false)];
+    while let Some((node, children_done)) = stack.pop() {
+
+        if names.contains_key(&node_id(&node)) {
+            continue;
+        }
+
+        let children: Vec<&Value> = match &*node {
+            Node::Input(_) => vec![],
+            Node::Add(a, b) | Node::Mul(a, b) | Node::Div(a, b) | Node::Nand(a, b) => vec![a, b],
+            Node::Ite(c, a, b) => vec![c, a, b]
+        };
+
+        if !children_done {
+            let pending: Vec<Rc<Node>> = children.iter().filter_map(|v| match v {
+                Value::Symbolic(n) if !names.contains_key(&node_id(n)) => Some(n.clone()),
+                _ => None
+            }).collect();
+            stack.push((node.clone(), true));
+            for child in pending {
+                stack.push((child, false));
+            }
+            continue;
+        }
+
+        let name = match &*node {
+            Node::Input(i) => format!("in{}", i),
+            _ => format!("t{}", names.len())
+        };
+        let v = |value: &Value| smt_value(value, names);
+        let body = match &*node {
+            Node::Input(_) => None,
+            Node::Add(a, b) => Some(format!("(bvadd {} {})", v(a), v(b))),
+            Node::Mul(a, b) => Some(format!("(bvmul {} {})", v(a), v(b))),
+            Node::Div(a, b) => Some(format!("(bvudiv {} {})", v(a), v(b))),
+            Node::Nand(a, b) => Some(format!("(bvnot (bvand {} {}))", v(a), v(b))),
+            Node::Ite(c, a, b) => Some(format!("(ite (distinct {} {}) {} {})", v(c), smt_const(0), v(a), v(b)))
+        };
+        if let Some(body) = body {
+            let _ = writeln!(out, "(define-fun {} () (_ BitVec 32) {})", name, body);
+        }
+        names.insert(node_id(&node), name);
+    }
+}
+
+/// Hand a query to an SMT solver (for instance `z3 -in`), and read back the input it
+/// found. `None` is returned if the solver decides that the path is impossible.
+pub fn solve(query: &str, inputs: usize, solver: &str) -> Result<Option<Vec<u8>>, String> {
+
+    let mut args = solver.split_whitespace();
+    let program = args.next().ok_or("no solver command given")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run solver '{}': {}", program, e))?;
+
+    child.stdin.take()
+        .ok_or("could not write to solver")?
+        .write_all(query.as_bytes())
+        .map_err(|e| format!("could not write to solver: {}", e))?;
+    let output = child.wait_with_output().map_err(|e| format!("solver failed: {}", e))?;
+    read_model(&String::from_utf8_lossy(&output.stdout), inputs)
+}
+
+/// Read the input bytes out of what the solver had to say about a query.
+fn read_model(output: &str, inputs: usize) -> Result<Option<Vec<u8>>, String> {
+
+    match output.lines().next().map(|l| l.trim()) {
+        Some("sat") => {},
+        Some("unsat") => return Ok(None),
+        other => return Err(format!("solver said: {}", other.unwrap_or("nothing")))
+    }
+
+    // Pick out `(inN value)` pairs; values may be given in hex,
+    // binary or as `(_ bvN 32)`:
+    let mut input = vec![0u8; inputs];
+    let tokens: Vec<&str> = output.split(|c: char| c == '(' || c == ')' || c.is_whitespace())
+        .filter(|t| !t.is_empty())
+        .collect();
+    for (i, token) in tokens.iter().enumerate() {
+        if !token.starts_with("in") {
+            continue;
+        }
+        let idx = match token[2..].parse::<usize>() {
+            Ok(idx) if idx < inputs => idx,
+            _ => continue
+        };
+        let value = match tokens.get(i + 1) {
+            Some(t) if t.starts_with("#x") => u32::from_str_radix(&t[2..], 16).ok(),
+            Some(t) if t.starts_with("#b") => u32::from_str_radix(&t[2..], 2).ok(),
+            Some(&"_") => tokens.get(i + 2).and_then(|t| t.trim_start_matches("bv").parse::<u32>().ok()),
+            _ => None
+        };
+        if let Some(value) = value {
+            input[idx] = value as u8;
+        }
+    }
+    Ok(Some(input))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Run a program symbolically until it halts:
+    fn run(instructions: &[Instruction]) -> State {
+        let mut program = Program::new();
+        program.load_platters(instructions.iter().map(|i| i.encode()).collect());
+        let mut state = State::from_program(&program, None, 8);
+        loop {
+            match state.step() {
+                Outcome::Halted => return state,
+                Outcome::Continue | Outcome::Output => {},
+                other => panic!("unexpected outcome: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn queries_for_output() {
+        let state = run(&[
+            Instruction::Input{ c: 1 },
+            Instruction::Orthography{ a: 2, value: 1 },
+            Instruction::Addition{ a: 3, b: 1, c: 2 },
+            Instruction::Output{ c: 3 },
+            Instruction::Output{ c: 3 },
+            Instruction::Halt
+        ]);
+        let extra = state.output_ends_with(b"BB").unwrap();
+        assert_eq!(smt_query(&state, &extra), "\
+(set-logic QF_BV)
+(declare-const in0 (_ BitVec 32))
+(assert (bvule in0 #x000000ff))
+(define-fun t1 () (_ BitVec 32) (bvadd in0 #x00000001))
+(assert (= t1 #x00000042))
+(assert (= t1 #x00000042))
+(check-sat)
+(get-value (in0))
+");
+    }
+
+    #[test]
+    fn queries_without_input() {
+        let state = run(&[Instruction::Halt]);
+        assert_eq!(smt_query(&state, &[]), "(set-logic QF_BV)\n(check-sat)\n");
+    }
+
+    #[test]
+    fn reads_models() {
+        let output = "sat\n((in0 #x00000041)\n (in1 #b01000010)\n (in2 (_ bv67 32)))\n";
+        assert_eq!(read_model(output, 3), Ok(Some(b"ABC".to_vec())));
+        // Anything the solver didn't mention is left as 0:
+        assert_eq!(read_model("sat\n((in1 #x00000041))\n", 2), Ok(Some(vec![0, b'A'])));
+    }
+
+    #[test]
+    fn reads_unsat() {
+        assert_eq!(read_model("unsat\n(error \"model is not available\")\n", 2), Ok(None));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(read_model("", 1).is_err());
+        assert!(read_model("unknown\n", 1).is_err());
+        assert!(read_model("(error \"line 1 column 1: unexpected character\")\n", 1).is_err());
+    }
+
+}
@@ -0,0 +1,282 @@
+//! Just enough of the telnet protocol (RFC 854) to talk to real telnet clients:
+//! negotiation is answered rather than being passed on as input, window size
+//! reports (NAWS) are understood, and the client can be asked to send each
+//! character as it's typed rather than a line at a time.
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const NAWS: u8 = 31;
+
+/// How the client should send input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // The client edits and echoes a line before sending it. This is what clients
+    // do by default, so we don't negotiate anything unless the client does, and
+    // plain TCP clients like nc see nothing different:
+    Line,
+    // We ask the client to send each character as it's typed, and
+    // agree to echo input back to it ourselves:
+    Character
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "line" => Ok(Mode::Line),
+            "character" | "char" => Ok(Mode::Character),
+            _ => Err(format!("'{}' is not a telnet mode (expected 'line' or 'character')", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Data,
+    Cr,
+    Iac,
+    Command(u8),
+    Sub,
+    SubIac
+}
+
+/// The telnet state of one connection. Bytes from the client are handed to `receive`,
+/// which separates the input meant for the program from any replies to send back.
+#[derive(Debug, Clone)]
+pub struct Telnet {
+    mode: Mode,
+    state: State,
+    // Options enabled on our side and on theirs, and those we've
+    // asked for but not yet had an answer about:
+    ours: Vec<u8>,
+    theirs: Vec<u8>,
+    asked_ours: Vec<u8>,
+    asked_theirs: Vec<u8>,
+    sub: Vec<u8>,
+    window: Option<(u16, u16)>
+}
+
+impl Telnet {
+
+    pub fn new(mode: Mode) -> Telnet {
+        Telnet {
+            mode,
+            state: State::Data,
+            ours: vec![],
+            theirs: vec![],
+            asked_ours: vec![],
+            asked_theirs: vec![],
+            sub: vec![],
+            window: None
+        }
+    }
+
+    /// Anything that should be sent to the client as soon as it connects.
+    pub fn start(&mut self) -> Vec<u8> {
+        let mut out = vec![];
+        if self.mode == Mode::Character {
+            for &opt in &[ECHO, SUPPRESS_GO_AHEAD] {
+                self.ours.push(opt);
+                self.asked_ours.push(opt);
+                out.extend_from_slice(&[IAC, WILL, opt]);
+            }
+            self.asked_theirs.push(NAWS);
+            out.extend_from_slice(&[IAC, DO, NAWS]);
+        }
+        out
+    }
+
+    /// Have we agreed to echo input back to the client? If so, the client
+    /// won't be showing what's typed itself.
+    pub fn echoing(&self) -> bool {
+        self.ours.contains(&ECHO)
+    }
+
+    /// The size of the client's window (width, height), if it's told us.
+    pub fn window_size(&self) -> Option<(u16, u16)> {
+        self.window
+    }
+
+    /// Handle a byte from the client. Anything meant as input for the program is
+    /// added to `input`, and anything that should be sent back to the client is
+    /// added to `replies`. The telnet newline (CR LF) is handed on as LF.
+    pub fn receive(&mut self, byte: u8, input: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        self.state = match (self.state, byte) {
+            (State::Data, IAC) => State::Iac,
+            (State::Data, b'\r') => State::Cr,
+            (State::Data, byte) => {
+                input.push(byte);
+                State::Data
+            },
+            (State::Cr, b'\n') => {
+                input.push(b'\n');
+                State::Data
+            },
+            (State::Cr, byte) => {
+                input.push(b'\r');
+                match byte {
+                    IAC => State::Iac,
+                    b'\r' => State::Cr,
+                    // CR NUL is how a bare CR is sent:
+                    0 => State::Data,
+                    byte => {
+                        input.push(byte);
+                        State::Data
+                    }
+                }
+            },
+            (State::Iac, IAC) => {
+                input.push(IAC);
+                State::Data
+            },
+            (State::Iac, SB) => {
+                self.sub.clear();
+                State::Sub
+            },
+            (State::Iac, cmd @ WILL..=DONT) => State::Command(cmd),
+            // NOP, go ahead, are you there and so on aren't of any use to us:
+            (State::Iac, _) => State::Data,
+            (State::Command(cmd), opt) => {
+                self.negotiate(cmd, opt, replies);
+                State::Data
+            },
+            (State::Sub, IAC) => State::SubIac,
+            (State::Sub, byte) => {
+                self.sub.push(byte);
+                State::Sub
+            },
+            (State::SubIac, SE) => {
+                self.subnegotiation();
+                State::Data
+            },
+            (State::SubIac, byte) => {
+                self.sub.push(byte);
+                State::Sub
+            }
+        };
+    }
+
+    fn negotiate(&mut self, cmd: u8, opt: u8, replies: &mut Vec<u8>) {
+
+        let (enabled, asked, supported) = match cmd {
+            DO | DONT => (&mut self.ours, &mut self.asked_ours, self.mode == Mode::Character && (opt == ECHO || opt == SUPPRESS_GO_AHEAD)),
+            _ => (&mut self.theirs, &mut self.asked_theirs, opt == NAWS || opt == SUPPRESS_GO_AHEAD)
+        };
+        let (yes, no) = match cmd {
+            DO | DONT => (WILL, WONT),
+            _ => (DO, DONT)
+        };
+        let wants = cmd == DO || cmd == WILL;
+
+        // If this answers something we asked for, there's no need to reply:
+        if let Some(idx) = asked.iter().position(|&o| o == opt) {
+            asked.remove(idx);
+            if wants && !enabled.contains(&opt) {
+                enabled.push(opt);
+            } else if !wants {
+                enabled.retain(|&o| o != opt);
+            }
+            return;
+        }
+
+        let is_enabled = enabled.contains(&opt);
+        if wants && !is_enabled {
+            if supported {
+                enabled.push(opt);
+                replies.extend_from_slice(&[IAC, yes, opt]);
+            } else {
+                replies.extend_from_slice(&[IAC, no, opt]);
+            }
+        } else if !wants && is_enabled {
+            enabled.retain(|&o| o != opt);
+            replies.extend_from_slice(&[IAC, no, opt]);
+        }
+    }
+
+    fn subnegotiation(&mut self) {
+        if let [NAWS, w1, w0, h1, h0] = self.sub[..] {
+            self.window = Some(((w1 as u16) << 8 | w0 as u16, (h1 as u16) << 8 | h0 as u16));
+        }
+    }
+
+}
+
+/// Prepare a byte of output to be sent to a telnet client; IAC
+/// bytes are doubled up so they aren't taken to be commands.
+pub fn escape(byte: u8) -> Vec<u8> {
+    if byte == IAC {
+        vec![IAC, IAC]
+    } else {
+        vec![byte]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn receive_all(telnet: &mut Telnet, bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut input, mut replies) = (vec![], vec![]);
+        for &byte in bytes {
+            telnet.receive(byte, &mut input, &mut replies);
+        }
+        (input, replies)
+    }
+
+    #[test]
+    fn iac_is_doubled_up_on_output() {
+        assert_eq!(escape(b'a'), vec![b'a']);
+        assert_eq!(escape(IAC), vec![IAC, IAC]);
+    }
+
+    #[test]
+    fn doubled_iac_is_input() {
+        let mut telnet = Telnet::new(Mode::Line);
+        let (input, replies) = receive_all(&mut telnet, &[b'a', IAC, IAC, b'b']);
+        assert_eq!(input, vec![b'a', IAC, b'b']);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn newlines() {
+        let mut telnet = Telnet::new(Mode::Line);
+        let (input, _) = receive_all(&mut telnet, b"a\r\nb\r\0c\rd");
+        assert_eq!(input, b"a\nb\rc\rd".to_vec());
+    }
+
+    #[test]
+    fn commands_are_not_input() {
+        let mut telnet = Telnet::new(Mode::Line);
+        // A NOP, and a request to echo (which we refuse in line mode):
+        let (input, replies) = receive_all(&mut telnet, &[b'a', IAC, 241, IAC, DO, ECHO, b'b']);
+        assert_eq!(input, b"ab".to_vec());
+        assert_eq!(replies, vec![IAC, WONT, ECHO]);
+        assert!(!telnet.echoing());
+    }
+
+    #[test]
+    fn character_mode_negotiation() {
+        let mut telnet = Telnet::new(Mode::Character);
+        assert_eq!(telnet.start(), vec![IAC, WILL, ECHO, IAC, WILL, SUPPRESS_GO_AHEAD, IAC, DO, NAWS]);
+        assert!(telnet.echoing());
+
+        // Answers to what we asked for don't need replies, and the window size is understood:
+        let (input, replies) = receive_all(&mut telnet, &[
+            IAC, DO, ECHO, IAC, DO, SUPPRESS_GO_AHEAD, IAC, WILL, NAWS,
+            IAC, SB, NAWS, 0, 80, 0, 24, IAC, SE
+        ]);
+        assert!(input.is_empty());
+        assert!(replies.is_empty());
+        assert_eq!(telnet.window_size(), Some((80, 24)));
+    }
+
+}
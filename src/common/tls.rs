@@ -0,0 +1,59 @@
+use crate::error::{ err, Error };
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{ ServerConfig, NoClientAuth, Certificate, PrivateKey };
+use tokio_rustls::rustls::internal::pemfile;
+use std::{ fs::File, io::BufReader, path::Path, sync::Arc };
+
+/// What we need to terminate TLS on incoming connections.
+#[derive(Clone)]
+pub struct Tls {
+    pub acceptor: TlsAcceptor
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Tls {{ .. }}")
+    }
+}
+
+impl Tls {
+
+    /// Load a PEM encoded certificate chain, and the private key to go with it
+    /// (either PKCS8 or RSA). A self-signed certificate is fine.
+    pub fn from_files(cert: &Path, key: &Path) -> Result<Tls, Error> {
+
+        let certs = load_certs(cert)?;
+        let key = load_key(key)?;
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(certs, key)
+            .map_err(|e| err(format!("Couldn't use the TLS certificate and key: {}", e)))?;
+
+        Ok(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(config))
+        })
+    }
+
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = pemfile::certs(&mut reader)
+        .map_err(|_| err(format!("Couldn't read certificates from {}", path.display())))?;
+    if certs.is_empty() {
+        return Err(err(format!("No certificates found in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, Error> {
+    let bad_key = || err(format!("Couldn't read a private key from {}", path.display()));
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).map_err(|_| bad_key())?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader).map_err(|_| bad_key())?;
+    }
+    keys.into_iter().next().ok_or_else(bad_key)
+}
@@ -0,0 +1,163 @@
+use crate::auth::Peer;
+use crate::connection::{ self, Context, Protocol };
+use crate::io_extra;
+use crate::websocket;
+use tokio::prelude::*;
+
+/// A small terminal which talks to us over a WebSocket.
+const PAGE: &str = include_str!("terminal.html");
+
+// Requests longer than this are given up on:
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// What an HTTP request is asking for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Page,
+    WebSocket{ key: String },
+    // A WebSocket asked for by a page served from somewhere else:
+    Forbidden,
+    NotFound,
+    Bad
+}
+
+/// Work out what a request (everything up to and including the blank line
+/// after the headers) is asking for. We only know how to answer GETs.
+pub fn parse_request(request: &[u8]) -> Request {
+
+    let text = match std::str::from_utf8(request) {
+        Ok(text) => text,
+        Err(_) => return Request::Bad
+    };
+    let mut lines = text.split("\r\n");
+    let mut first = lines.next().unwrap_or("").split(' ');
+    let (method, path) = (first.next(), first.next().unwrap_or("").split('?').next());
+
+    let mut upgrade = false;
+    let mut key = None;
+    let (mut host, mut origin) = (None, None);
+    for line in lines {
+        if let Some(idx) = line.find(':') {
+            let (name, value) = (line[..idx].trim().to_ascii_lowercase(), line[idx + 1..].trim());
+            match &*name {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.to_string()),
+                "host" => host = Some(value.to_ascii_lowercase()),
+                "origin" => origin = Some(value.to_ascii_lowercase()),
+                _ => {}
+            }
+        }
+    }
+
+    match (method, path) {
+        (Some("GET"), Some("/")) | (Some("GET"), Some("/index.html")) => Request::Page,
+        (Some("GET"), Some("/ws")) => match key {
+            Some(_) if upgrade && !same_origin(origin.as_ref(), host.as_ref()) => Request::Forbidden,
+            Some(key) if upgrade => Request::WebSocket{ key },
+            _ => Request::Bad
+        },
+        (Some("GET"), _) => Request::NotFound,
+        _ => Request::Bad
+    }
+}
+
+// Browsers always say which page a WebSocket was opened from, so that we can stop other
+// sites from talking to the program through a visitor's browser. Clients that aren't
+// browsers usually don't say, and are let through.
+fn same_origin(origin: Option<&String>, host: Option<&String>) -> bool {
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return true
+    };
+    let origin_host = origin.splitn(2, "://").nth(1).map(|h| h.trim_end_matches('/'));
+    match (origin_host, host) {
+        (Some(origin_host), Some(host)) => origin_host == host,
+        _ => false
+    }
+}
+
+/// A complete HTTP response, after which the connection is closed.
+pub fn response(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut out = format!("HTTP/1.1 {}\r\n\
+                           Content-Type: {}\r\n\
+                           Content-Length: {}\r\n\
+                           Connection: close\r\n\r\n", status, content_type, body.len()).into_bytes();
+    out.extend_from_slice(body);
+    out
+}
+
+/// Answer an HTTP connection, either with the terminal page or by upgrading it to
+/// a WebSocket which sends and receives bytes like any other connection.
+pub fn serve<R, W>(reader: R, writer: W, peer: Peer, context: Context)
+where R: AsyncRead + Send + 'static, W: AsyncWrite + Send + 'static {
+    tokio::spawn_async(async move {
+
+        let mut input = io_extra::stream_bytes(reader);
+        let mut request = vec![];
+        while !request.ends_with(b"\r\n\r\n") {
+            match await!(input.next()) {
+                Some(Ok(byte)) if request.len() < MAX_REQUEST_LEN => request.push(byte),
+                _ => return
+            }
+        }
+
+        let reply = match parse_request(&request) {
+            Request::WebSocket{ key } => {
+                let preamble = websocket::handshake_response(&key);
+                let context = Context{ protocol: Protocol::WebSocket, ..context };
+                connection::serve(input, writer, preamble, peer, context);
+                return;
+            },
+            Request::Page => response("200 OK", "text/html; charset=utf-8", PAGE.as_bytes()),
+            Request::Forbidden => response("403 Forbidden", "text/plain", b"Forbidden"),
+            Request::NotFound => response("404 Not Found", "text/plain", b"Not found"),
+            Request::Bad => response("400 Bad Request", "text/plain", b"Bad request")
+        };
+        let _ = await!(tokio::io::write_all(writer, reply));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn request(lines: &[&str]) -> Request {
+        parse_request(format!("{}\r\n\r\n", lines.join("\r\n")).as_bytes())
+    }
+
+    fn upgrade(origin: Option<&str>) -> Request {
+        let mut lines = vec!["GET /ws HTTP/1.1", "Host: localhost:8080", "Upgrade: websocket", "Sec-WebSocket-Key: abc"];
+        let origin = origin.map(|o| format!("Origin: {}", o));
+        lines.extend(origin.as_ref().map(|o| o.as_str()));
+        request(&lines)
+    }
+
+    #[test]
+    fn parses_requests() {
+        assert_eq!(request(&["GET / HTTP/1.1"]), Request::Page);
+        assert_eq!(request(&["GET /index.html?x=1 HTTP/1.1"]), Request::Page);
+        assert_eq!(request(&["GET /nope HTTP/1.1"]), Request::NotFound);
+        assert_eq!(request(&["POST / HTTP/1.1"]), Request::Bad);
+        assert_eq!(request(&["GET /ws HTTP/1.1", "Sec-WebSocket-Key: abc"]), Request::Bad);
+        assert_eq!(parse_request(b"GET / \xFF\r\n\r\n"), Request::Bad);
+    }
+
+    #[test]
+    fn upgrades_from_the_same_origin() {
+        let websocket = Request::WebSocket{ key: "abc".to_string() };
+        assert_eq!(upgrade(None), websocket);
+        assert_eq!(upgrade(Some("http://localhost:8080")), websocket);
+        assert_eq!(upgrade(Some("HTTPS://LocalHost:8080/")), websocket);
+    }
+
+    #[test]
+    fn refuses_upgrades_from_other_origins() {
+        assert_eq!(upgrade(Some("http://evil.example")), Request::Forbidden);
+        assert_eq!(upgrade(Some("http://localhost:9090")), Request::Forbidden);
+        assert_eq!(upgrade(Some("null")), Request::Forbidden);
+        let no_host = request(&["GET /ws HTTP/1.1", "Upgrade: websocket", "Sec-WebSocket-Key: abc", "Origin: http://localhost"]);
+        assert_eq!(no_host, Request::Forbidden);
+    }
+
+}
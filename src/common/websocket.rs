@@ -0,0 +1,293 @@
+//! The parts of the WebSocket protocol (RFC 6455) needed to exchange bytes
+//! with a browser: working out the handshake response, decoding frames from
+//! the client, and framing output to send back.
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+/// The value of the Sec-WebSocket-Accept header to answer a handshake with.
+pub fn accept_key(key: &str) -> String {
+    let mut sha = sha1::Sha1::new();
+    sha.update(key.trim().as_bytes());
+    sha.update(GUID.as_bytes());
+    base64::encode(&sha.digest().bytes())
+}
+
+/// The response agreeing to upgrade a connection to a WebSocket.
+pub fn handshake_response(key: &str) -> Vec<u8> {
+    format!("HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key)).into_bytes()
+}
+
+/// Frame a message to send to the client. Frames from the server aren't masked.
+pub fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 10);
+    out.push(0x80 | opcode);
+    let len = payload.len();
+    if len < 126 {
+        out.push(len as u8);
+    } else if len <= 0xFFFF {
+        out.push(126);
+        out.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    } else {
+        out.push(127);
+        for shift in (0..8).rev() {
+            out.push(((len as u64) >> (shift * 8)) as u8);
+        }
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Length,
+    ExtendedLength{ remaining: u8 },
+    Mask{ idx: usize },
+    Payload,
+    Closed
+}
+
+// Control frames can't be longer than this:
+const MAX_CONTROL_LEN: u64 = 125;
+
+// The status code given when closing on a client which broke the rules:
+const PROTOCOL_ERROR: [u8; 2] = [0x03, 0xEA];
+
+/// Decodes frames sent by a client, a byte at a time.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    state: State,
+    opcode: u8,
+    fin: bool,
+    // Whether we're part way through a message sent in several frames:
+    fragmented: bool,
+    masked: bool,
+    mask: [u8; 4],
+    len: u64,
+    pos: u64,
+    control: Vec<u8>
+}
+
+impl Decoder {
+
+    pub fn new() -> Decoder {
+        Decoder {
+            state: State::Start,
+            opcode: 0,
+            fin: false,
+            fragmented: false,
+            masked: false,
+            mask: [0; 4],
+            len: 0,
+            pos: 0,
+            control: vec![]
+        }
+    }
+
+    /// Has the connection been closed (by the client, or because it broke the rules)?
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Handle a byte from the client. The contents of text and binary messages are added
+    /// to `input`, and any frames we need to send in reply (pongs, and acknowledging a
+    /// close) are added to `replies`.
+    pub fn receive(&mut self, byte: u8, input: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        self.state = match self.state {
+            State::Closed => State::Closed,
+            State::Start => {
+                self.fin = byte & 0x80 != 0;
+                self.opcode = byte & 0x0F;
+                State::Length
+            },
+            State::Length => {
+                self.masked = byte & 0x80 != 0;
+                self.len = (byte & 0x7F) as u64;
+                match self.len {
+                    126 => { self.len = 0; State::ExtendedLength{ remaining: 2 } },
+                    127 => { self.len = 0; State::ExtendedLength{ remaining: 8 } },
+                    _ => self.after_length(replies)
+                }
+            },
+            State::ExtendedLength{ remaining } => {
+                self.len = self.len << 8 | byte as u64;
+                if remaining > 1 {
+                    State::ExtendedLength{ remaining: remaining - 1 }
+                } else {
+                    self.after_length(replies)
+                }
+            },
+            State::Mask{ idx } => {
+                self.mask[idx] = byte;
+                if idx < 3 {
+                    State::Mask{ idx: idx + 1 }
+                } else {
+                    self.begin_payload(replies)
+                }
+            },
+            State::Payload => {
+                let byte = byte ^ self.mask[(self.pos % 4) as usize];
+                self.pos += 1;
+                if self.opcode & 0x8 != 0 {
+                    self.control.push(byte);
+                } else {
+                    input.push(byte);
+                }
+                if self.pos == self.len {
+                    self.end_frame(replies)
+                } else {
+                    State::Payload
+                }
+            }
+        };
+    }
+
+    fn after_length(&mut self, replies: &mut Vec<u8>) -> State {
+        // Clients must mask what they send, control frames must be short and can't
+        // be split up, and continuations only make sense part way through a message:
+        let control = self.opcode & 0x8 != 0;
+        let valid = self.masked
+            && !(control && (self.len > MAX_CONTROL_LEN || !self.fin))
+            && match self.opcode {
+                CONTINUATION => self.fragmented,
+                TEXT | BINARY => !self.fragmented,
+                _ => true
+            };
+        if !valid {
+            replies.extend(frame(CLOSE, &PROTOCOL_ERROR));
+            return State::Closed;
+        }
+        if !control {
+            self.fragmented = !self.fin;
+        }
+        State::Mask{ idx: 0 }
+    }
+
+    fn begin_payload(&mut self, replies: &mut Vec<u8>) -> State {
+        self.pos = 0;
+        self.control.clear();
+        if self.len > 0 {
+            State::Payload
+        } else {
+            self.end_frame(replies)
+        }
+    }
+
+    fn end_frame(&mut self, replies: &mut Vec<u8>) -> State {
+        match self.opcode {
+            PING => {
+                replies.extend(frame(PONG, &self.control));
+                State::Start
+            },
+            CLOSE => {
+                replies.extend(frame(CLOSE, &self.control));
+                State::Closed
+            },
+            CONTINUATION | TEXT | BINARY | PONG => State::Start,
+            // Reserved opcodes aren't allowed:
+            _ => State::Closed
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Frame a message the way a client would, masked:
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut out = vec![first, 0x80 | payload.len() as u8];
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(idx, byte)| byte ^ mask[idx % 4]));
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> (Decoder, Vec<u8>, Vec<u8>) {
+        let (mut decoder, mut input, mut replies) = (Decoder::new(), vec![], vec![]);
+        for &byte in bytes {
+            decoder.receive(byte, &mut input, &mut replies);
+        }
+        (decoder, input, replies)
+    }
+
+    #[test]
+    fn accept_key_from_the_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn masked_frames_are_unmasked() {
+        let (decoder, input, replies) = decode(&client_frame(0x80 | TEXT, b"Hello"));
+        assert_eq!(input, b"Hello".to_vec());
+        assert!(replies.is_empty());
+        assert!(!decoder.is_closed());
+    }
+
+    #[test]
+    fn unmasked_frames_are_rejected() {
+        let (decoder, input, replies) = decode(&[0x80 | TEXT, 5, b'H', b'e', b'l', b'l', b'o']);
+        assert!(input.is_empty());
+        assert_eq!(replies, frame(CLOSE, &PROTOCOL_ERROR));
+        assert!(decoder.is_closed());
+    }
+
+    #[test]
+    fn fragmented_messages() {
+        // A message in three frames, with a ping in the middle of it:
+        let mut bytes = client_frame(TEXT, b"Hel");
+        bytes.extend(client_frame(0x80 | PING, b"hi"));
+        bytes.extend(client_frame(CONTINUATION, b"l"));
+        bytes.extend(client_frame(0x80 | CONTINUATION, b"o"));
+        bytes.extend(client_frame(0x80 | BINARY, b"!"));
+        let (decoder, input, replies) = decode(&bytes);
+        assert_eq!(input, b"Hello!".to_vec());
+        assert_eq!(replies, frame(PONG, b"hi"));
+        assert!(!decoder.is_closed());
+    }
+
+    #[test]
+    fn misplaced_continuations_are_rejected() {
+        let (decoder, _, _) = decode(&client_frame(0x80 | CONTINUATION, b"x"));
+        assert!(decoder.is_closed());
+
+        let mut bytes = client_frame(TEXT, b"a");
+        bytes.extend(client_frame(0x80 | TEXT, b"b"));
+        let (decoder, input, _) = decode(&bytes);
+        assert_eq!(input, b"a".to_vec());
+        assert!(decoder.is_closed());
+    }
+
+    #[test]
+    fn fragmented_control_frames_are_rejected() {
+        let (decoder, _, _) = decode(&client_frame(PING, b"x"));
+        assert!(decoder.is_closed());
+    }
+
+    #[test]
+    fn close_is_acknowledged() {
+        let (decoder, _, replies) = decode(&client_frame(0x80 | CLOSE, &[0x03, 0xE8]));
+        assert_eq!(replies, frame(CLOSE, &[0x03, 0xE8]));
+        assert!(decoder.is_closed());
+    }
+
+    #[test]
+    fn frame_lengths() {
+        assert_eq!(frame(TEXT, b"hi"), vec![0x81, 2, b'h', b'i']);
+        assert_eq!(&frame(BINARY, &[0; 300])[..4], &[0x82, 126, 0x01, 0x2C]);
+        assert_eq!(&frame(BINARY, &[0; 70000])[..10], &[0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+    }
+
+}